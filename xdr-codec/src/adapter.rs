@@ -0,0 +1,229 @@
+//! Position-tracking stream adapters
+//!
+//! `XdrReader` and `XdrWriter` wrap a `Read` or `Write`, keeping track of how many bytes have
+//! passed through them. This gives the position in the XDR stream, whether it's aligned to the
+//! 4-byte XDR unit, and how much is left of an optional size limit.
+//!
+//! Since they implement `Read` and `Write` themselves, they can be used anywhere a plain stream
+//! can be, including with `Pack` and `Unpack`:
+//!
+//! ```
+//! use xdr_codec::adapter::XdrReader;
+//! use xdr_codec::Unpack;
+//!
+//! let data = vec![0, 0, 0, 1, 0, 0, 0, 2];
+//! let mut rd = XdrReader::with_limit(&data[..], 4);
+//!
+//! assert_eq!(u32::unpack(&mut rd).unwrap().0, 1);
+//! assert_eq!(rd.position(), 4);
+//! assert!(u32::unpack(&mut rd).is_err()); // over the limit
+//! ```
+use std::io::{self, BufRead, Read, Write};
+use std::cmp::min;
+
+use crate::error::*;
+
+use super::padding;
+
+fn limit_exceeded(limit: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("XDR stream limit of {} bytes exceeded", limit),
+    )
+}
+
+/// Read from a stream, tracking the position and an optional limit.
+///
+/// Reads which would go past the limit fail with an `InvalidData` error, rather than looking like
+/// the end of the stream.
+#[derive(Debug)]
+pub struct XdrReader<R: Read> {
+    pos: u64,
+    limit: Option<u64>,
+    reader: R,
+}
+
+impl<R: Read> XdrReader<R> {
+    pub fn new(rd: R) -> XdrReader<R> {
+        XdrReader {
+            pos: 0,
+            limit: None,
+            reader: rd,
+        }
+    }
+
+    /// Wrap a reader which may have at most `limit` bytes read from it.
+    pub fn with_limit(rd: R, limit: u64) -> XdrReader<R> {
+        XdrReader {
+            pos: 0,
+            limit: Some(limit),
+            reader: rd,
+        }
+    }
+
+    /// Set or remove the limit. It's the total number of bytes, including any already read.
+    pub fn set_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit;
+    }
+
+    /// Number of bytes read so far.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Number of bytes which can be read before reaching the limit, if there is one.
+    pub fn remaining(&self) -> Option<u64> {
+        self.limit.map(|l| l.saturating_sub(self.pos))
+    }
+
+    /// True if the position is a multiple of 4 bytes.
+    pub fn is_aligned(&self) -> bool {
+        self.pos.is_multiple_of(4)
+    }
+
+    /// Read and discard `sz` bytes.
+    pub fn skip(&mut self, sz: u64) -> Result<()> {
+        let skipped = io::copy(&mut self.by_ref().take(sz), &mut io::sink())?;
+        if skipped != sz {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of stream while skipping").into());
+        }
+        Ok(())
+    }
+
+    /// Skip padding up to the next multiple of 4 bytes, returning its size.
+    pub fn align(&mut self) -> Result<usize> {
+        let p = padding((self.pos % 4) as usize).len();
+        self.skip(p as u64)?;
+        Ok(p)
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    // The largest read allowed by the limit
+    fn allowed(&self, len: usize) -> io::Result<usize> {
+        match self.remaining() {
+            None => Ok(len),
+            Some(0) if len > 0 => Err(limit_exceeded(self.limit.unwrap())),
+            Some(rem) => Ok(min(len as u64, rem) as usize),
+        }
+    }
+}
+
+impl<R: Read> Read for XdrReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.allowed(buf.len())?;
+        let nread = self.reader.read(&mut buf[..len])?;
+        self.pos += nread as u64;
+        Ok(nread)
+    }
+}
+
+impl<R: BufRead> BufRead for XdrReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let rem = self.remaining();
+        let data = self.reader.fill_buf()?;
+        match rem {
+            None => Ok(data),
+            Some(0) if !data.is_empty() => Err(limit_exceeded(self.limit.unwrap())),
+            Some(rem) => Ok(&data[..min(data.len() as u64, rem) as usize]),
+        }
+    }
+
+    fn consume(&mut self, sz: usize) {
+        self.pos += sz as u64;
+        self.reader.consume(sz);
+    }
+}
+
+/// Write to a stream, tracking the position and an optional limit.
+///
+/// Writes which would go past the limit fail with an `InvalidData` error, and write nothing.
+#[derive(Debug)]
+pub struct XdrWriter<W: Write> {
+    pos: u64,
+    limit: Option<u64>,
+    writer: W,
+}
+
+impl<W: Write> XdrWriter<W> {
+    pub fn new(wr: W) -> XdrWriter<W> {
+        XdrWriter {
+            pos: 0,
+            limit: None,
+            writer: wr,
+        }
+    }
+
+    /// Wrap a writer which may have at most `limit` bytes written to it.
+    pub fn with_limit(wr: W, limit: u64) -> XdrWriter<W> {
+        XdrWriter {
+            pos: 0,
+            limit: Some(limit),
+            writer: wr,
+        }
+    }
+
+    /// Set or remove the limit. It's the total number of bytes, including any already written.
+    pub fn set_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit;
+    }
+
+    /// Number of bytes written so far.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Number of bytes which can be written before reaching the limit, if there is one.
+    pub fn remaining(&self) -> Option<u64> {
+        self.limit.map(|l| l.saturating_sub(self.pos))
+    }
+
+    /// True if the position is a multiple of 4 bytes.
+    pub fn is_aligned(&self) -> bool {
+        self.pos.is_multiple_of(4)
+    }
+
+    /// Write zero padding up to the next multiple of 4 bytes, returning its size.
+    pub fn align(&mut self) -> Result<usize> {
+        let p = padding((self.pos % 4) as usize);
+        self.write_all(p)?;
+        Ok(p.len())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Write for XdrWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.remaining().map_or(false, |rem| buf.len() as u64 > rem) {
+            return Err(limit_exceeded(self.limit.unwrap()));
+        }
+        let nwritten = self.writer.write(buf)?;
+        self.pos += nwritten as u64;
+        Ok(nwritten)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -5,7 +5,7 @@ error_chain! {
     }
 
     errors {
-        InvalidCase(v: i32) {
+        InvalidCase(v: i128) {
             description("invalid union case")
             display("invalid union case: '{}'", v)
         }
@@ -23,8 +23,8 @@ error_chain! {
 unsafe impl Sync for Error {}
 
 impl Error {
-    pub fn invalidcase(v: i32) -> Error {
-        ErrorKind::InvalidCase(v).into()
+    pub fn invalidcase<V: Into<i128>>(v: V) -> Error {
+        ErrorKind::InvalidCase(v.into()).into()
     }
 
     pub fn invalidenum(v: i32) -> Error {
@@ -24,12 +24,21 @@ extern crate byteorder;
 extern crate error_chain;
 
 pub use std::io::{Read, Write};
-use std::ops::Deref;
+use std::io;
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
 use std::cmp::min;
 use std::borrow::{Borrow, Cow};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::ptr;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 pub mod record;
+pub mod adapter;
 
 mod error;
 pub use crate::error::*;
@@ -78,6 +87,452 @@ impl<'a> From<&'a [u8]> for Opaque<'a> {
     }
 }
 
+/// Fixed-size opaque data.
+///
+/// This is the fixed-size counterpart of `Opaque`: `N` bytes packed without a length, and then
+/// padded to a 4 byte offset. A plain `[u8; N]` would be packed as an array of bytes, each padded to
+/// 4 bytes (and only with the `bytecodec` feature).
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct OpaqueArray<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> Default for OpaqueArray<N> {
+    fn default() -> Self {
+        OpaqueArray([0; N])
+    }
+}
+
+impl<const N: usize> Deref for OpaqueArray<N> {
+    type Target = [u8; N];
+    fn deref(&self) -> &[u8; N] {
+        &self.0
+    }
+}
+
+impl<const N: usize> DerefMut for OpaqueArray<N> {
+    fn deref_mut(&mut self) -> &mut [u8; N] {
+        &mut self.0
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for OpaqueArray<N> {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for OpaqueArray<N> {
+    fn from(v: [u8; N]) -> Self {
+        OpaqueArray(v)
+    }
+}
+
+impl<const N: usize> From<OpaqueArray<N>> for [u8; N] {
+    fn from(v: OpaqueArray<N>) -> Self {
+        v.0
+    }
+}
+
+/// An XDR string as bytes.
+///
+/// XDR strings are meant to be ASCII, but in practice they can hold anything, such as filenames
+/// in a local encoding. Unlike `String`, this keeps whatever bytes were unpacked, and converts them
+/// to text when it's needed.
+#[derive(Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct XdrString(pub Vec<u8>);
+
+impl XdrString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// The string, if it's valid UTF-8.
+    pub fn to_str(&self) -> ::std::result::Result<&str, ::std::str::Utf8Error> {
+        ::std::str::from_utf8(&self.0)
+    }
+
+    /// The string, with invalid UTF-8 replaced by U+FFFD.
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// The bytes as an `OsStr`. On Unix they're used as they are, and elsewhere they're converted
+    /// lossily.
+    #[cfg(unix)]
+    pub fn to_os_str(&self) -> Cow<'_, OsStr> {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(OsStr::from_bytes(&self.0))
+    }
+
+    /// The bytes as an `OsStr`. On Unix they're used as they are, and elsewhere they're converted
+    /// lossily.
+    #[cfg(not(unix))]
+    pub fn to_os_str(&self) -> Cow<'_, OsStr> {
+        match self.to_string_lossy() {
+            Cow::Borrowed(s) => Cow::Borrowed(OsStr::new(s)),
+            Cow::Owned(s) => Cow::Owned(s.into()),
+        }
+    }
+}
+
+impl fmt::Display for XdrString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_string_lossy().fmt(f)
+    }
+}
+
+impl fmt::Debug for XdrString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XdrString({:?})", self.to_string_lossy())
+    }
+}
+
+impl Deref for XdrString {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for XdrString {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for XdrString {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl<'a> PartialEq<&'a str> for XdrString {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl From<Vec<u8>> for XdrString {
+    fn from(v: Vec<u8>) -> Self {
+        XdrString(v)
+    }
+}
+
+impl<'a> From<&'a [u8]> for XdrString {
+    fn from(v: &'a [u8]) -> Self {
+        XdrString(v.to_vec())
+    }
+}
+
+impl From<String> for XdrString {
+    fn from(s: String) -> Self {
+        XdrString(s.into_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for XdrString {
+    fn from(s: &'a str) -> Self {
+        XdrString(s.as_bytes().to_vec())
+    }
+}
+
+impl<'a> From<&'a OsStr> for XdrString {
+    #[cfg(unix)]
+    fn from(s: &'a OsStr) -> Self {
+        use std::os::unix::ffi::OsStrExt;
+        XdrString(s.as_bytes().to_vec())
+    }
+
+    #[cfg(not(unix))]
+    fn from(s: &'a OsStr) -> Self {
+        XdrString(s.to_string_lossy().into_owned().into_bytes())
+    }
+}
+
+impl From<XdrString> for Vec<u8> {
+    fn from(s: XdrString) -> Self {
+        s.0
+    }
+}
+
+fn check_len(len: usize, max: usize) -> Result<()> {
+    if len > max {
+        bail!(ErrorKind::InvalidLen(len));
+    }
+    Ok(())
+}
+
+/// A string of at most `N` bytes, for `string name<N>`.
+///
+/// The length is checked when the string is made, so one which is too long to pack can't be
+/// built. It derefs to `str`, but can't be changed in place.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BoundedString<const N: usize>(String);
+
+impl<const N: usize> BoundedString<N> {
+    /// Wrap a string, or fail with `ErrorKind::InvalidLen` if it's longer than `N` bytes.
+    pub fn new<S: Into<String>>(s: S) -> Result<Self> {
+        let s = s.into();
+        check_len(s.len(), N)?;
+        Ok(BoundedString(s))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl<const N: usize> Deref for BoundedString<N> {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<const N: usize> AsRef<str> for BoundedString<N> {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<const N: usize> fmt::Display for BoundedString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<const N: usize> PartialEq<str> for BoundedString<N> {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl<'a, const N: usize> PartialEq<&'a str> for BoundedString<N> {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl<const N: usize> TryFrom<String> for BoundedString<N> {
+    type Error = Error;
+    fn try_from(s: String) -> Result<Self> {
+        BoundedString::new(s)
+    }
+}
+
+impl<'a, const N: usize> TryFrom<&'a str> for BoundedString<N> {
+    type Error = Error;
+    fn try_from(s: &'a str) -> Result<Self> {
+        BoundedString::new(s)
+    }
+}
+
+impl<const N: usize> From<BoundedString<N>> for String {
+    fn from(s: BoundedString<N>) -> Self {
+        s.0
+    }
+}
+
+/// An array of at most `N` elements, for `T name<N>`.
+///
+/// The length is checked when the array is made and when elements are added, so one which is too
+/// long to pack can't be built. It derefs to a slice, so elements can be changed in place.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BoundedVec<T, const N: usize>(Vec<T>);
+
+impl<T, const N: usize> BoundedVec<T, N> {
+    /// Wrap a `Vec`, or fail with `ErrorKind::InvalidLen` if it has more than `N` elements.
+    pub fn new(v: Vec<T>) -> Result<Self> {
+        check_len(v.len(), N)?;
+        Ok(BoundedVec(v))
+    }
+
+    /// Add an element, unless there are already `N`.
+    pub fn push(&mut self, v: T) -> Result<()> {
+        check_len(self.0.len() + 1, N)?;
+        self.0.push(v);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.0.pop()
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.0
+    }
+}
+
+impl<T, const N: usize> Default for BoundedVec<T, N> {
+    fn default() -> Self {
+        BoundedVec(Vec::new())
+    }
+}
+
+impl<T, const N: usize> Deref for BoundedVec<T, N> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<T, const N: usize> DerefMut for BoundedVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.0
+    }
+}
+
+impl<T, const N: usize> AsRef<[T]> for BoundedVec<T, N> {
+    fn as_ref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<T, const N: usize> TryFrom<Vec<T>> for BoundedVec<T, N> {
+    type Error = Error;
+    fn try_from(v: Vec<T>) -> Result<Self> {
+        BoundedVec::new(v)
+    }
+}
+
+impl<T, const N: usize> From<BoundedVec<T, N>> for Vec<T> {
+    fn from(v: BoundedVec<T, N>) -> Self {
+        v.0
+    }
+}
+
+/// Opaque data of at most `N` bytes, for `opaque name<N>`.
+///
+/// Like `BoundedVec<u8, N>`, but packed as compact opaque data rather than as an array of bytes.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BoundedOpaque<const N: usize>(Vec<u8>);
+
+impl<const N: usize> BoundedOpaque<N> {
+    /// Wrap some bytes, or fail with `ErrorKind::InvalidLen` if there are more than `N`.
+    pub fn new<V: Into<Vec<u8>>>(v: V) -> Result<Self> {
+        let v = v.into();
+        check_len(v.len(), N)?;
+        Ok(BoundedOpaque(v))
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl<const N: usize> Deref for BoundedOpaque<N> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<const N: usize> DerefMut for BoundedOpaque<N> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for BoundedOpaque<N> {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<const N: usize> TryFrom<Vec<u8>> for BoundedOpaque<N> {
+    type Error = Error;
+    fn try_from(v: Vec<u8>) -> Result<Self> {
+        BoundedOpaque::new(v)
+    }
+}
+
+impl<'a, const N: usize> TryFrom<&'a [u8]> for BoundedOpaque<N> {
+    type Error = Error;
+    fn try_from(v: &'a [u8]) -> Result<Self> {
+        BoundedOpaque::new(v)
+    }
+}
+
+impl<const N: usize> From<BoundedOpaque<N>> for Vec<u8> {
+    fn from(v: BoundedOpaque<N>) -> Self {
+        v.0
+    }
+}
+
+/// Opaque data which is streamed from a reader when it's packed, rather than held in memory.
+///
+/// Packing takes the reader, so a stream can only be packed once. Unpacking reads the data into
+/// memory; use `unpack_opaque_into` to stream it into a `Write` instead, which leaves a stream with
+/// only the length.
+pub struct OpaqueStream {
+    len: usize,
+    source: RefCell<Option<Box<dyn Read + Send>>>,
+}
+
+impl OpaqueStream {
+    /// `len` bytes of data to be read from `source`.
+    pub fn new<R: Read + Send + 'static>(len: usize, source: R) -> Self {
+        OpaqueStream {
+            len,
+            source: RefCell::new(Some(Box::new(source))),
+        }
+    }
+
+    /// Data which is already in memory.
+    pub fn from_bytes<V: Into<Vec<u8>>>(v: V) -> Self {
+        let v = v.into();
+        OpaqueStream::new(v.len(), io::Cursor::new(v))
+    }
+
+    /// `len` bytes of data which have already been read, such as by unpacking them into a `Write`.
+    pub fn consumed(len: usize) -> Self {
+        OpaqueStream {
+            len,
+            source: RefCell::new(None),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// True if the data has been packed or taken, or was never there.
+    pub fn is_consumed(&self) -> bool {
+        self.source.borrow().is_none()
+    }
+
+    /// Take the reader for the data, if it hasn't been packed already.
+    pub fn into_reader(self) -> Option<Box<dyn Read + Send>> {
+        self.source.into_inner()
+    }
+}
+
+impl Default for OpaqueStream {
+    fn default() -> Self {
+        OpaqueStream::from_bytes(Vec::new())
+    }
+}
+
+impl fmt::Debug for OpaqueStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OpaqueStream")
+            .field("len", &self.len)
+            .field("consumed", &self.is_consumed())
+            .finish()
+    }
+}
+
 /// Serialization (packing) helper.
 ///
 /// Helper to serialize any type implementing `Pack` into an implementation of `std::io::Write`.
@@ -174,6 +629,53 @@ pub fn pack_string<Out: Write>(val: &str, maxsz: Option<usize>, out: &mut Out) -
     pack_opaque_flex(val.as_bytes(), maxsz, out)
 }
 
+/// Pack `len` bytes of opaque data read from `src`, with size limit check.
+///
+/// The data is copied to `out` as it's read rather than being buffered. It fails if `src` has fewer
+/// than `len` bytes.
+pub fn pack_opaque_from<Out: Write, R: Read + ?Sized>(
+    src: &mut R,
+    len: usize,
+    maxsz: Option<usize>,
+    out: &mut Out,
+) -> Result<usize> {
+    if maxsz.map_or(false, |m| len > m) {
+        bail!(ErrorKind::InvalidLen(maxsz.unwrap()));
+    }
+    if len > u32::MAX as usize {
+        bail!(ErrorKind::InvalidLen(u32::MAX as usize));
+    }
+
+    let mut sz = len.pack(out)?;
+
+    let copied = io::copy(&mut src.take(len as u64), out)?;
+    if copied != len as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "opaque data source too short").into());
+    }
+    sz += len;
+
+    let p = padding(sz);
+    out.write_all(p)?;
+    sz += p.len();
+
+    Ok(sz)
+}
+
+/// Pack an `OpaqueStream`, with size limit check.
+///
+/// This takes the stream's reader, so it fails if the stream has already been packed.
+pub fn pack_opaque_stream<Out: Write>(
+    val: &OpaqueStream,
+    maxsz: Option<usize>,
+    out: &mut Out,
+) -> Result<usize> {
+    match val.source.borrow_mut().take() {
+        Some(mut src) => pack_opaque_from(&mut src, val.len, maxsz, out),
+        None if val.len == 0 => pack_opaque_from(&mut io::empty(), 0, maxsz, out),
+        None => Err(Error::from("opaque stream has already been consumed")),
+    }
+}
+
 /// Unpack a fixed-sized array
 ///
 /// Unpack a fixed-size array of elements. The results are placed in `array`, but the actual wire-size of
@@ -261,6 +763,107 @@ where
     Ok(rsz)
 }
 
+// A partially initialized array. Elements are initialized in order, and if it is dropped before
+// being completed then only the initialized ones are dropped.
+struct PartialArray<T, const N: usize> {
+    buf: [MaybeUninit<T>; N],
+    init: usize,
+}
+
+impl<T, const N: usize> PartialArray<T, N> {
+    fn new() -> Self {
+        PartialArray {
+            buf: [const { MaybeUninit::uninit() }; N],
+            init: 0,
+        }
+    }
+
+    fn push(&mut self, v: T) {
+        self.buf[self.init].write(v);
+        self.init += 1;
+    }
+
+    fn into_array(self) -> [T; N] {
+        assert_eq!(self.init, N);
+        let this = ManuallyDrop::new(self);
+        // Safe because all the elements are initialized, and `[MaybeUninit<T>; N]` has the same
+        // layout as `[T; N]`. `this` won't be dropped, so ownership is moved to the result.
+        unsafe { ptr::read(&this.buf as *const [MaybeUninit<T>; N] as *const [T; N]) }
+    }
+}
+
+impl<T, const N: usize> Drop for PartialArray<T, N> {
+    fn drop(&mut self) {
+        for elem in &mut self.buf[..self.init] {
+            // Safe because the first `init` elements are initialized
+            unsafe { elem.assume_init_drop() }
+        }
+    }
+}
+
+/// Unpack a fixed-sized array into a new `[T; N]`
+///
+/// This is like `unpack_array`, but returns a new array rather than filling in an existing one,
+/// so there's no need to have an initialized array to start with. The actual wire-size of the
+/// array is `arraysz`; if `N` is larger then the remainder is filled in with `defl`, or decoding
+/// fails with an `InvalidLen` error if there's no default. If `N` is smaller, the excess elements
+/// are discarded.
+///
+/// If decoding fails part way through, the elements decoded so far are dropped.
+pub fn unpack_fixed_array<In, T, const N: usize>(
+    input: &mut In,
+    arraysz: usize,
+    defl: Option<&T>,
+) -> Result<([T; N], usize)>
+where
+    In: Read,
+    T: Unpack<In> + Clone,
+{
+    let mut array = PartialArray::<T, N>::new();
+    let mut rsz = 0;
+
+    for _ in 0..min(arraysz, N) {
+        let (v, sz) = Unpack::unpack(input)?;
+        rsz += sz;
+        array.push(v);
+    }
+
+    // Fill in excess array entries with default values
+    if arraysz < N {
+        if let Some(defl) = defl {
+            for _ in arraysz..N {
+                array.push(defl.clone());
+            }
+        } else {
+            bail!(ErrorKind::InvalidLen(arraysz));
+        }
+    }
+
+    // Mop up unused array entries on the wire
+    for _ in N..arraysz {
+        let (_, sz) = T::unpack(input)?;
+        rsz += sz;
+    }
+    assert!(rsz % 4 == 0);
+
+    Ok((array.into_array(), rsz))
+}
+
+/// Unpack a fixed-sized opaque array into a new `[u8; N]`
+///
+/// This is like `unpack_opaque_array`, but returns a new array. The actual wire-size of the array
+/// is `bytesz`; if `N` is larger then the remainder is filled in with 0x00, and if it's smaller
+/// the excess bytes are discarded.
+pub fn unpack_fixed_opaque_array<In: Read, const N: usize>(
+    input: &mut In,
+    bytesz: usize,
+) -> Result<([u8; N], usize)> {
+    let mut bytes = [0; N];
+    let sz = unpack_opaque_array(input, &mut bytes[..], bytesz)?;
+
+    Ok((bytes, sz))
+}
+
 /// Unpack a fixed-sized opaque array
 ///
 /// Unpack a fixed-size array of raw bytes. The results are placed in `bytes`, but the actual wire-size of
@@ -328,6 +931,209 @@ pub fn unpack_flex<In: Read, T: Unpack<In>>(
     Ok((out, sz))
 }
 
+/// Unpack a (perhaps) length-limited array lazily
+///
+/// This reads the length of an XDR encoded array, checking it against the optional maximum length,
+/// and returns a `FlexIter` which unpacks the elements one at a time rather than collecting them
+/// into a `Vec`.
+pub fn unpack_flex_iter<In: Read, T: Unpack<In>>(
+    input: &mut In,
+    maxsz: Option<usize>,
+) -> Result<FlexIter<'_, In, T>> {
+    let (elems, sz) = Unpack::unpack(input)?;
+
+    if maxsz.map_or(false, |m| elems > m) {
+        bail!(ErrorKind::InvalidLen(maxsz.unwrap()));
+    }
+
+    Ok(FlexIter {
+        input,
+        remaining: elems,
+        sz,
+        failed: false,
+        _elem: PhantomData,
+    })
+}
+
+/// Iterator over the elements of a flex array.
+///
+/// Returned by `unpack_flex_iter`. Each call to `next` unpacks one element from the input, and the
+/// padding is consumed after the last one. The iterator stops after the first error.
+///
+/// If it's dropped before it's finished the input is left part way through the array; use `finish`
+/// to skip the rest.
+pub struct FlexIter<'a, In: 'a, T> {
+    input: &'a mut In,
+    remaining: usize,
+    sz: usize,
+    failed: bool,
+    _elem: PhantomData<fn() -> T>,
+}
+
+impl<'a, In: Read, T: Unpack<In>> FlexIter<'a, In, T> {
+    /// Number of elements not yet unpacked.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Number of bytes unpacked so far, including the length.
+    pub fn size(&self) -> usize {
+        self.sz
+    }
+
+    /// Unpack and discard any remaining elements, returning the total size of the array.
+    ///
+    /// Fails if unpacking an element has already failed, since the rest can't be found.
+    pub fn finish(mut self) -> Result<usize> {
+        if self.failed {
+            return Err(Error::from("flex array iterator has already failed"));
+        }
+        for r in self.by_ref() {
+            let _ = r?;
+        }
+        Ok(self.sz)
+    }
+}
+
+impl<'a, In: Read, T: Unpack<In>> Iterator for FlexIter<'a, In, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        if self.failed || self.remaining == 0 {
+            return None;
+        }
+
+        let res = (|| {
+            let (v, sz) = T::unpack(self.input)?;
+            self.sz += sz;
+            self.remaining -= 1;
+
+            if self.remaining == 0 {
+                let p = padding(self.sz);
+                for _ in 0..p.len() {
+                    let _ = self.input.read_u8()?;
+                }
+                self.sz += p.len();
+            }
+            Ok(v)
+        })();
+
+        self.failed = res.is_err();
+        Some(res)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.failed {
+            (0, Some(0))
+        } else {
+            (0, Some(self.remaining))
+        }
+    }
+}
+
+/// Unpack a linked list lazily
+///
+/// XDR represents lists as a chain of optional pointers, such as `struct entry { ...; entry
+/// *next; }`. On the wire each element is preceded by a `TRUE` boolean, and the list is terminated
+/// by `FALSE`. This returns a `ListIter` which unpacks the elements one at a time, so a long list
+/// doesn't need to be unpacked recursively.
+///
+/// `T` is the body of each element - everything except the link to the next one.
+pub fn unpack_list_iter<In: Read, T: Unpack<In>>(input: &mut In) -> ListIter<'_, In, T> {
+    unpack_list_iter_with(input, T::unpack as fn(&mut In) -> Result<(T, usize)>)
+}
+
+/// Unpack a linked list lazily, using `unpack` to unpack the body of each element.
+pub fn unpack_list_iter_with<In, T, F>(input: &mut In, unpack: F) -> ListIter<'_, In, T, F>
+where
+    In: Read,
+    F: FnMut(&mut In) -> Result<(T, usize)>,
+{
+    ListIter {
+        input,
+        unpack,
+        sz: 0,
+        done: false,
+        failed: false,
+        _elem: PhantomData,
+    }
+}
+
+/// Iterator over the elements of a linked list.
+///
+/// Returned by `unpack_list_iter` and `unpack_list_iter_with`. The iterator stops at the end of
+/// the list, or after the first error.
+pub struct ListIter<'a, In: 'a, T, F = fn(&mut In) -> Result<(T, usize)>> {
+    input: &'a mut In,
+    unpack: F,
+    sz: usize,
+    done: bool,
+    failed: bool,
+    _elem: PhantomData<fn() -> T>,
+}
+
+impl<'a, In, T, F> ListIter<'a, In, T, F>
+where
+    In: Read,
+    F: FnMut(&mut In) -> Result<(T, usize)>,
+{
+    /// Number of bytes unpacked so far.
+    pub fn size(&self) -> usize {
+        self.sz
+    }
+
+    /// Unpack and discard any remaining elements, returning the total size of the list.
+    ///
+    /// Fails if unpacking an element has already failed, since the rest can't be found.
+    pub fn finish(mut self) -> Result<usize> {
+        if self.failed {
+            return Err(Error::from("list iterator has already failed"));
+        }
+        for r in self.by_ref() {
+            let _ = r?;
+        }
+        Ok(self.sz)
+    }
+}
+
+impl<'a, In, T, F> Iterator for ListIter<'a, In, T, F>
+where
+    In: Read,
+    F: FnMut(&mut In) -> Result<(T, usize)>,
+{
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        if self.done {
+            return None;
+        }
+
+        let res = (|| {
+            let (more, sz): (bool, _) = Unpack::unpack(self.input)?;
+            self.sz += sz;
+            if !more {
+                return Ok(None);
+            }
+            let (v, sz) = (self.unpack)(self.input)?;
+            self.sz += sz;
+            Ok(Some(v))
+        })();
+
+        match res {
+            Ok(Some(v)) => Some(Ok(v)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 /// Unpack a (perhaps) length-limited opaque array
 ///
 /// Unpack an XDR encoded array of bytes, with an optional maximum length.
@@ -361,6 +1167,60 @@ pub fn unpack_string<In: Read>(input: &mut In, maxsz: Option<usize>) -> Result<(
     String::from_utf8(v).map_err(Error::from).map(|s| (s, sz))
 }
 
+/// Unpack a (perhaps) length-limited string, replacing invalid UTF-8 with U+FFFD rather than
+/// failing.
+pub fn unpack_string_lossy<In: Read>(input: &mut In, maxsz: Option<usize>) -> Result<(String, usize)> {
+    let (v, sz) = unpack_opaque_flex(input, maxsz)?;
+
+    let s = match String::from_utf8(v) {
+        Ok(s) => s,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    };
+    Ok((s, sz))
+}
+
+/// Unpack a (perhaps) length-limited string as bytes, without checking it's UTF-8.
+pub fn unpack_xdr_string<In: Read>(input: &mut In, maxsz: Option<usize>) -> Result<(XdrString, usize)> {
+    let (v, sz) = unpack_opaque_flex(input, maxsz)?;
+    Ok((XdrString(v), sz))
+}
+
+/// Unpack (perhaps) length-limited opaque data into `sink`.
+///
+/// The data is copied to `sink` as it's read rather than being buffered. Returns the length of the
+/// data as well as the number of bytes unpacked.
+pub fn unpack_opaque_into<In: Read, W: Write + ?Sized>(
+    input: &mut In,
+    sink: &mut W,
+    maxsz: Option<usize>,
+) -> Result<(usize, usize)> {
+    let (len, mut sz) = Unpack::unpack(input)?;
+
+    if maxsz.map_or(false, |m| len > m) {
+        bail!(ErrorKind::InvalidLen(maxsz.unwrap()));
+    }
+
+    let copied = io::copy(&mut input.take(len as u64), sink)?;
+    if copied != len as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "opaque data truncated").into());
+    }
+    sz += len;
+
+    let p = padding(sz);
+    for _ in 0..p.len() {
+        let _ = input.read_u8()?;
+    }
+    sz += p.len();
+
+    Ok((len, sz))
+}
+
+/// Unpack (perhaps) length-limited opaque data into memory, as an `OpaqueStream`.
+pub fn unpack_opaque_stream<In: Read>(input: &mut In, maxsz: Option<usize>) -> Result<(OpaqueStream, usize)> {
+    let (v, sz) = unpack_opaque_flex(input, maxsz)?;
+    Ok((OpaqueStream::from_bytes(v), sz))
+}
+
 /// Basic packing trait.
 ///
 /// This trait is used to implement XDR packing any Rust type into a
@@ -489,6 +1349,45 @@ impl<Out: Write, T: Pack<Out>> Pack<Out> for [T] {
     }
 }
 
+/// Fixed-size arrays are packed without a length.
+impl<Out: Write, T: Pack<Out>, const N: usize> Pack<Out> for [T; N] {
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        let mut sz = 0;
+        for it in self {
+            sz += it.pack(out)?;
+        }
+        Ok(sz)
+    }
+}
+
+impl<Out: Write, const N: usize> Pack<Out> for OpaqueArray<N> {
+    #[inline]
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_opaque_array(&self.0[..], N, out)
+    }
+}
+
+impl<Out: Write, const N: usize> Pack<Out> for BoundedString<N> {
+    #[inline]
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_string(&self.0, Some(N), out)
+    }
+}
+
+impl<Out: Write, T: Pack<Out>, const N: usize> Pack<Out> for BoundedVec<T, N> {
+    #[inline]
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_flex(&self.0, Some(N), out)
+    }
+}
+
+impl<Out: Write, const N: usize> Pack<Out> for BoundedOpaque<N> {
+    #[inline]
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_opaque_flex(&self.0, Some(N), out)
+    }
+}
+
 impl<Out: Write, T: Pack<Out>> Pack<Out> for Vec<T> {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
@@ -531,6 +1430,19 @@ impl<Out: Write> Pack<Out> for str {
     }
 }
 
+impl<Out: Write> Pack<Out> for OpaqueStream {
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_opaque_stream(self, None, out)
+    }
+}
+
+impl<Out: Write> Pack<Out> for XdrString {
+    #[inline]
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_opaque_flex(&self.0, None, out)
+    }
+}
+
 impl<Out: Write, T: Pack<Out>> Pack<Out> for Option<T> {
     fn pack(&self, out: &mut Out) -> Result<usize> {
         match self {
@@ -697,6 +1609,18 @@ impl<In: Read> Unpack<In> for String {
     }
 }
 
+impl<In: Read> Unpack<In> for OpaqueStream {
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        unpack_opaque_stream(input, None)
+    }
+}
+
+impl<In: Read> Unpack<In> for XdrString {
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        unpack_xdr_string(input, None)
+    }
+}
+
 impl<'a, In: Read> Unpack<In> for Opaque<'a> {
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
         let (len, mut sz) = usize::unpack(input)?;
@@ -713,6 +1637,54 @@ impl<'a, In: Read> Unpack<In> for Opaque<'a> {
     }
 }
 
+/// Fixed-size arrays are unpacked without a length.
+impl<In: Read, T: Unpack<In>, const N: usize> Unpack<In> for [T; N] {
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        let mut array = PartialArray::<T, N>::new();
+        let mut sz = 0;
+
+        for _ in 0..N {
+            let (v, esz) = T::unpack(input)?;
+            sz += esz;
+            array.push(v);
+        }
+
+        Ok((array.into_array(), sz))
+    }
+}
+
+impl<In: Read, const N: usize> Unpack<In> for OpaqueArray<N> {
+    #[inline]
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        let (v, sz) = unpack_fixed_opaque_array(input, N)?;
+        Ok((OpaqueArray(v), sz))
+    }
+}
+
+impl<In: Read, const N: usize> Unpack<In> for BoundedString<N> {
+    #[inline]
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        let (v, sz) = unpack_string(input, Some(N))?;
+        Ok((BoundedString(v), sz))
+    }
+}
+
+impl<In: Read, T: Unpack<In>, const N: usize> Unpack<In> for BoundedVec<T, N> {
+    #[inline]
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        let (v, sz) = unpack_flex(input, Some(N))?;
+        Ok((BoundedVec(v), sz))
+    }
+}
+
+impl<In: Read, const N: usize> Unpack<In> for BoundedOpaque<N> {
+    #[inline]
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        let (v, sz) = unpack_opaque_flex(input, Some(N))?;
+        Ok((BoundedOpaque(v), sz))
+    }
+}
+
 impl<In: Read, T: Unpack<In>> Unpack<In> for Option<T> {
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
         let (have, mut sz) = Unpack::unpack(input)?;
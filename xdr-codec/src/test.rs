@@ -1,10 +1,14 @@
 // Don't rustfmt in here to avoid trashing vec![] formatting
 #![cfg_attr(rustfmt, rustfmt_skip)]
 
-use std::io::Cursor;
-use super::{Error, ErrorKind, Pack, Unpack, Opaque,
+use std::io::{Cursor, Read};
+use super::{Error, ErrorKind, Pack, Unpack, Opaque, OpaqueArray, BoundedString, BoundedVec, BoundedOpaque,
+            XdrString, unpack_string_lossy, unpack_xdr_string,
             pack_flex, pack_opaque_flex, pack_string, pack_array, pack_opaque_array,
-            unpack_array, unpack_opaque_array, unpack_string, unpack_flex, unpack_opaque_flex};
+            unpack_array, unpack_opaque_array, unpack_string, unpack_flex, unpack_opaque_flex,
+            unpack_fixed_array, unpack_fixed_opaque_array, unpack_flex_iter, unpack_list_iter,
+            unpack_list_iter_with, OpaqueStream, pack_opaque_from, pack_opaque_stream, unpack_opaque_into,
+            unpack_opaque_stream};
 
 
 #[cfg(feature = "bytecodec")]
@@ -508,6 +512,60 @@ fn bounded_string() {
     }
 }
 
+#[test]
+fn invalid_utf8_string() {
+    let mut out = Cursor::new(Vec::new());
+
+    assert_eq!(XdrString(vec![b'a', 0xff, b'b']).pack(&mut out).unwrap(), 8);
+
+    let v = out.into_inner();
+    assert_eq!(v, vec![0x00, 0x00, 0x00, 0x03, b'a', 0xff, b'b', 0x00]);
+
+    {
+        let mut input = Cursor::new(v.clone());
+        match unpack_string(&mut input, None) {
+            Result::Err(Error(ErrorKind::InvalidUtf8(_), _)) => (),
+            e => panic!("Unexpected {:?}", e),
+        }
+    }
+    {
+        let mut input = Cursor::new(v.clone());
+        assert_eq!(unpack_string_lossy(&mut input, None).unwrap(), (String::from("a\u{fffd}b"), 8));
+    }
+    {
+        let mut input = Cursor::new(v.clone());
+        let (s, sz) = unpack_xdr_string(&mut input, Some(3)).unwrap();
+        assert_eq!(sz, 8);
+        assert_eq!(s.as_bytes(), &[b'a', 0xff, b'b']);
+        assert_eq!(s.to_string(), "a\u{fffd}b");
+        assert!(s.to_str().is_err());
+    }
+    {
+        let mut input = Cursor::new(v.clone());
+        match unpack_xdr_string(&mut input, Some(2)) {
+            Result::Err(Error(ErrorKind::InvalidLen(_), _)) => (),
+            e => panic!("Unexpected {:?}", e),
+        }
+    }
+    {
+        let mut input = Cursor::new(v);
+        assert_eq!(XdrString::unpack(&mut input).unwrap(), (XdrString(vec![b'a', 0xff, b'b']), 8));
+    }
+
+    assert_eq!(XdrString::from("hello"), "hello");
+}
+
+#[cfg(unix)]
+#[test]
+fn xdr_string_os_str() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let s = XdrString(vec![b'a', 0xff]);
+    assert_eq!(s.to_os_str().as_bytes(), &[b'a', 0xff]);
+    assert_eq!(XdrString::from(OsStr::from_bytes(&[0xfe])), XdrString(vec![0xfe]));
+}
+
 #[test]
 fn basic_array() {
     {
@@ -615,6 +673,194 @@ fn basic_array() {
     }
 }
 
+#[test]
+fn fixed_array() {
+    let v = vec![0x00, 0x00, 0x00, 0x11,
+                 0x00, 0x00, 0x00, 0x22,
+                 0x00, 0x00, 0x00, 0x33];
+
+    {
+        let mut input = Cursor::new(v.clone());
+        let (b, bsz): ([u32; 3], _) = unpack_fixed_array(&mut input, 3, None).expect("unpack failed");
+        assert_eq!(bsz, 3*4);
+        assert_eq!(b, [0x11, 0x22, 0x33]);
+    }
+
+    {
+        let mut input = Cursor::new(v.clone());
+        let (b, bsz): ([u32; 2], _) = unpack_fixed_array(&mut input, 3, None).expect("unpack failed");
+        assert_eq!(bsz, 3*4);
+        assert_eq!(b, [0x11, 0x22]);
+        assert_eq!(input.position(), 3*4);
+    }
+
+    {
+        let mut input = Cursor::new(v.clone());
+        let (b, bsz): ([u32; 4], _) = unpack_fixed_array(&mut input, 3, Some(&0)).expect("unpack failed");
+        assert_eq!(bsz, 3*4);
+        assert_eq!(b, [0x11, 0x22, 0x33, 0x00]);
+    }
+
+    {
+        let mut input = Cursor::new(v.clone());
+        let res: Result<([u32; 4], _), _> = unpack_fixed_array(&mut input, 3, None);
+        match res {
+            Err(Error(ErrorKind::InvalidLen(3), _)) => (),
+            e => panic!("bad result {:?}", e),
+        }
+    }
+}
+
+#[test]
+fn fixed_array_short() {
+    use std::rc::Rc;
+    use std::io::Read;
+
+    // Count live elements to make sure partially decoded arrays are cleaned up
+    #[derive(Clone)]
+    struct Counted { _live: Rc<()> }
+    thread_local!(static LIVE: Rc<()> = Rc::new(()));
+
+    impl<In: Read> Unpack<In> for Counted {
+        fn unpack(input: &mut In) -> super::Result<(Self, usize)> {
+            let (_, sz) = u32::unpack(input)?;
+            Ok((Counted { _live: LIVE.with(|l| l.clone()) }, sz))
+        }
+    }
+
+    let mut input = Cursor::new(vec![0x00, 0x00, 0x00, 0x11,
+                                     0x00, 0x00, 0x00, 0x22]);
+    let res: Result<([Counted; 3], _), _> = unpack_fixed_array(&mut input, 3, None);
+    assert!(res.is_err());
+    assert_eq!(LIVE.with(|l| Rc::strong_count(l)), 1);
+}
+
+#[test]
+fn fixed_opaque_array() {
+    let v = vec![0x11, 0x22, 0x33, 0x00];
+
+    {
+        let mut input = Cursor::new(v.clone());
+        let (b, bsz): ([u8; 3], _) = unpack_fixed_opaque_array(&mut input, 3).expect("unpack failed");
+        assert_eq!(bsz, 4);
+        assert_eq!(b, [0x11, 0x22, 0x33]);
+    }
+
+    {
+        let mut input = Cursor::new(v.clone());
+        let (b, bsz): ([u8; 4], _) = unpack_fixed_opaque_array(&mut input, 3).expect("unpack failed");
+        assert_eq!(bsz, 4);
+        assert_eq!(b, [0x11, 0x22, 0x33, 0x00]);
+    }
+}
+
+#[test]
+fn array_trait() {
+    let mut out = Cursor::new(Vec::new());
+    let a = [0x11u32, 0x22, 0x33];
+
+    assert_eq!(a.pack(&mut out).unwrap(), 3*4);
+
+    let v = out.into_inner();
+    assert_eq!(v, vec![0x00, 0x00, 0x00, 0x11,
+                       0x00, 0x00, 0x00, 0x22,
+                       0x00, 0x00, 0x00, 0x33]);
+
+    let mut input = Cursor::new(v);
+    assert_eq!(<[u32; 3]>::unpack(&mut input).unwrap(), (a, 3*4));
+
+    let mut input = Cursor::new(vec![0x00, 0x00, 0x00, 0x11]);
+    assert!(<[u32; 2]>::unpack(&mut input).is_err());
+}
+
+#[test]
+fn opaque_array_trait() {
+    let mut out = Cursor::new(Vec::new());
+    let a = OpaqueArray([0x11u8, 0x22, 0x33]);
+
+    assert_eq!(a.pack(&mut out).unwrap(), 4);
+
+    let v = out.into_inner();
+    assert_eq!(v, vec![0x11, 0x22, 0x33, 0x00]);
+
+    let mut input = Cursor::new(v);
+    assert_eq!(OpaqueArray::<3>::unpack(&mut input).unwrap(), (a, 4));
+    assert_eq!(input.position(), 4);
+
+    assert_eq!(OpaqueArray::<40>::default(), OpaqueArray([0; 40]));
+}
+
+#[test]
+fn bounded_string_type() {
+    assert!(BoundedString::<3>::new("abcd").is_err());
+    let s = BoundedString::<3>::new("abc").unwrap();
+    assert_eq!(s, "abc");
+    assert_eq!(s.len(), 3);
+
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(s.pack(&mut out).unwrap(), 8);
+
+    let v = out.into_inner();
+    assert_eq!(v, vec![0x00, 0x00, 0x00, 0x03, b'a', b'b', b'c', 0x00]);
+
+    let mut input = Cursor::new(v.clone());
+    assert_eq!(BoundedString::<3>::unpack(&mut input).unwrap(), (s, 8));
+
+    // Too long to unpack
+    let mut input = Cursor::new(v);
+    match BoundedString::<2>::unpack(&mut input) {
+        Err(Error(ErrorKind::InvalidLen(_), _)) => (),
+        e => panic!("bad result {:?}", e),
+    }
+}
+
+#[test]
+fn bounded_vec_type() {
+    assert!(BoundedVec::<u32, 2>::new(vec![1, 2, 3]).is_err());
+    let mut a = BoundedVec::<u32, 2>::default();
+    a.push(1).unwrap();
+    a.push(2).unwrap();
+    assert!(a.push(3).is_err());
+    a[1] = 5;
+    assert_eq!(&a[..], &[1, 5]);
+
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(a.pack(&mut out).unwrap(), 12);
+
+    let v = out.into_inner();
+    assert_eq!(v, vec![0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x05]);
+
+    let mut input = Cursor::new(v.clone());
+    assert_eq!(BoundedVec::<u32, 2>::unpack(&mut input).unwrap(), (a, 12));
+
+    let mut input = Cursor::new(v);
+    match BoundedVec::<u32, 1>::unpack(&mut input) {
+        Err(Error(ErrorKind::InvalidLen(_), _)) => (),
+        e => panic!("bad result {:?}", e),
+    }
+}
+
+#[test]
+fn bounded_opaque_type() {
+    assert!(BoundedOpaque::<4>::new(&[1u8, 2, 3, 4, 5][..]).is_err());
+    let a = BoundedOpaque::<4>::new(vec![0x11, 0x22, 0x33]).unwrap();
+
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(a.pack(&mut out).unwrap(), 8);
+
+    let v = out.into_inner();
+    assert_eq!(v, vec![0x00, 0x00, 0x00, 0x03, 0x11, 0x22, 0x33, 0x00]);
+
+    let mut input = Cursor::new(v.clone());
+    assert_eq!(BoundedOpaque::<4>::unpack(&mut input).unwrap(), (a, 8));
+
+    let mut input = Cursor::new(v);
+    match BoundedOpaque::<2>::unpack(&mut input) {
+        Err(Error(ErrorKind::InvalidLen(_), _)) => (),
+        e => panic!("bad result {:?}", e),
+    }
+}
+
 #[test]
 fn basic_opaque_array() {
     {
@@ -737,3 +983,144 @@ fn basic_option() {
         res => panic!("bad result {:?}", res),
     }
 }
+
+#[test]
+fn flex_iter() {
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(pack_flex(&vec![1u32, 2, 3], None, &mut out).unwrap(), 16);
+    assert_eq!(7u32.pack(&mut out).unwrap(), 4);
+    let v = out.into_inner();
+
+    let mut input = Cursor::new(v.clone());
+    {
+        let mut it = unpack_flex_iter::<_, u32>(&mut input, Some(3)).unwrap();
+        assert_eq!(it.remaining(), 3);
+        assert_eq!(it.next().unwrap().unwrap(), 1);
+        assert_eq!(it.size(), 8);
+        assert_eq!(it.collect::<Result<Vec<_>, _>>().unwrap(), vec![2, 3]);
+    }
+    assert_eq!(u32::unpack(&mut input).unwrap(), (7, 4));
+
+    let mut input = Cursor::new(v.clone());
+    {
+        let mut it = unpack_flex_iter::<_, u32>(&mut input, None).unwrap();
+        assert_eq!(it.next().unwrap().unwrap(), 1);
+        assert_eq!(it.finish().unwrap(), 16);
+    }
+    assert_eq!(u32::unpack(&mut input).unwrap(), (7, 4));
+
+    let mut input = Cursor::new(v.clone());
+    match unpack_flex_iter::<_, u32>(&mut input, Some(2)) {
+        Err(Error(ErrorKind::InvalidLen(2), _)) => (),
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("missing error"),
+    }
+
+    // Truncated input stops after the error
+    let mut input = Cursor::new(v[..12].to_vec());
+    let got: Vec<_> = unpack_flex_iter::<_, u32>(&mut input, None).unwrap().collect();
+    assert_eq!(got.len(), 3);
+    assert!(got[2].is_err());
+
+    // and finishing after the error fails rather than giving a partial size
+    let mut input = Cursor::new(v[..12].to_vec());
+    let mut it = unpack_flex_iter::<_, u32>(&mut input, None).unwrap();
+    assert!(it.by_ref().any(|r| r.is_err()));
+    assert!(it.finish().is_err());
+}
+
+#[test]
+fn list_iter() {
+    let mut out = Cursor::new(Vec::new());
+    for i in 0..3u32 {
+        true.pack(&mut out).unwrap();
+        i.pack(&mut out).unwrap();
+    }
+    false.pack(&mut out).unwrap();
+    7u32.pack(&mut out).unwrap();
+    let v = out.into_inner();
+
+    let mut input = Cursor::new(v.clone());
+    {
+        let it = unpack_list_iter::<_, u32>(&mut input);
+        assert_eq!(it.collect::<Result<Vec<_>, _>>().unwrap(), vec![0, 1, 2]);
+    }
+    assert_eq!(u32::unpack(&mut input).unwrap(), (7, 4));
+
+    let mut input = Cursor::new(v.clone());
+    {
+        let mut it = unpack_list_iter_with(&mut input, |i| {
+            u32::unpack(i).map(|(v, sz)| (v * 2, sz))
+        });
+        assert_eq!(it.next().unwrap().unwrap(), 0);
+        assert_eq!(it.next().unwrap().unwrap(), 2);
+        assert_eq!(it.finish().unwrap(), 28);
+    }
+    assert_eq!(u32::unpack(&mut input).unwrap(), (7, 4));
+
+    // A long list doesn't recurse
+    let mut out = Cursor::new(Vec::new());
+    for i in 0..100_000u32 {
+        true.pack(&mut out).unwrap();
+        i.pack(&mut out).unwrap();
+    }
+    false.pack(&mut out).unwrap();
+    let mut input = Cursor::new(out.into_inner());
+    assert_eq!(unpack_list_iter::<_, u32>(&mut input).count(), 100_000);
+
+    let mut input = Cursor::new(vec![0, 0, 0, 1, 0, 0]);
+    let got: Vec<_> = unpack_list_iter::<_, u32>(&mut input).collect();
+    assert_eq!(got.len(), 1);
+    assert!(got[0].is_err());
+
+    let mut input = Cursor::new(vec![0, 0, 0, 1, 0, 0]);
+    let mut it = unpack_list_iter::<_, u32>(&mut input);
+    assert!(it.next().unwrap().is_err());
+    assert!(it.finish().is_err());
+}
+
+#[test]
+fn opaque_stream() {
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(pack_opaque_from(&mut &[1u8, 2, 3, 4, 5][..], 3, Some(3), &mut out).unwrap(), 8);
+    let v = out.into_inner();
+    assert_eq!(v, vec![0, 0, 0, 3,  1, 2, 3, 0]);
+
+    let mut sink = Vec::new();
+    let mut input = Cursor::new(v.clone());
+    assert_eq!(unpack_opaque_into(&mut input, &mut sink, Some(3)).unwrap(), (3, 8));
+    assert_eq!(sink, vec![1, 2, 3]);
+    assert_eq!(input.position(), 8);
+
+    match unpack_opaque_into(&mut Cursor::new(v.clone()), &mut Vec::new(), Some(2)) {
+        Err(Error(ErrorKind::InvalidLen(2), _)) => (),
+        res => panic!("bad result {:?}", res),
+    }
+    match pack_opaque_from(&mut &[1u8, 2][..], 3, None, &mut Vec::new()) {
+        Err(Error(ErrorKind::IOError(_), _)) => (),
+        res => panic!("bad result {:?}", res),
+    }
+    match unpack_opaque_into(&mut Cursor::new(&v[..6]), &mut Vec::new(), None) {
+        Err(Error(ErrorKind::IOError(_), _)) => (),
+        res => panic!("bad result {:?}", res),
+    }
+
+    // A stream can only be packed once
+    let s = OpaqueStream::new(3, Cursor::new(vec![1, 2, 3]));
+    assert!(!s.is_consumed());
+    let mut out = Vec::new();
+    assert_eq!(s.pack(&mut out).unwrap(), 8);
+    assert_eq!(out, v);
+    assert!(s.is_consumed());
+    assert!(pack_opaque_stream(&s, None, &mut Vec::new()).is_err());
+    assert!(OpaqueStream::default().pack(&mut Vec::new()).is_ok());
+
+    let (s, sz) = unpack_opaque_stream(&mut Cursor::new(v.clone()), None).unwrap();
+    assert_eq!((s.len(), sz), (3, 8));
+    let mut data = Vec::new();
+    s.into_reader().unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data, vec![1, 2, 3]);
+
+    let (s, _) = OpaqueStream::unpack(&mut Cursor::new(v)).unwrap();
+    assert_eq!(format!("{:?}", s), "OpaqueStream { len: 3, consumed: false }");
+}
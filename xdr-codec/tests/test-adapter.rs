@@ -0,0 +1,120 @@
+// Don't rustfmt in here to avoid trashing vec![] formatting
+#![cfg_attr(rustfmt, rustfmt_skip)]
+
+extern crate xdr_codec;
+
+use std::io::{BufRead, Cursor, ErrorKind, Read, Write};
+
+use xdr_codec::{Error, Pack, Unpack, pack_opaque_flex, unpack_opaque_flex};
+use xdr_codec::adapter::{XdrReader, XdrWriter};
+use xdr_codec::record::{XdrRecordReader, XdrRecordWriter};
+
+fn is_limit(err: Error) -> bool {
+    match err {
+        Error(xdr_codec::ErrorKind::IOError(ref e), _) => e.kind() == ErrorKind::InvalidData,
+        _ => false,
+    }
+}
+
+#[test]
+fn reader_position() {
+    let mut buf = Vec::new();
+    1u32.pack(&mut buf).unwrap();
+    pack_opaque_flex(&[1, 2, 3], None, &mut buf).unwrap();
+    2u32.pack(&mut buf).unwrap();
+
+    let mut rd = XdrReader::new(Cursor::new(buf));
+    assert_eq!(rd.remaining(), None);
+    assert_eq!(u32::unpack(&mut rd).unwrap(), (1, 4));
+    assert_eq!(rd.position(), 4);
+    assert!(rd.is_aligned());
+
+    // Skip the opaque length and data, then its padding
+    rd.skip(7).unwrap();
+    assert_eq!(rd.position(), 11);
+    assert!(!rd.is_aligned());
+    assert_eq!(rd.align().unwrap(), 1);
+    assert_eq!(rd.align().unwrap(), 0);
+    assert_eq!(u32::unpack(&mut rd).unwrap(), (2, 4));
+    assert_eq!(rd.position(), 16);
+
+    assert!(rd.skip(1).is_err());
+    assert_eq!(rd.into_inner().position(), 16);
+}
+
+#[test]
+fn reader_limit() {
+    let mut buf = Vec::new();
+    pack_opaque_flex(&[1, 2, 3, 4, 5, 6, 7, 8], None, &mut buf).unwrap();
+
+    let mut rd = XdrReader::with_limit(&buf[..], 8);
+    assert_eq!(rd.remaining(), Some(8));
+    match unpack_opaque_flex(&mut rd, None) {
+        Err(e) => assert!(is_limit(e)),
+        Ok(v) => panic!("unexpected success {:?}", v),
+    }
+    assert_eq!(rd.position(), 8);
+    assert_eq!(rd.remaining(), Some(0));
+
+    let mut rd = XdrReader::with_limit(&buf[..], 8);
+    rd.set_limit(None);
+    assert_eq!(unpack_opaque_flex(&mut rd, None).unwrap().1, 12);
+
+    // A read at the limit is fine, and a read past it fails
+    let mut rd = XdrReader::with_limit(&buf[..], 4);
+    let mut b = [0; 8];
+    assert_eq!(rd.read(&mut b).unwrap(), 4);
+    assert_eq!(rd.read(&mut []).unwrap(), 0);
+    assert_eq!(rd.read(&mut b).unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn reader_bufread() {
+    let mut buf = Vec::new();
+    let mut wr = XdrRecordWriter::new(&mut buf);
+    1u32.pack(&mut wr).unwrap();
+    2u32.pack(&mut wr).unwrap();
+    wr.flush_eor(true).unwrap();
+    drop(wr);
+
+    let mut rd = XdrReader::with_limit(XdrRecordReader::new(&buf[..]), 6);
+    assert_eq!(rd.fill_buf().unwrap(), &[0, 0, 0, 1, 0, 0]);
+    rd.consume(4);
+    assert_eq!(rd.position(), 4);
+    assert_eq!(rd.fill_buf().unwrap(), &[0, 0]);
+    rd.consume(2);
+    assert_eq!(rd.fill_buf().unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn writer_position() {
+    let mut wr = XdrWriter::new(Vec::new());
+    assert_eq!(1u32.pack(&mut wr).unwrap(), 4);
+    assert_eq!(wr.position(), 4);
+    wr.write_all(&[1, 2, 3]).unwrap();
+    assert!(!wr.is_aligned());
+    assert_eq!(wr.align().unwrap(), 1);
+    assert!(wr.is_aligned());
+    assert_eq!(wr.align().unwrap(), 0);
+    assert_eq!(wr.position(), 8);
+    assert_eq!(wr.into_inner(), vec![0, 0, 0, 1,  1, 2, 3, 0]);
+}
+
+#[test]
+fn writer_limit() {
+    let mut wr = XdrWriter::with_limit(Vec::new(), 6);
+    assert_eq!(wr.remaining(), Some(6));
+    1u32.pack(&mut wr).unwrap();
+    match 2u32.pack(&mut wr) {
+        Err(e) => assert!(is_limit(e)),
+        Ok(v) => panic!("unexpected success {:?}", v),
+    }
+    assert_eq!(wr.position(), 4);
+    assert_eq!(wr.remaining(), Some(2));
+    wr.write_all(&[1, 2]).unwrap();
+    assert_eq!(wr.write(&[3]).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    wr.set_limit(None);
+    2u32.pack(&mut wr).unwrap();
+    assert_eq!(wr.get_ref(), &vec![0, 0, 0, 1,  1, 2,  0, 0, 0, 2]);
+}
@@ -4,9 +4,9 @@ use std::io::Cursor;
 use xdr_codec::{unpack,pack};
 
 mod simple {
+    #![allow(dead_code)]
     use xdr_codec;
-    
-    #[allow(dead_code)]
+
     include!(concat!(env!("OUT_DIR"), "/simple_xdr.rs"));
 }
 
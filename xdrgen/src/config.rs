@@ -0,0 +1,394 @@
+//! Code generation options
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use crate::spec::Derives;
+
+/// Options controlling the generated code.
+///
+/// The default configuration generates the same code as `generate` and `compile`. Options are set
+/// with builder-style methods:
+///
+/// ```ignore
+/// let config = xdrgen::Config::new()
+///     .derive("Hash")
+///     .derive("serde::Serialize")
+///     .type_attribute("file", "#[serde(rename_all = \"camelCase\")]");
+///
+/// xdrgen::compile_with_config("src/simple.x", &config).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Config {
+    derives: Derives,
+    extra_derives: Vec<String>,
+    type_derives: BTreeMap<String, Vec<String>>,
+    attributes: Vec<String>,
+    type_attributes: BTreeMap<String, Vec<String>>,
+    pub(crate) allow_undefined_types: bool,
+    pub(crate) warn_unused: bool,
+    pub(crate) include_dirs: Vec<PathBuf>,
+    pub(crate) file_modules: bool,
+    pub(crate) extern_types: BTreeMap<String, ExternType>,
+    pub(crate) passthrough: Passthrough,
+    pub(crate) defines: BTreeMap<String, i64>,
+    pub(crate) union_labels: bool,
+    pub(crate) shared_arms: bool,
+    pub(crate) builders: bool,
+    pub(crate) bounded_types: bool,
+    pub(crate) iterative_drop: bool,
+    strings: Strings,
+    string_fields: BTreeMap<String, Strings>,
+    stream_fields: BTreeSet<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            derives: Derives::COPY | Derives::CLONE | Derives::DEBUG | Derives::EQ | Derives::PARTIALEQ,
+            extra_derives: Vec::new(),
+            type_derives: BTreeMap::new(),
+            attributes: Vec::new(),
+            type_attributes: BTreeMap::new(),
+            allow_undefined_types: true,
+            warn_unused: false,
+            include_dirs: Vec::new(),
+            file_modules: false,
+            extern_types: BTreeMap::new(),
+            passthrough: Passthrough::Off,
+            defines: BTreeMap::new(),
+            union_labels: false,
+            shared_arms: false,
+            builders: false,
+            bounded_types: false,
+            iterative_drop: false,
+            strings: Strings::Utf8,
+            string_fields: BTreeMap::new(),
+            stream_fields: BTreeSet::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config::default()
+    }
+
+    /// Set the standard traits to derive.
+    ///
+    /// Each trait is only derived on types where xdrgen can tell it's derivable: for example
+    /// floating-point fields rule out `Eq`, `Ord` and `Hash`, and unions never derive `Default`.
+    pub fn derives(mut self, derives: Derives) -> Config {
+        self.derives = derives;
+        self
+    }
+
+    /// Add a trait to derive on all generated types.
+    ///
+    /// The name of a standard trait (`Copy`, `Clone`, `Debug`, `Eq`, `PartialEq`, `Hash`,
+    /// `PartialOrd`, `Ord` or `Default`) is added to the standard set, and so is only derived where
+    /// possible. Anything else is a path to a derive macro, and is derived unconditionally.
+    pub fn derive<S: AsRef<str>>(mut self, path: S) -> Config {
+        match Derives::from_name(path.as_ref()) {
+            Some(d) => self.derives |= d,
+            None => self.extra_derives.push(path.as_ref().to_string()),
+        }
+        self
+    }
+
+    /// Add a derive macro for a single type, named as it is in the specification.
+    pub fn type_derive<T: AsRef<str>, S: AsRef<str>>(mut self, ty: T, path: S) -> Config {
+        self.type_derives
+            .entry(ty.as_ref().to_string())
+            .or_default()
+            .push(path.as_ref().to_string());
+        self
+    }
+
+    /// Add an attribute, such as `#[serde(deny_unknown_fields)]`, to all generated structs and
+    /// enums. Type aliases don't get attributes.
+    pub fn attribute<S: AsRef<str>>(mut self, attr: S) -> Config {
+        self.attributes.push(attr.as_ref().to_string());
+        self
+    }
+
+    /// Add an attribute for a single type, named as it is in the specification.
+    pub fn type_attribute<T: AsRef<str>, S: AsRef<str>>(mut self, ty: T, attr: S) -> Config {
+        self.type_attributes
+            .entry(ty.as_ref().to_string())
+            .or_default()
+            .push(attr.as_ref().to_string());
+        self
+    }
+
+    /// Allow references to types which aren't defined in the specification.
+    ///
+    /// They're allowed by default, with a warning, and must be defined alongside the generated
+    /// code with their own `Pack` and `Unpack` implementations. Disallowing them makes them an
+    /// error instead, which catches misspelt type names before the generated code is compiled.
+    pub fn allow_undefined_types(mut self, allow: bool) -> Config {
+        self.allow_undefined_types = allow;
+        self
+    }
+
+    /// Warn about types and constants which are defined but never used within the
+    /// specification. Off by default, since top-level types are usually only used by code
+    /// outside the specification.
+    pub fn warn_unused(mut self, warn: bool) -> Config {
+        self.warn_unused = warn;
+        self
+    }
+
+    /// Add a directory to search for `#include`d files which aren't found relative to the file
+    /// including them. Directories are searched in the order they're added.
+    pub fn include_dir<P: AsRef<Path>>(mut self, dir: P) -> Config {
+        self.include_dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Generate a module for each `#include`d file, named after the file (`common.x` becomes
+    /// `common_x`), rather than putting all definitions at the top level. Each module imports the definitions it includes, and the
+    /// main file's definitions stay at the top level.
+    pub fn file_modules(mut self, modules: bool) -> Config {
+        self.file_modules = modules;
+        self
+    }
+
+    /// Refer to a type by a Rust path instead of generating it, for types which are generated
+    /// elsewhere, such as in another crate from a shared specification file.
+    ///
+    /// `name` is the type's name in the specification. The type doesn't need to be defined in the
+    /// specification, but if it is (for example by an `#include`), the definition is only used
+    /// for its constants.
+    pub fn extern_type<S: AsRef<str>>(mut self, name: S, ty: ExternType) -> Config {
+        self.extern_types.insert(name.as_ref().to_string(), ty);
+        self
+    }
+
+    /// Copy lines starting with `%` into the generated code, at the same position relative to the
+    /// definitions around them.
+    pub fn passthrough(mut self, passthrough: Passthrough) -> Config {
+        self.passthrough = passthrough;
+        self
+    }
+
+    /// Define a preprocessor symbol, as though by `#define` before the start of the specification.
+    ///
+    /// xdrgen only supports integer `#define`s, which also define constants, and conditional
+    /// sections (`#if`, `#ifdef`, `#ifndef`, `#elif`, `#else` and `#endif`). Symbols defined here
+    /// are only used for conditionals.
+    pub fn define<S: AsRef<str>>(mut self, name: S, value: i64) -> Config {
+        self.defines.insert(name.as_ref().to_string(), value);
+        self
+    }
+
+    /// Generate union arms as struct-like variants, `case { field: T }`, named after the field
+    /// from the specification, rather than as tuple variants.
+    ///
+    /// Variants are normally named after their case values, and numeric cases are named like
+    /// `Const5`. With labels, a numeric case is named after its arm's field instead, as long as no
+    /// other arm has the same field name; numeric cases of a `bool` union are named `TRUE` and
+    /// `FALSE`.
+    pub fn union_labels(mut self, labels: bool) -> Config {
+        self.union_labels = labels;
+        self
+    }
+
+    /// Generate one union variant for the cases sharing an arm, as in `case A: case B: int x;`,
+    /// rather than a variant for each case. The variant holds the discriminant as well as the
+    /// arm's value, as the first field of a tuple variant or a `discriminant` field with
+    /// `union_labels`.
+    ///
+    /// The variant is named after the arm's field if no other arm has a field with the same name,
+    /// and otherwise after all its cases, joined with `_`.
+    pub fn shared_arms(mut self, shared: bool) -> Config {
+        self.shared_arms = shared;
+        self
+    }
+
+    /// Generate a builder for each struct, and derive `Default` wherever possible.
+    ///
+    /// `foo::builder()` returns a `fooBuilder`, which has a method to set each field and a
+    /// `build()` method which returns the struct. Fields which aren't set are left as their
+    /// default, and `build()` fails if a field has no default and hasn't been set, or if an
+    /// array, string or opaque field is longer than the specification allows. Fields with
+    /// [`bounded_types`](Config::bounded_types) are set from the plain `String` or `Vec`.
+    pub fn builders(mut self, builders: bool) -> Config {
+        self.builders = builders;
+        self
+    }
+
+    /// Use xdr-codec's `BoundedString<N>`, `BoundedOpaque<N>` and `BoundedVec<T, N>` for strings,
+    /// opaque data and arrays with a maximum size, such as `string name<255>`, rather than
+    /// `String` and `Vec`. Their constructors check the size, so values which are too large to pack
+    /// can't be made.
+    pub fn bounded_types(mut self, bounded: bool) -> Config {
+        self.bounded_types = bounded;
+        self
+    }
+
+    /// Implement `Drop` for lists, such as `struct entry { ...; entry *next; }`, unlinking them a
+    /// node at a time so dropping a long list can't overflow the stack.
+    ///
+    /// Lists are always packed and unpacked a node at a time, but a struct which implements `Drop`
+    /// can't have its fields moved out of it, so this is off by default. The derived traits, such
+    /// as `Clone`, `PartialEq` and `Debug`, still recurse for each node.
+    pub fn iterative_drop(mut self, iterative: bool) -> Config {
+        self.iterative_drop = iterative;
+        self
+    }
+
+    /// How to represent `string`s: as `String`, which must be valid UTF-8, as `String` with
+    /// invalid UTF-8 replaced, or as bytes with xdr-codec's `XdrString`.
+    pub fn strings(mut self, strings: Strings) -> Config {
+        self.strings = strings;
+        self
+    }
+
+    /// How to represent a single `string`, overriding `strings`. `path` is `type.field` for a
+    /// field of a struct or an arm of a union, or the type's name for a typedef such as
+    /// `typedef string filename<>;`.
+    pub fn string_field<S: AsRef<str>>(mut self, path: S, strings: Strings) -> Config {
+        self.string_fields.insert(path.as_ref().to_string(), strings);
+        self
+    }
+
+    pub(crate) fn strings_for(&self, path: &str) -> Strings {
+        self.string_fields.get(path).cloned().unwrap_or(self.strings)
+    }
+
+    /// Stream a variable-length `opaque` rather than holding it in memory, using xdr-codec's
+    /// `OpaqueStream`. `path` is as for `string_field`.
+    ///
+    /// The data is packed from the stream's reader. A typedef, struct or union containing streamed
+    /// data, directly or in another type, also has an `unpack_into` function, which writes the data
+    /// to a sink as it's unpacked; the `Unpack` impl reads it into memory. A struct can only have
+    /// one streamed field, since there's only one sink.
+    pub fn stream_field<S: AsRef<str>>(mut self, path: S) -> Config {
+        self.stream_fields.insert(path.as_ref().to_string());
+        self
+    }
+
+    pub(crate) fn is_stream(&self, path: &str) -> bool {
+        self.stream_fields.contains(path)
+    }
+
+    pub(crate) fn std_derives(&self) -> Derives {
+        if self.builders {
+            self.derives | Derives::DEFAULT
+        } else {
+            self.derives
+        }
+    }
+
+    /// Extra derive macros for a type
+    pub(crate) fn extra_derives<'a>(&'a self, ty: &str) -> impl Iterator<Item = &'a String> {
+        self.extra_derives
+            .iter()
+            .chain(self.type_derives.get(ty).into_iter().flatten())
+    }
+
+    /// Extra attributes for a type
+    pub(crate) fn attributes<'a>(&'a self, ty: &str) -> impl Iterator<Item = &'a String> {
+        self.attributes
+            .iter()
+            .chain(self.type_attributes.get(ty).into_iter().flatten())
+    }
+}
+
+/// What's known about a type which is defined outside the generated code.
+///
+/// xdrgen needs to know which traits the type implements to work out what can be derived on the
+/// types which contain it, and how it's represented in optional data (`type *name`).
+///
+/// ```ignore
+/// let config = xdrgen::Config::new()
+///     .extern_type("nfs_fh3", xdrgen::ExternType::new("::nfs_common::nfs_fh3")
+///         .derives(xdrgen::Derives::CLONE | xdrgen::Derives::DEBUG));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ExternType {
+    pub(crate) path: String,
+    pub(crate) derives: Derives,
+    pub(crate) boxed: bool,
+    pub(crate) primitive: bool,
+}
+
+impl ExternType {
+    /// A type at `path`, which implements `Pack` and `Unpack` but is assumed to derive nothing.
+    pub fn new<S: AsRef<str>>(path: S) -> ExternType {
+        ExternType {
+            path: path.as_ref().to_string(),
+            derives: Derives::empty(),
+            boxed: true,
+            primitive: false,
+        }
+    }
+
+    /// Standard traits the type implements.
+    pub fn derives(mut self, derives: Derives) -> ExternType {
+        self.derives = derives;
+        self
+    }
+
+    /// Whether the type is boxed when it's optional. This is true by default, and should be false
+    /// if the type is itself an array, optional, or a primitive.
+    pub fn boxed(mut self, boxed: bool) -> ExternType {
+        self.boxed = boxed;
+        self
+    }
+
+    /// Whether the type is a primitive, like an integer or `bool`. Primitives are never boxed, and
+    /// are assumed to implement all the standard traits.
+    pub fn primitive(mut self, primitive: bool) -> ExternType {
+        self.primitive = primitive;
+        self
+    }
+}
+
+/// Which lines starting with `%` are copied into the generated code.
+///
+/// rpcgen copies all of them into its C output, so specifications written for it may have C code
+/// in them. Lines starting with `%rust` are meant for xdrgen.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Passthrough {
+    /// None, which is the default
+    Off,
+    /// Only `%rust` lines
+    Rust,
+    /// All of them
+    All,
+}
+
+/// How `string`s are represented in the generated code.
+///
+/// XDR strings are meant to be ASCII, but are often used for data such as filenames, which can
+/// hold arbitrary bytes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Strings {
+    /// `String`, which fails to unpack if it isn't valid UTF-8. This is the default.
+    Utf8,
+    /// `String`, with invalid UTF-8 replaced by U+FFFD when unpacking
+    Lossy,
+    /// xdr-codec's `XdrString`, which keeps the bytes as they are
+    Bytes,
+}
+
+impl Passthrough {
+    /// The code to emit for a `%` line, given the text after the `%`. The `rust` marker is
+    /// removed, along with a space after it.
+    pub(crate) fn code<'a>(&self, text: &'a str) -> Option<&'a str> {
+        let rust = match text.strip_prefix("rust") {
+            Some("") => Some(""),
+            Some(rest) if rest.starts_with([' ', '\t']) => Some(&rest[1..]),
+            _ => None,
+        };
+
+        match (*self, rust) {
+            (Passthrough::Off, _) => None,
+            (_, Some(code)) => Some(code),
+            (Passthrough::Rust, None) => None,
+            (Passthrough::All, None) => Some(text),
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Loading a specification which is split across files with `#include`
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::spec::{self, Defn, Diagnostic, Location};
+use crate::xdr::{Error, Result};
+
+/// One file of a specification.
+pub(crate) struct SpecFile {
+    /// Module for the file's definitions, when generating a module per file
+    pub module: String,
+    /// Indices of the files it includes
+    pub includes: Vec<usize>,
+    pub defns: Vec<(Location, Defn)>,
+}
+
+struct Loader<'a> {
+    config: &'a Config,
+    files: Vec<SpecFile>,
+    // Index of each file which has been loaded, so that each is only loaded once
+    loaded: HashMap<PathBuf, usize>,
+    warnings: Vec<Diagnostic>,
+}
+
+// Identify a file independently of how it was named, if possible.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn error(loc: &Location, msg: String) -> Error {
+    Error::from(Diagnostic::error(loc, msg).to_string())
+}
+
+impl<'a> Loader<'a> {
+    fn load(&mut self, name: &str, path: &Path, source: &str) -> Result<usize> {
+        let source = match spec::preprocess(source, &self.config.defines) {
+            Ok(source) => source,
+            Err(mut e) => {
+                e.location.set_file(name);
+                return Err(Error::from(e.to_string()));
+            }
+        };
+        let mut defns = match spec::specification(&source) {
+            Ok(defns) => spec::hoist_types(defns),
+            Err(e) => return Err(Error::from(e.with_file(name).to_string())),
+        };
+
+        // Register the file before loading its includes, so an include cycle ends here
+        let idx = self.files.len();
+        let module = self.module_name(path);
+        self.loaded.insert(canonical(path), idx);
+        self.files.push(SpecFile {
+            module: module,
+            includes: Vec::new(),
+            defns: Vec::new(),
+        });
+
+        let mut includes = Vec::new();
+        for &mut (ref mut loc, ref defn) in &mut defns {
+            loc.set_file(name);
+            if let &Defn::Include(ref file, system) = defn {
+                includes.push(self.include(path, loc, file, system)?);
+            }
+        }
+
+        // Leave out the includes which were skipped
+        let mut found = includes.iter();
+        defns.retain(|&(_, ref defn)| match defn {
+            &Defn::Include(..) => found.next().is_some_and(Option::is_some),
+            _ => true,
+        });
+
+        self.files[idx].includes = includes.into_iter().flatten().collect();
+        self.files[idx].defns = defns;
+        Ok(idx)
+    }
+
+    // Find an included file relative to the including one, then in the include directories.
+    //
+    // Specifications written for rpcgen often include system headers such as `<rpc/types.h>`,
+    // which only mean something to C, so a `<>` include which can't be found is skipped with a
+    // warning rather than being an error.
+    fn include(&mut self, from: &Path, loc: &Location, file: &str, system: bool) -> Result<Option<usize>> {
+        let dir = from.parent().unwrap_or_else(|| Path::new(""));
+        let found = Some(dir)
+            .into_iter()
+            .chain(self.config.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(file))
+            .find(|path| path.is_file());
+
+        let path = match found {
+            Some(path) => path,
+            None if system => {
+                let msg = format!("can't find included file `{}`, so skipping it", file);
+                self.warnings.push(Diagnostic::warning(loc, msg));
+                return Ok(None);
+            }
+            None => return Err(error(loc, format!("can't find included file `{}`", file))),
+        };
+
+        if let Some(&idx) = self.loaded.get(&canonical(&path)) {
+            return Ok(Some(idx));
+        }
+
+        let mut source = String::new();
+        if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut source)) {
+            return Err(error(loc, format!("can't read included file `{}`: {}", path.display(), e)));
+        }
+
+        self.load(&path.to_string_lossy(), &path, &source).map(Some)
+    }
+
+    // A module name from the file name, made unique among the files so far. The extension is kept,
+    // so `common.x` becomes `common_x`, which won't clash with a type named after the file.
+    fn module_name(&self, path: &Path) -> String {
+        let file = path.file_name().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+        let mut base: String = file.chars()
+            .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
+            .collect();
+        if !base.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            base.insert(0, '_');
+        }
+
+        let mut module = base.clone();
+        let mut n = 1;
+        while self.files.iter().any(|f| f.module == module) {
+            n += 1;
+            module = format!("{}_{}", base, n);
+        }
+        module
+    }
+}
+
+// Add the definitions from `files[idx]` to `defns`, with each included file's definitions in place
+// of the first `#include` of it.
+fn flatten_file(files: &[SpecFile], idx: usize, seen: &mut Vec<bool>, defns: &mut Vec<(Location, Defn)>) {
+    seen[idx] = true;
+
+    let mut includes = files[idx].includes.iter();
+    for defn in &files[idx].defns {
+        if let (_, Defn::Include(..)) = *defn {
+            let inc = *includes.next().expect("include not loaded");
+            if !seen[inc] {
+                flatten_file(files, inc, seen, defns);
+            }
+        }
+        defns.push(defn.clone());
+    }
+}
+
+/// All the definitions in a specification in source order, as though the included files were
+/// pasted in.
+pub(crate) fn flatten(files: &[SpecFile]) -> Vec<(Location, Defn)> {
+    let mut defns = Vec::new();
+
+    if !files.is_empty() {
+        flatten_file(files, 0, &mut vec![false; files.len()], &mut defns);
+    }
+    defns
+}
+
+/// Parse the specification in `source` and all the files it includes, directly or indirectly.
+///
+/// `name` is the name of the main file, and is used to find files it includes. The main file is
+/// first in the result, which also has any warnings about the includes.
+pub(crate) fn load(name: &str, source: &str, config: &Config) -> Result<(Vec<SpecFile>, Vec<Diagnostic>)> {
+    let mut loader = Loader {
+        config: config,
+        files: Vec::new(),
+        loaded: HashMap::new(),
+        warnings: Vec::new(),
+    };
+
+    loader.load(name, Path::new(name), source)?;
+
+    Ok((loader.files, loader.warnings))
+}
@@ -27,7 +27,7 @@ extern crate bitflags;
 
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::io::{Read, Write};
+use std::io::{stderr, Read, Write};
 use std::fmt::Display;
 use std::env;
 use std::result;
@@ -35,7 +35,13 @@ use std::result;
 use crate::xdr::Result;
 
 mod spec;
-use crate::spec::{Emit, Emitpack, Symtab};
+use crate::spec::{Defn, Emit, Emitpack, Symtab};
+
+mod config;
+pub use crate::config::{Config, ExternType, Passthrough, Strings};
+
+mod include;
+pub use crate::spec::{Derives, Diagnostic, Location, ParseError, Severity};
 
 fn result_option<T, E>(resopt: result::Result<Option<T>, E>) -> Option<result::Result<T, E>> {
     match resopt {
@@ -48,8 +54,30 @@ fn result_option<T, E>(resopt: result::Result<Option<T>, E>) -> Option<result::R
 /// Generate Rust code from an RFC4506 XDR specification
 ///
 /// `infile` is simply a string used in error messages; it may be empty. `input` is a read stream of
-/// the specification, and `output` is where the generated code is sent.
-pub fn generate<In, Out>(infile: &str, mut input: In, mut output: Out) -> Result<()>
+/// the specification, and `output` is where the generated code is sent. Warnings about the
+/// specification are written to stderr.
+pub fn generate<In, Out>(infile: &str, input: In, output: Out) -> Result<()>
+where
+    In: Read,
+    Out: Write,
+{
+    for warning in generate_with_config(infile, input, output, &Config::default())? {
+        let _ = writeln!(stderr(), "{}", warning);
+    }
+    Ok(())
+}
+
+/// Generate Rust code from an RFC4506 XDR specification, with options set by `config`.
+///
+/// Files named by `#include` directives are found relative to `infile`, then in the
+/// configured include directories. Warnings about the specification are returned for the caller
+/// to report. If there are errors, no code is generated, and the error has the warnings as well.
+pub fn generate_with_config<In, Out>(
+    infile: &str,
+    mut input: In,
+    mut output: Out,
+    config: &Config,
+) -> Result<Vec<Diagnostic>>
 where
     In: Read,
     Out: Write,
@@ -58,44 +86,21 @@ where
 
     input.read_to_string(&mut source)?;
 
-    let xdr = match spec::specification(&source) {
-        Ok(defns) => Symtab::new(&defns),
-        Err(e) => return Err(xdr::Error::from(format!("parse error: {}", e))),
-    };
+    let (files, mut warnings) = include::load(infile, &source, config)?;
+    let defns = include::flatten(&files);
+    let xdr = Symtab::new(&defns, config);
 
-    let xdr = xdr;
+    let (errors, more): (Vec<_>, Vec<_>) = xdr.validate().into_iter().partition(|d| d.is_error());
+    warnings.extend(more);
+    if !errors.is_empty() {
+        let diags: Vec<_> = errors.iter().chain(&warnings).map(|d| d.to_string()).collect();
+        return Err(xdr::Error::from(diags.join("\n")));
+    }
 
-    let res: Vec<_> = {
-        let consts = xdr.constants()
-            .filter_map(|(c, &(v, ref scope))| if scope.is_none() {
-                Some(spec::Const(c.clone(), v))
-            } else {
-                None
-            })
-            .map(|c| c.define(&xdr));
-
-        let typespecs = xdr.typespecs()
-            .map(|(n, ty)| spec::Typespec(n.clone(), ty.clone()))
-            .map(|c| c.define(&xdr));
-
-        let typesyns = xdr.typesyns()
-            .map(|(n, ty)| spec::Typesyn(n.clone(), ty.clone()))
-            .map(|c| c.define(&xdr));
-
-        let packers = xdr.typespecs()
-            .map(|(n, ty)| spec::Typespec(n.clone(), ty.clone()))
-            .filter_map(|c| result_option(c.pack(&xdr)));
-
-        let unpackers = xdr.typespecs()
-            .map(|(n, ty)| spec::Typespec(n.clone(), ty.clone()))
-            .filter_map(|c| result_option(c.unpack(&xdr)));
-
-        consts
-            .chain(typespecs)
-            .chain(typesyns)
-            .chain(packers)
-            .chain(unpackers)
-            .collect::<Result<Vec<_>>>()?
+    let res = if config.file_modules {
+        file_modules(&xdr, &files)?
+    } else {
+        emit(&xdr, &defns)?
     };
 
     let _ = writeln!(
@@ -115,7 +120,109 @@ where
         let _ = writeln!(output, "{}\n", it.as_str());
     }
 
-    Ok(())
+    Ok(warnings)
+}
+
+// Code for `defns`, in order, followed by their `Pack` and `Unpack` implementations.
+fn emit(xdr: &Symtab, defns: &[(Location, Defn)]) -> Result<Vec<quote::Tokens>> {
+    let mut res = Vec::new();
+    // Consecutive passthrough lines, kept together
+    let mut lines = Vec::new();
+
+    // External types are defined elsewhere, even if they're in the specification
+    let wanted = |name: &String| xdr.extern_type(name).is_none();
+
+    for &(_, ref defn) in defns {
+        if let &Defn::Passthrough(ref text) = defn {
+            lines.extend(xdr.passthrough(text));
+            continue;
+        }
+        if !lines.is_empty() {
+            let mut toks = quote::Tokens::new();
+            toks.append(lines.join("\n"));
+            res.push(toks);
+            lines.clear();
+        }
+
+        // The symbol table has the values of constant expressions
+        match defn {
+            &Defn::Const(ref name, ..) => {
+                if let Some((val, _)) = xdr.getconst(name) {
+                    res.push(spec::Const(name.clone(), val).define(xdr)?)
+                }
+            }
+            &Defn::Typespec(ref name, ..) if wanted(name) => {
+                if let Some(ty) = xdr.typespec(name) {
+                    res.push(spec::Typespec(name.clone(), ty.clone()).define(xdr)?)
+                }
+            }
+            &Defn::Typesyn(ref name, ..) if wanted(name) => {
+                if let Some(ty) = xdr.typespec(name) {
+                    res.push(spec::Typesyn(name.clone(), ty.clone()).define(xdr)?)
+                }
+            }
+            _ => (),
+        }
+    }
+    if !lines.is_empty() {
+        let mut toks = quote::Tokens::new();
+        toks.append(lines.join("\n"));
+        res.push(toks);
+    }
+
+    let typespecs: Vec<_> = defns
+        .iter()
+        .filter_map(|&(_, ref defn)| match defn {
+            &Defn::Typespec(ref name, ..) if wanted(name) => {
+                xdr.typespec(name).map(|ty| spec::Typespec(name.clone(), ty.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let packers = typespecs.iter().filter_map(|c| result_option(c.pack(xdr)));
+    let unpackers = typespecs.iter().filter_map(|c| result_option(c.unpack(xdr)));
+
+    for code in packers.chain(unpackers) {
+        res.push(code?);
+    }
+
+    Ok(res)
+}
+
+// Code with the main file's definitions at the top level, and a module for each included file.
+fn file_modules(xdr: &Symtab, files: &[include::SpecFile]) -> Result<Vec<quote::Tokens>> {
+    let mut res = Vec::new();
+
+    for (idx, file) in files.iter().enumerate() {
+        let body = emit(xdr, &file.defns)?;
+        let uses = file.includes.iter().map(|&inc| {
+            let module = spec::quote_ident(&files[inc].module);
+            if idx == 0 {
+                quote!(#[allow(unused_imports)] use self::#module::*;)
+            } else {
+                quote!(#[allow(unused_imports)] use super::#module::*;)
+            }
+        });
+
+        if idx == 0 {
+            res.extend(uses);
+            res.extend(body);
+        } else {
+            let module = spec::quote_ident(&file.module);
+            res.push(quote! {
+                pub mod #module {
+                    #[allow(unused_imports)]
+                    use super::*;
+                    #(#uses)*
+
+                    #(#body)*
+                }
+            });
+        }
+    }
+
+    Ok(res)
 }
 
 /// Simplest possible way to generate Rust code from an XDR specification.
@@ -141,8 +248,17 @@ where
 /// ```
 ///
 /// If your specification uses types which are not within the specification, you can provide your
-/// own implementations of `Pack` and `Unpack` for them.
+/// own implementations of `Pack` and `Unpack` for them. They're reported as Cargo warnings, or as
+/// errors if disallowed with `Config::allow_undefined_types(false)`.
 pub fn compile<P>(infile: P) -> Result<()>
+where
+    P: AsRef<Path> + Display,
+{
+    compile_with_config(infile, &Config::default())
+}
+
+/// Like `compile`, but with options set by `config`.
+pub fn compile_with_config<P>(infile: P, config: &Config) -> Result<()>
 where
     P: AsRef<Path> + Display,
 {
@@ -161,9 +277,18 @@ where
 
     let output = File::create(outdir)?;
 
-    generate(
+    let warnings = generate_with_config(
         infile.as_ref().as_os_str().to_str().unwrap_or("<unknown>"),
         input,
         output,
-    )
+        config,
+    )?;
+
+    // Cargo hides a build script's output unless it fails, apart from the warnings it's told about
+    for warning in warnings {
+        for line in warning.to_string().lines() {
+            println!("cargo:warning={}", line);
+        }
+    }
+    Ok(())
 }
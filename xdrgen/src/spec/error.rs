@@ -0,0 +1,266 @@
+// Reporting problems in a specification, with the location and offending source.
+use std::error;
+use std::fmt::{self, Display};
+
+/// Length in bytes of the token at the start of `text`: an identifier, number or single character.
+pub(crate) fn token_len(text: &str) -> usize {
+    match text.find(|c: char| !(c.is_alphanumeric() || c == '_')) {
+        Some(0) => text.chars().next().map_or(0, |c| c.len_utf8()),
+        Some(len) => len,
+        None => text.len(),
+    }
+}
+
+/// A position in a specification.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Location {
+    /// Name of the specification file, if known
+    pub file: String,
+    /// Line number, starting at 1
+    pub line: usize,
+    /// Column in characters, starting at 1
+    pub column: usize,
+    // The text of the line, for showing a snippet
+    text: Box<str>,
+    // Length in characters of the item at the location
+    len: usize,
+    // Source of the definition at the location, for finding the tokens within it: the whole of
+    // the lines it's on, and the byte range of the definition within them
+    span: Option<Box<(String, usize, usize)>>,
+}
+
+impl Location {
+    /// Location of `len` bytes at `offset` bytes into `source`.
+    pub(crate) fn new(source: &str, offset: usize, len: usize) -> Location {
+        let mut offset = offset.min(source.len());
+        while !source.is_char_boundary(offset) {
+            offset -= 1;
+        }
+
+        let start = source[..offset].rfind('\n').map_or(0, |p| p + 1);
+        let end = source[offset..].find('\n').map_or(source.len(), |p| offset + p);
+        let text = source[start..end].trim_end_matches('\r');
+        let column = source[start..offset].chars().count() + 1;
+        let len = source[offset..(offset + len).min(end)].chars().count();
+
+        Location {
+            file: String::new(),
+            line: source[..offset].matches('\n').count() + 1,
+            column: column,
+            text: text.into(),
+            len: len.max(1),
+            span: None,
+        }
+    }
+
+    /// Keep the source of a definition which starts at this location and ends at `end` bytes
+    /// into `source`, so that `find_token` can look within it.
+    pub(crate) fn with_span(mut self, source: &str, offset: usize, end: usize) -> Location {
+        let start = source[..offset].rfind('\n').map_or(0, |p| p + 1);
+        let eol = source[end..].find('\n').map_or(source.len(), |p| end + p);
+        self.span = Some(Box::new((source[start..eol].to_string(), offset - start, end - start)));
+        self
+    }
+
+    // The tokens of the definition at this location, with their offsets into its span, skipping
+    // whitespace and comments.
+    fn tokens(&self) -> Vec<(usize, &str)> {
+        let (span, from, to) = match self.span {
+            Some(ref span) => (&span.0, span.1, span.2),
+            None => return Vec::new(),
+        };
+        let mut toks = Vec::new();
+        let mut rest = &span[from..to];
+        while let Some(c) = rest.chars().next() {
+            let len = if rest.starts_with("/*") {
+                rest.find("*/").map_or(rest.len(), |p| p + 2)
+            } else if rest.starts_with("//") {
+                rest.find('\n').unwrap_or(rest.len())
+            } else {
+                let len = token_len(rest);
+                if !c.is_whitespace() {
+                    toks.push((to - rest.len(), &rest[..len]));
+                }
+                len
+            };
+            rest = &rest[len..];
+        }
+        toks
+    }
+
+    // Location of `len` bytes at `offset` into the span of the definition at this location.
+    fn within(&self, offset: usize, len: usize) -> Location {
+        let span = match self.span {
+            Some(ref span) => &span.0,
+            None => return self.clone(),
+        };
+        let mut loc = Location::new(span, offset, len);
+        loc.file = self.file.clone();
+        loc.line += self.line - 1;
+        loc
+    }
+
+    /// Location of the first use of the identifier `token` within the definition at this
+    /// location, skipping comments. Falls back to this location if it isn't found.
+    pub(crate) fn find_token(&self, token: &str) -> Location {
+        let ident = |tok: &str| tok.starts_with(|c: char| c.is_alphabetic() || c == '_');
+        match self.tokens().into_iter().find(|&(_, tok)| ident(tok) && tok == token) {
+            Some((offset, tok)) => self.within(offset, tok.len()),
+            None => self.clone(),
+        }
+    }
+
+    /// Location of the value of the `n`th case, counting from 0, of the union defined at this
+    /// location, skipping the cases of unions nested in it. Falls back to this location if there
+    /// isn't one.
+    pub(crate) fn find_case(&self, n: usize) -> Location {
+        let toks = self.tokens();
+        let mut depth = 0;
+        let mut cases = toks.iter().enumerate().filter(|&(_, &(_, tok))| {
+            match tok {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => (),
+            }
+            tok == "case" && depth == 1
+        });
+
+        // The value is everything up to the colon
+        let value = match cases.nth(n) {
+            Some((idx, _)) => &toks[idx + 1..],
+            None => return self.clone(),
+        };
+        let end = value.iter().position(|&(_, tok)| tok == ":").unwrap_or(value.len());
+        match (value.first(), value[..end].last()) {
+            (Some(&(start, _)), Some(&(last, tok))) => self.within(start, last + tok.len() - start),
+            _ => self.clone(),
+        }
+    }
+
+    /// Location of the token at `offset` bytes into `source`.
+    pub(crate) fn at_token(source: &str, offset: usize) -> Location {
+        Location::new(source, offset, token_len(&source[offset.min(source.len())..]))
+    }
+
+    pub(crate) fn set_file(&mut self, file: &str) {
+        self.file = file.to_string();
+    }
+
+    // Source line with the location underlined, in the style of rustc.
+    fn fmt_snippet(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lineno = self.line.to_string();
+        let gutter = " ".repeat(lineno.len());
+
+        // Keep tabs so the underline lines up
+        let indent: String = self.text
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "{}--> {}", gutter, self)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", lineno, self.text)?;
+        write!(f, "{} | {}{}", gutter, indent, "^".repeat(self.len))
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.file.is_empty() {
+            write!(f, "{}:{}", self.line, self.column)
+        } else {
+            write!(f, "{}:{}:{}", self.file, self.line, self.column)
+        }
+    }
+}
+
+/// An error parsing a specification.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    /// Where parsing failed
+    pub location: Location,
+    /// Descriptions of the tokens which would have been valid there
+    pub expected: Vec<String>,
+    /// Description of what was actually found
+    pub found: String,
+}
+
+impl ParseError {
+    pub(crate) fn with_file(mut self, file: &str) -> ParseError {
+        self.location.set_file(file);
+        self
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.expected.len() {
+            0 => write!(f, "parse error: unexpected {}", self.found)?,
+            1 => write!(f, "parse error: expected {}, found {}", self.expected[0], self.found)?,
+            _ => {
+                write!(
+                    f,
+                    "parse error: expected one of {}, found {}",
+                    self.expected.join(", "),
+                    self.found
+                )?
+            }
+        }
+        writeln!(f)?;
+        self.location.fmt_snippet(f)
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// How serious a `Diagnostic` is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Severity {
+    /// Code can't be generated
+    Error,
+    /// Code can be generated, but the specification is suspect
+    Warning,
+}
+
+/// A problem found when validating a specification.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// The definition containing the problem
+    pub location: Location,
+}
+
+impl Diagnostic {
+    pub(crate) fn error<S: Into<String>>(location: &Location, message: S) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            location: location.clone(),
+        }
+    }
+
+    pub(crate) fn warning<S: Into<String>>(location: &Location, message: S) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: message.into(),
+            location: location.clone(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        writeln!(f, "{}: {}", severity, self.message)?;
+        self.location.fmt_snippet(f)
+    }
+}
@@ -0,0 +1,149 @@
+// Give anonymous structs, unions and enums nested in other types names of their own, so they can be
+// generated like any other type.
+use std::collections::BTreeSet;
+
+use super::{Decl, Defn, Doc, Type, UnionCase};
+use super::error::Location;
+
+struct Hoister {
+    // Names of all the types and constants, so new names don't clash with them
+    names: BTreeSet<String>,
+    // Types which have been given names, in the order they're found
+    hoisted: Vec<Defn>,
+}
+
+impl Hoister {
+    // A name for a nested type, from the name of the containing type and the field holding it
+    fn name(&mut self, outer: &str, field: &str) -> String {
+        let base = format!("{}_{}", outer, field);
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.names.contains(&name) {
+            n += 1;
+            name = format!("{}_{}", base, n);
+        }
+        self.names.insert(name.clone());
+        name
+    }
+
+    // Replace anonymous types in `ty`, the type of `field` within `outer`, with references to
+    // named ones. The named types are added once their own nested types have been named.
+    fn ty(&mut self, outer: &str, field: &str, ty: Type) -> Type {
+        use super::Type::*;
+
+        match ty {
+            Option(ty) => Option(Box::new(self.ty(outer, field, *ty))),
+            Array(ty, sz) => Array(Box::new(self.ty(outer, field, *ty)), sz),
+            Flex(ty, sz) => Flex(Box::new(self.ty(outer, field, *ty)), sz),
+
+            ty @ Struct(_) | ty @ Union(..) | ty @ Enum(_) => {
+                let name = self.name(outer, field);
+                let ty = self.body(&name, ty);
+                self.hoisted.push(Defn::Typespec(name.clone(), ty, Doc::default()));
+                Type::ident(name)
+            }
+
+            ty => ty,
+        }
+    }
+
+    // Name the anonymous types within the body of the compound type `name`
+    fn body(&mut self, name: &str, ty: Type) -> Type {
+        use super::Type::*;
+
+        match ty {
+            Struct(decls) => Struct(decls.into_iter().map(|d| self.decl(name, d)).collect()),
+            Union(sel, cases, defl) => {
+                let sel = self.decl(name, *sel);
+
+                // Cases with several values are parsed into a copy of the arm per value, which
+                // should all end up with the same type
+                let mut done: Vec<(Decl, Decl)> = Vec::new();
+                let mut named = Vec::with_capacity(cases.len());
+                for UnionCase(val, decl, doc) in cases {
+                    let new = match done.iter().find(|&&(ref old, _)| *old == decl) {
+                        Some(&(_, ref new)) => new.clone(),
+                        None => {
+                            let new = self.decl(name, decl.clone());
+                            done.push((decl, new.clone()));
+                            new
+                        }
+                    };
+                    named.push(UnionCase(val, new, doc));
+                }
+
+                Union(Box::new(sel), named, defl.map(|(d, doc)| (Box::new(self.decl(name, *d)), doc)))
+            }
+            ty => ty,
+        }
+    }
+
+    // Name the anonymous types in the type of the definition `name`. A compound type is the
+    // definition itself, but one which is optional or in an array, as in
+    // `typedef struct { ... } *name;`, is named `name_item`.
+    fn top(&mut self, name: &str, ty: Type) -> Type {
+        match ty {
+            Type::Option(_) | Type::Array(..) | Type::Flex(..) => self.ty(name, "item", ty),
+            ty => self.body(name, ty),
+        }
+    }
+
+    fn decl(&mut self, outer: &str, decl: Decl) -> Decl {
+        match decl {
+            Decl::Void => Decl::Void,
+            Decl::Named(name, ty, doc) => {
+                let ty = self.ty(outer, &name, ty);
+                Decl::Named(name, ty, doc)
+            }
+        }
+    }
+}
+
+/// Replace structs, unions and enums which are declared inline in other types with named types.
+///
+/// A nested type is named after the type and field containing it, so the type of `u` in
+/// `struct outer { union switch (int x) { ... } u; }` becomes `outer_u`. Types which are
+/// optional or in an array at the top level of a typedef `name` are named `name_item`. Named types
+/// are added just before the definition they were nested in, and fields which share a union arm
+/// share the same type.
+pub fn hoist_types(defns: Vec<(Location, Defn)>) -> Vec<(Location, Defn)> {
+    let mut hoister = Hoister {
+        names: BTreeSet::new(),
+        hoisted: Vec::new(),
+    };
+
+    for &(_, ref defn) in &defns {
+        match defn {
+            &Defn::Typespec(ref name, ref ty, _) | &Defn::Typesyn(ref name, ref ty, _) => {
+                hoister.names.insert(name.clone());
+                if let &Type::Enum(ref defs) = ty {
+                    hoister.names.extend(defs.iter().map(|d| d.0.clone()));
+                }
+            }
+            &Defn::Const(ref name, ..) => {
+                hoister.names.insert(name.clone());
+            }
+            &Defn::Include(..) | &Defn::Passthrough(_) => (),
+        }
+    }
+
+    let mut ret = Vec::with_capacity(defns.len());
+    for (loc, defn) in defns {
+        let defn = match defn {
+            Defn::Typespec(name, ty, doc) => {
+                let ty = hoister.top(&name, ty);
+                Defn::Typespec(name, ty, doc)
+            }
+            Defn::Typesyn(name, ty, doc) => {
+                let ty = hoister.top(&name, ty);
+                Defn::Typesyn(name, ty, doc)
+            }
+            defn => defn,
+        };
+
+        ret.extend(hoister.hoisted.drain(..).map(|d| (loc.clone(), d)));
+        ret.push((loc, defn));
+    }
+
+    ret
+}
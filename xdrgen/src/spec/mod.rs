@@ -1,28 +1,60 @@
-use std::collections::btree_map::{BTreeMap, Iter};
+use std::collections::btree_map::BTreeMap;
 use std::collections::{HashMap, HashSet};
-use std::io::{Write, stderr};
+use std::fmt::{self, Display};
 
 use std::result;
 
 use quote::{self, ToTokens, Tokens};
 
 mod xdr_nom;
+mod error;
+mod validate;
+mod preprocess;
+mod hoist;
 
 use crate::xdr::Error;
 
 pub type Result<T> = result::Result<T, Error>;
 
 pub use self::xdr_nom::specification;
+pub use self::preprocess::preprocess;
+pub use self::hoist::hoist_types;
+pub use self::error::{Diagnostic, Location, ParseError, Severity};
 
 use super::result_option;
+use crate::config::{Config, ExternType, Strings};
 
 bitflags! {
+    /// Set of standard traits which can be derived for generated types.
     pub struct Derives: u32 {
         const COPY = 1 << 0;
         const CLONE = 1 << 1;
         const DEBUG = 1 << 2;
         const EQ = 1 << 3;
         const PARTIALEQ = 1 << 4;
+        const HASH = 1 << 5;
+        const PARTIALORD = 1 << 6;
+        const ORD = 1 << 7;
+        const DEFAULT = 1 << 8;
+    }
+}
+
+impl Derives {
+    /// Look up a standard trait by name.
+    pub fn from_name(name: &str) -> Option<Derives> {
+        let d = match name {
+            "Copy" => Self::COPY,
+            "Clone" => Self::CLONE,
+            "Debug" => Self::DEBUG,
+            "Eq" => Self::EQ,
+            "PartialEq" => Self::PARTIALEQ,
+            "Hash" => Self::HASH,
+            "PartialOrd" => Self::PARTIALORD,
+            "Ord" => Self::ORD,
+            "Default" => Self::DEFAULT,
+            _ => return None,
+        };
+        Some(d)
     }
 }
 
@@ -51,12 +83,69 @@ impl ToTokens for Derives {
         if self.contains(Self::PARTIALEQ) {
             der.push(quote!(PartialEq))
         }
+        if self.contains(Self::HASH) {
+            der.push(quote!(Hash))
+        }
+        if self.contains(Self::PARTIALORD) {
+            der.push(quote!(PartialOrd))
+        }
+        if self.contains(Self::ORD) {
+            der.push(quote!(Ord))
+        }
+        if self.contains(Self::DEFAULT) {
+            der.push(quote!(Default))
+        }
 
         toks.append_separated(der, ",");
         toks.append(")]");
     }
 }
 
+/// Documentation for an item, taken from the comments preceding it in the specification.
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+pub struct Doc(pub Vec<String>);
+
+impl Doc {
+    // Tidy up raw comment lines: strip common indentation and surrounding blank lines. Comments
+    // are plain text rather than Markdown, so if anything is indented enough to be taken as a
+    // code block (and then compiled as a doctest) the whole lot is kept verbatim.
+    fn new(lines: Vec<String>) -> Doc {
+        let indent = |l: &String| l.len() - l.trim_start_matches([' ', '\t']).len();
+
+        let min = lines.iter().filter(|l| !l.trim().is_empty()).map(indent).min().unwrap_or(0);
+        let mut lines: Vec<_> = lines.iter()
+            .map(|l| if l.trim().is_empty() { String::new() } else { l[min..].trim_end().to_string() })
+            .skip_while(|l| l.is_empty())
+            .collect();
+        while lines.last().map_or(false, |l| l.is_empty()) {
+            lines.pop();
+        }
+
+        if lines.iter().any(|l| l.starts_with("    ") || l.starts_with("\t")) {
+            lines.insert(0, String::from("```text"));
+            lines.push(String::from("```"));
+        }
+
+        Doc(lines)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl ToTokens for Doc {
+    fn to_tokens(&self, toks: &mut Tokens) {
+        for line in &self.0 {
+            if line.is_empty() {
+                toks.append("///\n")
+            } else {
+                toks.append(format!("/// {}\n", line))
+            }
+        }
+    }
+}
+
 lazy_static! {
     static ref KEYWORDS: HashSet<&'static str> = {
         let kws = [
@@ -77,7 +166,7 @@ lazy_static! {
     };
 }
 
-fn quote_ident<S: AsRef<str>>(id: S) -> quote::Ident {
+pub(crate) fn quote_ident<S: AsRef<str>>(id: S) -> quote::Ident {
     let id = id.as_ref();
 
     if (*KEYWORDS).contains(id) {
@@ -90,7 +179,73 @@ fn quote_ident<S: AsRef<str>>(id: S) -> quote::Ident {
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
 pub enum Value {
     Ident(String),
-    Const(i64),
+    // Wide enough for every hyper and unsigned hyper
+    Const(i128),
+    // Constant expressions, which are evaluated before generating code
+    Neg(Box<Value>),
+    BinOp(BinOp, Box<Value>, Box<Value>),
+}
+
+/// Binary operators in constant expressions
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
+pub enum BinOp {
+    Or,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinOp {
+    // Binding strength, as in C
+    fn precedence(self) -> u8 {
+        use self::BinOp::*;
+
+        match self {
+            Or => 0,
+            And => 1,
+            Shl | Shr => 2,
+            Add | Sub => 3,
+            Mul | Div => 4,
+        }
+    }
+
+    // The result, or None on overflow or division by zero
+    fn apply(self, a: i128, b: i128) -> Option<i128> {
+        use self::BinOp::*;
+
+        match self {
+            Or => Some(a | b),
+            And => Some(a & b),
+            // Shifting out significant bits is overflow too
+            Shl => a.checked_shl(b as u32).filter(|v| b >= 0 && v >> b == a),
+            Shr => a.checked_shr(b as u32).filter(|_| b >= 0),
+            Add => a.checked_add(b),
+            Sub => a.checked_sub(b),
+            Mul => a.checked_mul(b),
+            Div => a.checked_div(b),
+        }
+    }
+}
+
+impl Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BinOp::*;
+
+        f.write_str(match *self {
+            Or => "|",
+            And => "&",
+            Shl => "<<",
+            Shr => ">>",
+            Add => "+",
+            Sub => "-",
+            Mul => "*",
+            Div => "/",
+        })
+    }
 }
 
 impl Value {
@@ -98,6 +253,18 @@ impl Value {
         Value::Ident(id.as_ref().to_string())
     }
 
+    fn binop(op: BinOp, a: Value, b: Value) -> Value {
+        Value::BinOp(op, Box::new(a), Box::new(b))
+    }
+
+    // Replace an expression by its value, if it can be evaluated
+    fn fold(&self, symtab: &Symtab) -> Value {
+        match self {
+            &Value::Ident(_) | &Value::Const(_) => self.clone(),
+            val => symtab.value(val).map_or_else(|| val.clone(), Value::Const),
+        }
+    }
+
     fn as_ident(&self) -> quote::Ident {
         match self {
             &Value::Ident(ref id) => quote_ident(id),
@@ -108,25 +275,56 @@ impl Value {
                     val.abs()
                 ))
             }
+            val => panic!("unevaluated constant expression {}", val),
         }
     }
 
-    fn as_i64(&self, symtab: &Symtab) -> Option<i64> {
+    fn as_int(&self, symtab: &Symtab) -> Option<i128> {
         symtab.value(self)
     }
 
     fn as_token(&self, symtab: &Symtab) -> Tokens {
         match self {
-            &Value::Const(c) => quote!(#c),
+            &Value::Const(c) => int_token(c),
             &Value::Ident(ref id) => {
                 let tok = quote_ident(id.as_str());
-                if let Some((_, Some(ref scope))) = symtab.getconst(id) {
-                    let scope = quote_ident(scope);
-                    quote!(#scope :: #tok)
-                } else {
-                    quote!(#tok)
+                match (symtab.getconst(id), builtin_const(id)) {
+                    (Some((_, Some(ref scope))), _) => {
+                        let scope = symtab.type_path(scope);
+                        quote!(#scope :: #tok)
+                    }
+                    (None, Some(c)) => int_token(c),
+                    _ => quote!(#tok),
                 }
             }
+            val => {
+                let c = symtab.value(val).expect("unevaluated constant expression");
+                int_token(c)
+            }
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Subexpressions are parenthesized, rather than worrying about precedence
+        let sub = |f: &mut fmt::Formatter, v: &Value| match v {
+            &Value::BinOp(..) => write!(f, "({})", v),
+            v => write!(f, "{}", v),
+        };
+
+        match self {
+            &Value::Ident(ref id) => write!(f, "{}", id),
+            &Value::Const(c) => write!(f, "{}", c),
+            &Value::Neg(ref v) => {
+                f.write_str("-")?;
+                sub(f, v)
+            }
+            &Value::BinOp(op, ref a, ref b) => {
+                sub(f, a)?;
+                write!(f, " {} ", op)?;
+                sub(f, b)
+            }
         }
     }
 }
@@ -145,11 +343,16 @@ pub enum Type {
     // Special array elements
     Opaque, // binary
     String, // text
+    // Strings which are configured to be kept as bytes, or decoded lossily
+    XdrString,
+    LossyString,
+    // Opaque data which is configured to be streamed
+    OpaqueStream,
 
     // Compound types
     Enum(Vec<EnumDefn>),
     Struct(Vec<Decl>),
-    Union(Box<Decl>, Vec<UnionCase>, Option<Box<Decl>>),
+    Union(Box<Decl>, Vec<UnionCase>, Option<(Box<Decl>, Doc)>),
 
     Option(Box<Type>),
     Array(Box<Type>, Value),
@@ -172,8 +375,8 @@ impl Type {
         Type::Option(Box::new(ty))
     }
 
-    fn union((d, c, dfl): (Decl, Vec<UnionCase>, Option<Decl>)) -> Type {
-        Type::Union(Box::new(d), c, dfl.map(Box::new))
+    fn union((d, c, dfl): (Decl, Vec<UnionCase>, Option<(Decl, Doc)>)) -> Type {
+        Type::Union(Box::new(d), c, dfl.map(|(d, doc)| (Box::new(d), doc)))
     }
 
     fn ident<S: AsRef<str>>(id: S) -> Type {
@@ -191,7 +394,9 @@ impl Type {
             _ if self.is_prim(symtab) => false,
             &Array(_, _) | &Flex(_, _) | &Option(_) => false,
             &Ident(ref name, _) => {
-                if let Some(ty) = symtab.typespec(name) {
+                if let Some(ext) = symtab.extern_type(name) {
+                    ext.boxed
+                } else if let Some(ty) = symtab.typespec(name) {
                     ty.is_boxed(symtab)
                 } else {
                     true
@@ -208,6 +413,9 @@ impl Type {
             &Int | &UInt | &Hyper | &UHyper | &Float | &Double | &Quadruple | &Bool => true,
 
             &Ident(ref id, _) => {
+                if let Some(ext) = symtab.extern_type(id) {
+                    return ext.primitive;
+                }
                 match symtab.typespec(id) {
                     None => false,
                     Some(ref ty) => ty.is_prim(symtab),
@@ -235,23 +443,28 @@ impl Type {
         memo.insert(self.clone(), Derives::empty());
 
         let set = match self {
-            &Array(ref ty, ref len) => {
-                let ty = ty.as_ref();
-                let set = match ty {
-                    &Opaque | &String => Derives::EQ | Derives::PARTIALEQ | Derives::COPY | Derives::CLONE | Derives::DEBUG,
+            &Array(ref ty, _) => {
+                // Default can only be derived for arrays up to 32 elements; `manual_default`
+                // covers the rest
+                match ty.as_ref() {
+                    &Opaque | &String => Derives::all(),
                     ref ty => ty.derivable(symtab, Some(memo)),
-                };
-                match len.as_i64(symtab) {
-                    Some(v) if v <= 32 => set,
-                    _ => Derives::empty(),   // no #[derive] for arrays > 32
                 }
             }
             &Flex(ref ty, ..) => {
                 let set = ty.derivable(symtab, Some(memo));
-                set & !Derives::COPY // no Copy, everything else OK
+                (set & !Derives::COPY) | Derives::DEFAULT // no Copy, always Default
+            }
+            &Enum(_) => Derives::all(), // Default is the first member
+            &OpaqueStream => Derives::DEBUG | Derives::DEFAULT,
+            &Option(ref ty) => {
+                let set = ty.derivable(symtab, Some(memo)) | Derives::DEFAULT;
+                if ty.is_boxed(symtab) {
+                    set & !Derives::COPY
+                } else {
+                    set
+                }
             }
-            &Enum(_) => Derives::EQ | Derives::PARTIALEQ | Derives::COPY | Derives::CLONE | Derives::DEBUG,
-            &Option(ref ty) => ty.derivable(symtab, Some(memo)),
             &Struct(ref fields) => {
                 fields.iter().fold(Derives::all(), |a, f| {
                     a & f.derivable(symtab, memo)
@@ -262,22 +475,33 @@ impl Type {
                 cases.iter().map(|c| &c.1).fold(Derives::all(), |a, c| {
                     a & c.derivable(symtab, memo)
                 }) &
-                    defl.as_ref().map_or(
-                        Derives::all(),
-                        |d| d.derivable(symtab, memo),
-                    )
+                    defl.as_ref().map_or(Derives::all(), |&(ref d, _)| match d.as_ref() {
+                        // The default arm is boxed, which rules out Copy
+                        &Decl::Named(_, ref ty, _) if ty.is_boxed(symtab) => {
+                            d.derivable(symtab, memo) & !Derives::COPY
+                        }
+                        d => d.derivable(symtab, memo),
+                    }) &
+                    !Derives::DEFAULT // no obvious default case
             }
 
             &Ident(_, Some(derives)) => derives,
 
             &Ident(ref id, None) => {
-                match symtab.typespec(id) {
-                    None => Derives::empty(),  // unknown, really
-                    Some(ref ty) => ty.derivable(symtab, Some(memo)),
+                if let Some(ext) = symtab.extern_type(id) {
+                    if ext.primitive { Derives::all() } else { ext.derives }
+                } else {
+                    match symtab.typespec(id) {
+                        None => Derives::empty(),  // unknown, really
+                        Some(ref ty) => ty.derivable(symtab, Some(memo)),
+                    }
                 }
             }
 
-            &Float | &Double => Derives::PARTIALEQ | Derives::COPY | Derives::CLONE | Derives::DEBUG,
+            &Float | &Double => {
+                Derives::PARTIALEQ | Derives::PARTIALORD | Derives::COPY | Derives::CLONE | Derives::DEBUG |
+                    Derives::DEFAULT
+            }
             ty if ty.is_prim(symtab) => Derives::all(),
 
             _ => Derives::all() & !Derives::COPY,
@@ -288,11 +512,119 @@ impl Type {
     }
 
 
+    // The type with its constant expressions evaluated
+    fn fold(&self, symtab: &Symtab) -> Type {
+        use self::Type::*;
+
+        match self {
+            &Enum(ref defs) => {
+                Enum(defs.iter()
+                    .map(|&EnumDefn(ref name, ref val, ref doc)| {
+                        EnumDefn(name.clone(), val.as_ref().map(|v| v.fold(symtab)), doc.clone())
+                    })
+                    .collect())
+            }
+            &Struct(ref decls) => Struct(decls.iter().map(|d| d.fold(symtab)).collect()),
+            &Union(ref sel, ref cases, ref defl) => {
+                Union(
+                    Box::new(sel.fold(symtab)),
+                    cases.iter()
+                        .map(|&UnionCase(ref val, ref decl, ref doc)| {
+                            UnionCase(val.fold(symtab), decl.fold(symtab), doc.clone())
+                        })
+                        .collect(),
+                    defl.as_ref().map(|&(ref d, ref doc)| (Box::new(d.fold(symtab)), doc.clone())),
+                )
+            }
+            &Option(ref ty) => Option(Box::new(ty.fold(symtab))),
+            &Array(ref ty, ref sz) => Array(Box::new(ty.fold(symtab)), sz.fold(symtab)),
+            &Flex(ref ty, ref sz) => Flex(Box::new(ty.fold(symtab)), sz.as_ref().map(|v| v.fold(symtab))),
+            ty => ty.clone(),
+        }
+    }
+
+    // Arrays too large for `Default` to be derived
+    fn is_large_array(&self, symtab: &Symtab) -> bool {
+        match self {
+            &Type::Array(ref ty, _) if **ty == Type::Opaque || **ty == Type::String => false, // OpaqueArray
+            &Type::Array(_, ref len) => len.as_int(symtab).map_or(true, |v| v > 32),
+            _ => false,
+        }
+    }
+
+    // Whether this is represented by one of xdr-codec's bounded types. Strings which aren't checked
+    // for UTF-8 don't have a bounded type.
+    fn is_bounded(&self, symtab: &Symtab) -> bool {
+        match self {
+            &Type::Flex(ref ty, Some(_)) => {
+                symtab.bounded_types() && !matches!(**ty, Type::XdrString | Type::LossyString | Type::OpaqueStream)
+            }
+            _ => false,
+        }
+    }
+
+    // The type with strings and opaque data represented as configured. `path` names the definition,
+    // or the field within it, as `type.field`.
+    fn map_fields(self, path: &str, config: &Config) -> Type {
+        use self::Type::*;
+
+        match self {
+            Flex(ty, sz) => {
+                let ty = match *ty {
+                    String => match config.strings_for(path) {
+                        Strings::Utf8 => String,
+                        Strings::Lossy => LossyString,
+                        Strings::Bytes => XdrString,
+                    },
+                    Opaque if config.is_stream(path) => OpaqueStream,
+                    ty => ty.map_fields(path, config),
+                };
+                Flex(Box::new(ty), sz)
+            }
+            Option(ty) => Option(Box::new(ty.map_fields(path, config))),
+            Array(ty, sz) => Array(Box::new(ty.map_fields(path, config)), sz),
+            Struct(decls) => Struct(decls.into_iter().map(|d| d.map_fields(path, config)).collect()),
+            Union(sel, cases, defl) => Union(
+                sel,
+                cases
+                    .into_iter()
+                    .map(|UnionCase(val, decl, doc)| UnionCase(val, decl.map_fields(path, config), doc))
+                    .collect(),
+                defl.map(|(d, doc)| (Box::new(d.map_fields(path, config)), doc)),
+            ),
+            ty => ty,
+        }
+    }
+
+    // True if a definition of this type needs a hand-written `Default` impl
+    fn manual_default(&self, symtab: &Symtab) -> bool {
+        match self {
+            &Type::Struct(ref decls) => {
+                decls.iter().any(|decl| match decl {
+                    &Decl::Named(_, ref ty, _) => ty.is_large_array(symtab),
+                    &Decl::Void => false,
+                })
+            }
+            ty => ty.is_large_array(symtab),
+        }
+    }
+
+    fn default_value(&self, symtab: &Symtab) -> Tokens {
+        if self.is_large_array(symtab) {
+            quote!(::std::array::from_fn(|_| Default::default()))
+        } else {
+            quote!(Default::default())
+        }
+    }
+
     fn packer(&self, val: Tokens, symtab: &Symtab) -> Result<Tokens> {
         use self::Type::*;
 
         let res = match self {
-            &Enum(_) => quote!((*#val as i32).pack(out)?),
+            &Enum(_) => quote!((*#val as i32).pack(out)),
+
+            // Bounded types check their own size
+            ty if ty.is_bounded(symtab) => quote!(#val.pack(out)),
 
             &Flex(ref ty, ref maxsz) => {
                 let ty = ty.as_ref();
@@ -304,23 +636,15 @@ impl Type {
                     }
                 };
                 match ty {
-                    &Opaque => quote!(xdr_codec::pack_opaque_flex(&#val, #maxsz, out)?),
-                    &String => quote!(xdr_codec::pack_string(&#val, #maxsz, out)?),
-                    _ => quote!(xdr_codec::pack_flex(&#val, #maxsz, out)?),
-                }
-            }
-
-            &Array(ref ty, _) => {
-                let ty = ty.as_ref();
-                match ty {
-                    &Opaque | &String => {
-                        quote!(xdr_codec::pack_opaque_array(&#val[..], #val.len(), out)?)
-                    }
-                    _ => quote!(xdr_codec::pack_array(&#val[..], #val.len(), out, None)?),
+                    &Opaque => quote!(xdr_codec::pack_opaque_flex(&#val, #maxsz, out)),
+                    &String | &LossyString => quote!(xdr_codec::pack_string(&#val, #maxsz, out)),
+                    &XdrString => quote!(xdr_codec::pack_opaque_flex(&#val, #maxsz, out)),
+                    &OpaqueStream => quote!(xdr_codec::pack_opaque_stream(&#val, #maxsz, out)),
+                    _ => quote!(xdr_codec::pack_flex(&#val, #maxsz, out)),
                 }
             }
 
-            _ => quote!(#val.pack(out)?),
+            _ => quote!(#val.pack(out)),
         };
 
         trace!("packed {:?} val {:?} => {:?}", self, val, res);
@@ -331,7 +655,7 @@ impl Type {
         use self::Type::*;
 
         match self {
-            &Opaque | &String | &Option(_) | &Ident(..) | &Int | &UInt | &Hyper | &UHyper |
+            &Opaque | &String | &XdrString | &LossyString | &OpaqueStream | &Option(_) | &Ident(..) | &Int | &UInt | &Hyper | &UHyper |
             &Float | &Double | &Quadruple | &Bool => true,
             _ => false,
         }
@@ -341,64 +665,7 @@ impl Type {
         use self::Type::*;
 
         match self {
-            &Array(ref ty, ref value) => {
-                let ty = ty.as_ref();
-                let value = value.as_token(symtab);
-
-                match ty {
-                    &Opaque | &String => {
-                        quote!({
-                            let mut buf: [u8; #value as usize] = unsafe { ::std::mem::uninitialized() };
-                            let sz = xdr_codec::unpack_opaque_array(input, &mut buf[..], #value as usize)?;
-                            (buf, sz)
-                        })
-                    }
-                    ty => {
-                        let ty = ty.as_token(symtab).unwrap();
-                        // Create the return array as uninitialized, since we don't know what to initialize it until
-                        // we can deserialize values. We don't even have a guaranteed value we can populate it with, since
-                        // the type may not implement Default (and it would be a waste anyway, since they're going to be
-                        // replaced).
-                        //
-                        // However, having an uninitialized array makes for lots of awkward corner cases.
-                        // Even in the common case, we can't simply use `unpack_array`, as it will replace each element
-                        // by assignment, but that will Drop any existing value - but in this case that will be undefined
-                        // as they're uninitialized. So we need to use `unpack_array_with` that allows us to specify a function
-                        // which does the initializing assignment. In this case we use `ptr::write` which overwrites memory
-                        // without Dropping the current contents.
-                        //
-                        // With that solved, we also need to deal with the error cases, where the array could be partially
-                        // initialized. For this case, `unpack_array_with` also takes a drop function which deinitializes
-                        // the partially initialized elements, so the array is left uninitialized in the failure case.
-                        // We can then just use `mem::forget` to dispose of the whole thing.
-                        //
-                        // We also need to catch panics to make sure the buf is forgotten. It may be partially initialized then
-                        // it may leak, but that's better than calling Drop on uninitialized elements.
-                        quote!({
-                            #[inline]
-                            fn uninit_ptr_setter<T>(p: &mut T, v: T) {
-                                unsafe { ::std::ptr::write(p, v) }
-                            }
-                            #[inline]
-                            fn uninit_ptr_dropper<T>(p: &mut T) {
-                                unsafe { ::std::ptr::drop_in_place(p) }
-                            }
-                            let mut buf: [#ty; #value as usize] = unsafe { ::std::mem::uninitialized() };
-                            let res = ::std::panic::catch_unwind(
-                                ::std::panic::AssertUnwindSafe(||
-                                    xdr_codec::unpack_array_with(
-                                        input, &mut buf[..], #value as usize, uninit_ptr_setter, uninit_ptr_dropper, None)));
-
-                            let sz = match res {
-                                Ok(Ok(sz)) => sz,
-                                Ok(Err(err)) => { ::std::mem::forget(buf); return Err(err); }
-                                Err(panic) => { ::std::mem::forget(buf); ::std::panic::resume_unwind(panic);  }
-                            };
-                            (buf, sz)
-                        })
-                    }
-                }
-            }
+            ty if ty.is_bounded(symtab) => quote!(xdr_codec::Unpack::unpack(input)?),
 
             &Flex(ref ty, ref maxsz) => {
                 let ty = ty.as_ref();
@@ -412,6 +679,9 @@ impl Type {
 
                 match ty {
                     &String => quote!(xdr_codec::unpack_string(input, #maxsz)?),
+                    &LossyString => quote!(xdr_codec::unpack_string_lossy(input, #maxsz)?),
+                    &XdrString => quote!(xdr_codec::unpack_xdr_string(input, #maxsz)?),
+                    &OpaqueStream => quote!(xdr_codec::unpack_opaque_stream(input, #maxsz)?),
                     &Opaque => quote!(xdr_codec::unpack_opaque_flex(input, #maxsz)?),
                     _ => quote!(xdr_codec::unpack_flex(input, #maxsz)?),
                 }
@@ -421,6 +691,31 @@ impl Type {
         }
     }
 
+    // An unpacker for streamed opaque data which writes it to `sink`, for `unpack_into`. Types
+    // containing streamed data are unpacked with their own `unpack_into`.
+    fn sink_unpacker(&self, symtab: &Symtab) -> Option<Tokens> {
+        match self {
+            &Type::Ident(ref name, _) if symtab.is_streamed(self) => {
+                let path = symtab.type_path(name);
+                Some(quote!(#path::unpack_into(input, sink)?))
+            }
+            &Type::Flex(ref ty, ref maxsz) if **ty == Type::OpaqueStream => {
+                let maxsz = match maxsz {
+                    &None => quote!(None),
+                    &Some(ref mx) => {
+                        let mx = mx.as_token(symtab);
+                        quote!(Some(#mx as usize))
+                    }
+                };
+                Some(quote!(
+                    xdr_codec::unpack_opaque_into(input, sink, #maxsz)
+                        .map(|(len, sz)| (xdr_codec::OpaqueStream::consumed(len), sz))?
+                ))
+            }
+            _ => None,
+        }
+    }
+
     fn as_token(&self, symtab: &Symtab) -> Result<Tokens> {
         use self::Type::*;
 
@@ -452,7 +747,7 @@ impl Type {
                 match ty {
                     &String | &Opaque => {
                         let sztok = sz.as_token(symtab);
-                        quote!([u8; #sztok as usize])
+                        quote!(xdr_codec::OpaqueArray<{ #sztok as usize }>)
                     }
                     ref ty => {
                         let tytok = ty.as_token(symtab)?;
@@ -462,10 +757,24 @@ impl Type {
                 }
             }
 
+            &Flex(ref ty, Some(ref mx)) if self.is_bounded(symtab) => {
+                let mx = mx.as_token(symtab);
+                match ty.as_ref() {
+                    &String => quote!(xdr_codec::BoundedString<{ #mx as usize }>),
+                    &Opaque => quote!(xdr_codec::BoundedOpaque<{ #mx as usize }>),
+                    ty => {
+                        let tok = ty.as_token(symtab)?;
+                        quote!(xdr_codec::BoundedVec<#tok, { #mx as usize }>)
+                    }
+                }
+            }
+
             &Flex(ref ty, _) => {
                 let ty = ty.as_ref();
                 match ty {
-                    &String => quote!(String),
+                    &String | &LossyString => quote!(String),
+                    &XdrString => quote!(xdr_codec::XdrString),
+                    &OpaqueStream => quote!(xdr_codec::OpaqueStream),
                     &Opaque => quote!(Vec<u8>),
                     ref ty => {
                         let tok = ty.as_token(symtab)?;
@@ -474,10 +783,7 @@ impl Type {
                 }
             }
 
-            &Ident(ref name, _) => {
-                let id = quote_ident(name.as_str());
-                quote!(#id)
-            }
+            &Ident(ref name, _) => symtab.type_path(name),
 
             _ => return Err(format!("can't have unnamed type {:?}", self).into()),
         };
@@ -486,33 +792,110 @@ impl Type {
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
-pub struct EnumDefn(pub String, pub Option<Value>);
+pub struct EnumDefn(pub String, pub Option<Value>, pub Doc);
 
 impl EnumDefn {
     fn new<S: AsRef<str>>(id: S, val: Option<Value>) -> EnumDefn {
-        EnumDefn(id.as_ref().to_string(), val)
+        EnumDefn(id.as_ref().to_string(), val, Doc::default())
+    }
+
+    fn with_doc(self, doc: Doc) -> EnumDefn {
+        EnumDefn(self.0, self.1, doc)
     }
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
-pub struct UnionCase(Value, Decl);
+pub struct UnionCase(Value, Decl, Doc);
+
+// A variant of a generated union. Each case normally has its own variant, but with
+// `Config::shared_arms` the cases sharing an arm have one between them, which also holds the
+// discriminant.
+struct UnionArm<'a> {
+    label: quote::Ident,
+    // The case values, which are empty for the default arm
+    cases: Vec<&'a Value>,
+    decl: &'a Decl,
+    doc: Option<&'a Doc>,
+}
+
+impl<'a> UnionArm<'a> {
+    fn shared(&self) -> bool {
+        self.cases.len() > 1
+    }
+
+    fn field(&self) -> Option<&'a str> {
+        match self.decl {
+            &Decl::Void => None,
+            &Decl::Named(ref name, ..) => Some(name),
+        }
+    }
+
+    // The variant with discriminant `disc` and body `val`, prefixed with `path`. This is used for
+    // the definition, patterns and constructors, so `disc` and `val` are types, bindings or
+    // expressions to match.
+    fn variant(&self, path: Tokens, labelled: bool, disc: Tokens, val: Tokens) -> Tokens {
+        let label = &self.label;
+        let field = self.field().map(quote_ident);
+
+        match (labelled, self.shared(), field) {
+            (_, false, None) => quote!(#path #label),
+            (false, false, Some(_)) => quote!(#path #label(#val)),
+            (false, true, None) => quote!(#path #label(#disc)),
+            (false, true, Some(_)) => quote!(#path #label(#disc, #val)),
+            (true, false, Some(field)) => quote!(#path #label { #field: #val }),
+            (true, true, None) => quote!(#path #label { discriminant: #disc }),
+            (true, true, Some(field)) => quote!(#path #label { discriminant: #disc, #field: #val }),
+        }
+    }
+}
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
 pub enum Decl {
     Void,
-    Named(String, Type),
+    Named(String, Type, Doc),
 }
 
 impl Decl {
     fn named<S: AsRef<str>>(id: S, ty: Type) -> Decl {
-        Decl::Named(id.as_ref().to_string(), ty)
+        Decl::Named(id.as_ref().to_string(), ty, Doc::default())
+    }
+
+    fn fold(&self, symtab: &Symtab) -> Decl {
+        match self {
+            &Decl::Void => Decl::Void,
+            &Decl::Named(ref name, ref ty, ref doc) => Decl::Named(name.clone(), ty.fold(symtab), doc.clone()),
+        }
+    }
+
+    fn map_fields(self, outer: &str, config: &Config) -> Decl {
+        match self {
+            Decl::Void => Decl::Void,
+            Decl::Named(name, ty, doc) => {
+                let ty = ty.map_fields(&format!("{}.{}", outer, name), config);
+                Decl::Named(name, ty, doc)
+            }
+        }
+    }
+
+    fn with_doc(self, doc: Doc) -> Decl {
+        match self {
+            Decl::Void => Decl::Void,
+            Decl::Named(name, ty, _) => Decl::Named(name, ty, doc),
+        }
+    }
+
+    fn doc(&self) -> Option<&Doc> {
+        match self {
+            &Decl::Void => None,
+            &Decl::Named(_, _, ref doc) => Some(doc),
+        }
     }
 
     fn name_as_ident(&self) -> Option<(quote::Ident, &Type)> {
         use self::Decl::*;
         match self {
             &Void => None,
-            &Named(ref name, ref ty) => Some((quote_ident(name), ty)),
+            &Named(ref name, ref ty, _) => Some((quote_ident(name), ty)),
         }
     }
 
@@ -520,12 +903,9 @@ impl Decl {
         use self::Decl::*;
         match self {
             &Void => Ok(None),
-            &Named(ref name, ref ty) => {
+            &Named(ref name, ref ty, _) => {
                 let nametok = quote_ident(name.as_str());
-                let mut tok = ty.as_token(symtab)?;
-                if false && ty.is_boxed(symtab) {
-                    tok = quote!(Box<#tok>)
-                };
+                let tok = ty.as_token(symtab)?;
                 Ok(Some((nametok, tok)))
             }
         }
@@ -535,222 +915,560 @@ impl Decl {
         use self::Decl::*;
         match self {
             &Void => Derives::all(),
-            &Named(_, ref ty) => ty.derivable(symtab, Some(memo)),
+            &Named(_, ref ty, _) => ty.derivable(symtab, Some(memo)),
         }
     }
 }
 
-// Specification of a named type
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
-pub struct Typespec(pub String, pub Type);
-
-// Named synonym for a type
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
-pub struct Typesyn(pub String, pub Type);
-
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
-pub struct Const(pub String, pub i64);
+// Constants which specifications use without defining them, since rpcgen's output is C where they're
+// macros
+fn builtin_const(name: &str) -> Option<i128> {
+    match name {
+        "TRUE" => Some(1),
+        "FALSE" => Some(0),
+        _ => None,
+    }
+}
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
-pub enum Defn {
-    Typespec(String, Type),
-    Typesyn(String, Type),
-    Const(String, i64),
+// Constants are hypers, or unsigned hypers if they're too big for that
+fn in_range(val: i128) -> bool {
+    val >= i64::min_value() as i128 && val <= u64::max_value() as i128
 }
 
-impl Defn {
-    fn typespec<S: AsRef<str>>(id: S, ty: Type) -> Defn {
-        Defn::Typespec(id.as_ref().to_string(), ty)
+// A constant as a literal of the type it has in generated code
+fn int_token(val: i128) -> Tokens {
+    if val > i64::max_value() as i128 {
+        let val = val as u64;
+        quote!(#val)
+    } else {
+        let val = val as i64;
+        quote!(#val)
     }
+}
 
-    fn typesyn<S: AsRef<str>>(id: S, ty: Type) -> Defn {
-        Defn::Typesyn(id.as_ref().to_string(), ty)
-    }
+// Return true if a union case value is compatible with the union's selector
+fn case_compatible(selector: &Decl, case: &Value, symtab: &Symtab) -> bool {
+    use self::Type::*;
 
-    fn constant<S: AsRef<str>>(id: S, v: i64) -> Defn {
-        Defn::Const(id.as_ref().to_string(), v)
+    let seltype = match symtab.selector_type(selector) {
+        None => return false,
+        Some(ty) => ty,
+    };
+
+    match case {
+        &Value::Ident(ref id) if *seltype == Bool => id == "TRUE" || id == "FALSE",
+        val if *seltype == Bool => symtab.value(val).map_or(false, |v| v == 0 || v == 1),
+
+        // Enum selectors need members of the enum, but integer selectors can have any constant.
+        // That includes enum members, since they're plain integers to rpcgen, and specifications
+        // such as RFC 7531's NFSv4 callbacks switch on `unsigned` with an enum's members as cases.
+        &Value::Ident(ref id) if symtab.getconst(id).is_some() => {
+            match (seltype, symtab.getconst(id)) {
+                (&Ident(ref selname, _), Some((_, Some(ref scope)))) => scope == selname,
+                (&Ident(..), _) => false,
+                (_, Some((val, _))) => int_case_compatible(seltype, val),
+                (_, None) => false,
+            }
+        }
+        &Value::Ident(_) => false,
+
+        val => symtab.value(val).map_or(false, |val| int_case_compatible(seltype, val)),
     }
 }
 
-pub trait Emit {
-    fn define(&self, symtab: &Symtab) -> Result<Tokens>;
-}
+// A builder for the struct `name`. Fields which aren't set are defaulted if they can be, and
+// otherwise `build()` fails, as it does if a variable-length field is longer than its maximum.
+fn struct_builder(struct_name: &str, decls: &[Decl], symtab: &Symtab) -> Result<Tokens> {
+    let builder = quote::Ident::new(format!("{}Builder", struct_name));
+    let name = quote_ident(struct_name);
+
+    let mut fields = Vec::new();
+    let mut setters = Vec::new();
+    let mut build = Vec::new();
+    for decl in decls {
+        let (fname, ty) = match decl {
+            &Decl::Void => continue,
+            &Decl::Named(ref fname, ref ty, _) => (fname, ty),
+        };
+        let field = quote_ident(fname);
+        let flex = symtab.flex_type(ty);
+
+        // Bounded fields are set from the plain String or Vec, and checked when they're built
+        let (tok, convert) = match flex {
+            Some((bounded @ &Type::Flex(ref elem, _), newtype)) if bounded.is_bounded(symtab) => {
+                let raw = Type::Flex(elem.clone(), None).as_token(symtab)?;
+                let wrap = match newtype {
+                    Some(id) => {
+                        let path = symtab.type_path(id);
+                        quote!(let #field = #path(#field);)
+                    }
+                    None => quote!(),
+                };
+                (raw, Some(quote!(let #field = std::convert::TryFrom::try_from(#field)?; #wrap)))
+            }
+            _ => (ty.as_token(symtab)?, None),
+        };
 
-pub trait Emitpack: Emit {
-    fn pack(&self, symtab: &Symtab) -> Result<Option<Tokens>>;
-    fn unpack(&self, symtab: &Symtab) -> Result<Option<Tokens>>;
-}
+        fields.push(quote!(#field: Option<#tok>,));
+        setters.push(quote! {
+            pub fn #field<V: Into<#tok>>(mut self, v: V) -> Self {
+                self.#field = Some(v.into());
+                self
+            }
+        });
 
-impl Emit for Const {
-    fn define(&self, _: &Symtab) -> Result<Tokens> {
-        let name = quote_ident(&self.0);
-        let val = &self.1;
+        if let Some(convert) = convert {
+            build.push(quote!(let #field = self.#field.unwrap_or_default(); #convert));
+            continue;
+        }
 
-        Ok(quote!(pub const #name: i64 = #val;))
+        let val = if ty.derivable(symtab, None).contains(Derives::DEFAULT) {
+            if ty.is_large_array(symtab) {
+                let default = ty.default_value(symtab);
+                quote!(self.#field.unwrap_or_else(|| #default))
+            } else {
+                quote!(self.#field.unwrap_or_default())
+            }
+        } else {
+            let missing = format!("{}.{} is not set", struct_name, fname);
+            quote!(match self.#field { Some(v) => v, None => return Err(xdr_codec::Error::from(#missing)) })
+        };
+        let check = match flex {
+            Some((&Type::Flex(_, Some(ref max)), newtype)) => {
+                let max = max.as_int(symtab).ok_or_else(|| Error::from(format!("unknown size {:?}", max)))? as usize;
+                let data = if newtype.is_some() { quote!(#field.0) } else { quote!(#field) };
+                quote!(if #data.len() > #max { return Err(xdr_codec::Error::invalidlen(#data.len())); })
+            }
+            _ => quote!(),
+        };
+        build.push(quote!(let #field = #val; #check));
     }
-}
 
-impl Emit for Typesyn {
-    fn define(&self, symtab: &Symtab) -> Result<Tokens> {
-        let ty = &self.1;
-        let name = quote_ident(&self.0);
-        let tok = ty.as_token(symtab)?;
-        Ok(quote!(pub type #name = #tok;))
-    }
+    let init: Vec<_> = decls.iter().filter_map(|decl| decl.name_as_ident()).map(|(field, _)| field).collect();
+
+    Ok(quote! {
+        impl #name {
+            pub fn builder() -> #builder {
+                #builder::default()
+            }
+        }
+
+        #[derive(Default)]
+        pub struct #builder { #(#fields)* }
+
+        impl #builder {
+            #(#setters)*
+
+            pub fn build(self) -> xdr_codec::Result<#name> {
+                #(#build)*
+                Ok(#name { #(#init,)* })
+            }
+        }
+    })
 }
 
-impl Emit for Typespec {
-    fn define(&self, symtab: &Symtab) -> Result<Tokens> {
-        use self::Type::*;
+// Conversions between an enum and its values and names
+fn enum_conversions(name: &quote::Ident, defs: &[EnumDefn], symtab: &Symtab) -> Tokens {
+    let members: Vec<_> = defs
+        .iter()
+        .filter(|&&EnumDefn(ref field, ..)| symtab.getconst(field).is_some())
+        .map(|&EnumDefn(ref field, ..)| (field.as_str(), quote_ident(field)))
+        .collect();
+    let count = members.len();
+
+    let all: Vec<_> = members.iter().map(|&(_, ref tok)| quote!(#name::#tok,)).collect();
+    let values: Vec<_> = members
+        .iter()
+        .map(|&(_, ref tok)| quote!(x if x == #name::#tok as i32 => Ok(#name::#tok),))
+        .collect();
+    let names: Vec<_> = members.iter().map(|&(field, ref tok)| quote!(#name::#tok => #field,)).collect();
+    let parse: Vec<_> = members.iter().map(|&(field, ref tok)| quote!(#field => Ok(#name::#tok),)).collect();
+    let unknown = format!("unknown {} name ", name);
+
+    quote! {
+        impl #name {
+            /// All the members, in the order they're defined
+            pub const ALL: [#name; #count] = [#(#all)*];
+
+            /// The member's name in the specification
+            pub fn name(&self) -> &'static str {
+                match *self { #(#names)* }
+            }
+        }
 
-        let name = quote_ident(&self.0);
-        let ty = &self.1;
+        impl std::convert::TryFrom<i32> for #name {
+            type Error = xdr_codec::Error;
 
-        let ret = match ty {
+            fn try_from(v: i32) -> xdr_codec::Result<#name> {
+                match v {
+                    #(#values)*
+                    v => Err(xdr_codec::Error::invalidenum(v)),
+                }
+            }
+        }
+
+        impl From<#name> for i32 {
+            fn from(v: #name) -> i32 {
+                v as i32
+            }
+        }
+
+        impl std::str::FromStr for #name {
+            type Err = xdr_codec::Error;
+
+            fn from_str(s: &str) -> xdr_codec::Result<#name> {
+                match s {
+                    #(#parse)*
+                    s => Err(xdr_codec::Error::from(format!("{}{:?}", #unknown, s))),
+                }
+            }
+        }
+    }
+}
+
+// The Rust type of a union's selector
+fn selector_token(selector: &Decl, symtab: &Symtab) -> Result<Tokens> {
+    match selector {
+        &Decl::Void => Err(Error::from("void switch selector")),
+        &Decl::Named(_, ref ty, _) => ty.as_token(symtab),
+    }
+}
+
+// Methods for a union: `discriminant()`, and `as_field()` for each field which has the same type in
+// every arm it's in
+fn union_methods(name: quote::Ident, selector: &Decl, arms: &[UnionArm], symtab: &Symtab) -> Result<Tokens> {
+    let labelled = symtab.labelled_unions();
+    let selty = selector_token(selector, symtab)?;
+    let path = quote!(#name::);
+
+    // The default arm's discriminant isn't kept
+    let hasdefl = arms.iter().any(|arm| arm.cases.is_empty());
+    let discs: Vec<_> = arms
+        .iter()
+        .map(|arm| {
+            let pat = arm.variant(path.clone(), labelled, quote!(disc), quote!(_));
+            let disc = match arm.cases.first() {
+                None => return quote!(#pat => None,),
+                Some(_) if arm.shared() => quote!(*disc),
+                Some(val) => symtab.selector_value(selector, val),
+            };
+            if hasdefl {
+                quote!(#pat => Some(#disc),)
+            } else {
+                quote!(#pat => #disc,)
+            }
+        })
+        .collect();
+    let discty = if hasdefl { quote!(Option<#selty>) } else { selty };
+
+    let mut ret = quote! {
+        pub fn discriminant(&self) -> #discty {
+            match self { #(#discs)* }
+        }
+    };
+
+    let mut fields: Vec<&str> = Vec::new();
+    for field in arms.iter().filter_map(|arm| arm.field()) {
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+
+    for field in fields {
+        let with: Vec<_> = arms.iter().filter(|arm| arm.field() == Some(field)).collect();
+        let ty = match with[0].decl {
+            &Decl::Named(_, ref ty, _) => ty,
+            &Decl::Void => unreachable!(),
+        };
+        if with.iter().any(|arm| match arm.decl {
+            &Decl::Named(_, ref t, _) => t != ty,
+            &Decl::Void => true,
+        }) {
+            continue;
+        }
+
+        let tok = ty.as_token(symtab)?;
+        let mut matches: Vec<_> = with
+            .iter()
+            .map(|arm| {
+                let pat = arm.variant(path.clone(), labelled, quote!(_), quote!(val));
+                if arm.cases.is_empty() && ty.is_boxed(symtab) {
+                    quote!(#pat => Some(&**val),)
+                } else {
+                    quote!(#pat => Some(val),)
+                }
+            })
+            .collect();
+        if with.len() < arms.len() {
+            matches.push(quote!(_ => None,));
+        }
+
+        let method = quote_ident(format!("as_{}", field));
+        ret.append(quote! {
+            pub fn #method(&self) -> Option<&#tok> {
+                match self { #(#matches)* }
+            }
+        });
+    }
+
+    Ok(ret)
+}
+
+// Whether `val` fits in an integer selector
+fn int_case_compatible(seltype: &Type, val: i128) -> bool {
+    use self::Type::*;
+
+    match seltype {
+        &Int => val >= i32::min_value() as i128 && val <= i32::max_value() as i128,
+        &UInt => val >= 0 && val <= u32::max_value() as i128,
+        &Hyper => val >= i64::min_value() as i128 && val <= i64::max_value() as i128,
+        &UHyper => val >= 0 && val <= u64::max_value() as i128,
+        _ => false,
+    }
+}
+
+// Specification of a named type
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+pub struct Typespec(pub String, pub Type);
+
+// Named synonym for a type
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+pub struct Typesyn(pub String, pub Type);
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+pub struct Const(pub String, pub i128);
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+pub enum Defn {
+    Typespec(String, Type, Doc),
+    Typesyn(String, Type, Doc),
+    Const(String, Value, Doc),
+    /// `#include` of another specification file, and whether it was named with `<>` rather than
+    /// quotes, like a system header
+    Include(String, bool),
+    /// Text of a `%` line, after the `%`
+    Passthrough(String),
+}
+
+impl Defn {
+    fn typespec<S: AsRef<str>>(id: S, ty: Type) -> Defn {
+        Defn::Typespec(id.as_ref().to_string(), ty, Doc::default())
+    }
+
+    fn typesyn<S: AsRef<str>>(id: S, ty: Type) -> Defn {
+        Defn::Typesyn(id.as_ref().to_string(), ty, Doc::default())
+    }
+
+    #[cfg(test)]
+    fn constant<S: AsRef<str>>(id: S, v: i128) -> Defn {
+        Defn::Const(id.as_ref().to_string(), Value::Const(v), Doc::default())
+    }
+
+    // A typedef of a type to its own name, as in C's `typedef struct foo foo;`, which doesn't
+    // define anything new
+    fn is_self_alias(&self) -> bool {
+        match self {
+            &Defn::Typesyn(ref id, Type::Ident(ref ty, _), _) => id == ty,
+            _ => false,
+        }
+    }
+
+    fn with_doc(self, doc: Doc) -> Defn {
+        match self {
+            Defn::Typespec(id, ty, _) => Defn::Typespec(id, ty, doc),
+            Defn::Typesyn(id, ty, _) => Defn::Typesyn(id, ty, doc),
+            Defn::Const(id, v, _) => Defn::Const(id, v, doc),
+            defn => defn,
+        }
+    }
+}
+
+pub trait Emit {
+    fn define(&self, symtab: &Symtab) -> Result<Tokens>;
+}
+
+pub trait Emitpack: Emit {
+    fn pack(&self, symtab: &Symtab) -> Result<Option<Tokens>>;
+    fn unpack(&self, symtab: &Symtab) -> Result<Option<Tokens>>;
+}
+
+impl Emit for Const {
+    fn define(&self, symtab: &Symtab) -> Result<Tokens> {
+        let name = quote_ident(&self.0);
+        let val = int_token(self.1);
+        let doc = symtab.doc(&self.0);
+
+        // Constants too big for an i64 can only be unsigned hypers
+        let ty = if self.1 > i64::max_value() as i128 { quote!(u64) } else { quote!(i64) };
+        Ok(quote!(#doc pub const #name: #ty = #val;))
+    }
+}
+
+impl Emit for Typesyn {
+    fn define(&self, symtab: &Symtab) -> Result<Tokens> {
+        let ty = &self.1;
+        let name = quote_ident(&self.0);
+        let tok = ty.as_token(symtab)?;
+        let doc = symtab.doc(&self.0);
+        Ok(quote!(#doc pub type #name = #tok;))
+    }
+}
+
+impl Emit for Typespec {
+    fn define(&self, symtab: &Symtab) -> Result<Tokens> {
+        use self::Type::*;
+
+        let name = quote_ident(&self.0);
+        let ty = &self.1;
+        let doc = symtab.doc(&self.0);
+
+        let ret = match ty {
             &Enum(ref edefs) => {
+                let default = symtab.derives(ty).contains(Derives::DEFAULT);
                 let defs: Vec<_> = edefs
                     .iter()
-                    .filter_map(|&EnumDefn(ref field, _)| if let Some((val, Some(_))) =
+                    .filter_map(|&EnumDefn(ref field, _, ref doc)| if let Some((val, Some(_))) =
                         symtab.getconst(field)
                     {
-                        Some((quote_ident(field), val as isize))
+                        Some((quote_ident(field), val as isize, doc))
                     } else {
                         None
                     })
-                    .map(|(field, val)| quote!(#field = #val,))
+                    .enumerate()
+                    .map(|(idx, (field, val, doc))| if default && idx == 0 {
+                        quote!(#doc #[default] #field = #val,)
+                    } else {
+                        quote!(#doc #field = #val,)
+                    })
                     .collect();
 
-                let derive = ty.derivable(symtab, None);
-                quote!(#derive pub enum #name { #(#defs)* })
+                let derive = symtab.type_attrs(&self.0, ty);
+                let mut ret = quote!(#doc #derive pub enum #name { #(#defs)* });
+                ret.append(enum_conversions(&name, edefs, symtab));
+                ret
             }
 
             &Struct(ref decls) => {
-                let decls: Vec<_> = decls
+                let fields: Vec<_> = decls
                     .iter()
-                    .filter_map(|decl| result_option(decl.as_token(symtab)))
-                    .map(|res| res.map(|(field, ty)| quote!(pub #field: #ty,)))
+                    .filter_map(|decl| result_option(decl.as_token(symtab)).map(|res| (decl.doc(), res)))
+                    .map(|(doc, res)| res.map(|(field, ty)| quote!(#doc pub #field: #ty,)))
                     .collect::<Result<Vec<_>>>()?;
 
-                let derive = ty.derivable(symtab, None);
-                quote! {
+                let derive = symtab.type_attrs(&self.0, ty);
+                let mut ret = quote! {
+                    #doc
                     #derive
-                    pub struct #name { #(#decls)* }
-                }
-            }
-
-            &Union(ref selector, ref cases, ref defl) => {
-                let selector = selector.as_ref();
-                use self::Decl::*;
-                use self::Value::*;
-
-                let labelfields = false; // true - include label in enum branch
+                    pub struct #name { #(#fields)* }
+                };
 
-                // return true if case is compatible with the selector
-                let compatcase = |case: &Value| {
-                    let seltype = match selector {
-                        &Void => return false,
-                        &Named(_, ref ty) => ty,
-                    };
+                if symtab.manual_default(ty) {
+                    let defaults: Vec<_> = decls
+                        .iter()
+                        .filter_map(|decl| decl.name_as_ident())
+                        .map(|(field, ty)| {
+                            let val = ty.default_value(symtab);
+                            quote!(#field: #val,)
+                        })
+                        .collect();
 
-                    match case {
-                        &Const(val) if val < 0 => {
-                            match seltype {
-                                &Int | &Hyper => true,
-                                _ => false,
-                            }
+                    ret.append(quote! {
+                        impl Default for #name {
+                            fn default() -> Self { #name { #(#defaults)* } }
                         }
+                    });
+                }
 
-                        &Const(_) => {
-                            match seltype {
-                                &Int | &Hyper | &UInt | &UHyper => true,
-                                _ => false,
-                            }
-                        }
+                if symtab.builders() {
+                    ret.append(struct_builder(&self.0, decls, symtab)?);
+                }
 
-                        &Ident(ref id) => {
-                            if *seltype == Bool {
-                                id == "TRUE" || id == "FALSE"
-                            } else {
-                                if let &Type::Ident(ref selname, _) = seltype {
-                                    match symtab.getconst(id) {
-                                        Some((_, Some(ref scope))) => scope == selname,
-                                        _ => false,
-                                    }
-                                } else {
-                                    false
+                // Dropping a list recursively could overflow the stack, so unlink it first
+                if let Some(link) = symtab.list_link(&self.0, decls).filter(|_| symtab.iterative_drop()) {
+                    let link = quote_ident(link);
+                    ret.append(quote! {
+                        impl Drop for #name {
+                            fn drop(&mut self) {
+                                let mut next = self.#link.take();
+                                while let Some(mut node) = next {
+                                    next = node.#link.take();
                                 }
                             }
                         }
+                    });
+                }
+                ret
+            }
+
+            &Union(ref selector, ref cases, ref defl) => {
+                let selector = selector.as_ref();
+
+                for &UnionCase(ref val, ..) in cases {
+                    if !case_compatible(selector, val, symtab) {
+                        return Err(Error::from(
+                            format!("incompat selector {:?} case {:?}", selector, val),
+                        ));
                     }
-                };
+                }
 
-                let mut cases: Vec<_> = cases
-                    .iter()
-                    .map(|&UnionCase(ref val, ref decl)| {
-                        if !compatcase(val) {
-                            return Err(Error::from(
-                                format!("incompat selector {:?} case {:?}", selector, val),
-                            ));
-                        }
+                let labelled = symtab.labelled_unions();
+                let arms = symtab.union_arms(selector, cases, defl);
+                let selty = selector_token(selector, symtab)?;
 
-                        let label = val.as_ident();
-
-                        match decl {
-                            &Void => Ok(quote!(#label,)),
-                            &Named(ref name, ref ty) => {
-                                let mut tok = ty.as_token(symtab)?;
-                                if false && ty.is_boxed(symtab) {
-                                    tok = quote!(Box<#tok>)
-                                };
-                                if labelfields {
-                                    let name = quote_ident(name);
-                                    Ok(quote!(#label { #name : #tok },))
+                let variants = arms
+                    .iter()
+                    .map(|arm| {
+                        let doc = arm.doc;
+                        let tok = match arm.decl {
+                            &Decl::Void => quote!(),
+                            &Decl::Named(_, ref ty, _) => {
+                                let tok = ty.as_token(symtab)?;
+                                if arm.cases.is_empty() && ty.is_boxed(symtab) {
+                                    quote!(Box<#tok>)
                                 } else {
-                                    Ok(quote!(#label(#tok),))
+                                    tok
                                 }
                             }
-                        }
+                        };
+                        let variant = arm.variant(quote!(), labelled, selty.clone(), tok);
+                        Ok(quote!(#doc #variant,))
                     })
                     .collect::<Result<Vec<_>>>()?;
 
-                if let &Some(ref def_val) = defl {
-                    let def_val = def_val.as_ref();
-                    match def_val {
-                        &Named(ref name, ref ty) => {
-                            let mut tok = ty.as_token(symtab)?;
-                            if ty.is_boxed(symtab) {
-                                tok = quote!(Box<#tok>)
-                            };
-                            if labelfields {
-                                let name = quote_ident(name);
-                                cases.push(quote!(default { #name: #tok },))
-                            } else {
-                                cases.push(quote!(default(#tok),))
-                            }
-                        }
-                        &Void => cases.push(quote!(default,)),
-                    }
-                }
+                let methods = union_methods(name.clone(), selector, &arms, symtab)?;
 
-                let derive = ty.derivable(symtab, None);
+                let derive = symtab.type_attrs(&self.0, ty);
                 quote! {
+                    #doc
                     #derive
-                    pub enum #name { #(#cases)* }
+                    pub enum #name { #(#variants)* }
+                    impl #name { #methods }
                 }
             }
 
             &Flex(..) | &Array(..) => {
                 let tok = ty.as_token(symtab)?;
-                let derive = ty.derivable(symtab, None);
-                quote! {
+                let derive = symtab.type_attrs(&self.0, ty);
+                let mut ret = quote! {
+                    #doc
                     #derive
                     pub struct #name(pub #tok);
+                };
+
+                if symtab.manual_default(ty) {
+                    let val = ty.default_value(symtab);
+                    ret.append(quote! {
+                        impl Default for #name {
+                            fn default() -> Self { #name(#val) }
+                        }
+                    });
                 }
+                ret
             }
 
             _ => {
                 let tok = ty.as_token(symtab)?;
-                quote!(pub type #name = #tok;)
+                quote!(#doc pub type #name = #tok;)
             }
         };
         Ok(ret)
@@ -773,60 +1491,96 @@ impl Emitpack for Typespec {
             }
 
             &Struct(ref decl) => {
+                let link = symtab.list_link(&self.0, decl);
+                let node = if link.is_some() { quote!(node) } else { quote!(self) };
                 let decls: Vec<_> = decl.iter()
                     .filter_map(|d| match d {
                         &Void => None,
-                        &Named(ref name, ref ty) => Some((quote_ident(name), ty)),
-                    })
-                    .map(|(field, ty)| {
-                        let p = ty.packer(quote!(self.#field), symtab).unwrap();
-                        quote!(#p + )
+                        &Named(ref name, _, _) if Some(name.as_str()) == link => None,
+                        &Named(ref name, ref ty, _) => Some((quote_ident(name), ty)),
                     })
+                    .map(|(field, ty)| ty.packer(quote!(#node.#field), symtab).unwrap())
                     .collect();
-                quote!(#(#decls)* 0)
+
+                match link {
+                    // A single field's result is passed straight through
+                    None if decls.is_empty() => quote!(Ok(0)),
+                    None if decls.len() == 1 => decls[0].clone(),
+                    None => quote!(Ok(#(#decls?)+*)),
+                    Some(link) => {
+                        // Lists are packed a node at a time rather than recursively
+                        let link = quote_ident(link);
+                        quote!({
+                            let mut sz = 0;
+                            let mut node = self;
+                            loop {
+                                #(sz += #decls?;)*
+                                match node.#link {
+                                    None => break Ok(sz + false.pack(out)?),
+                                    Some(ref next) => {
+                                        sz += true.pack(out)?;
+                                        node = next;
+                                    }
+                                }
+                            }
+                        })
+                    }
+                }
             }
 
-            &Union(_, ref cases, ref defl) => {
-                let mut matches: Vec<_> = cases
+            &Union(ref sel, ref cases, ref defl) => {
+                let labelled = symtab.labelled_unions();
+                let arms = symtab.union_arms(sel, cases, defl);
+
+                let matches: Vec<_> = arms
                     .iter()
-                    .filter_map(|&UnionCase(ref val, ref decl)| {
-                        let label = val.as_ident();
-                        let disc = val.as_token(symtab);
-
-                        let ret = match decl {
-                            &Void => quote!(&#name::#label => (#disc as i32).pack(out)?,),
-                            &Named(_, ref ty) => {
-                                let pack = match ty.packer(quote!(val), symtab) {
-                                    Err(_) => return None,
-                                    Ok(p) => p,
-                                };
-                                quote!(&#name::#label(ref val) => (#disc as i32).pack(out)? + #pack,)
+                    .filter_map(|arm| {
+                        let pat = arm.variant(quote!(#name::), labelled, quote!(disc), quote!(val));
+                        let body = match arm.decl {
+                            &Void => quote!(),
+                            &Named(_, ref ty, _) => match ty.packer(quote!(val), symtab) {
+                                Err(_) => return None,
+                                Ok(p) => quote!(+ #p?),
+                            },
+                        };
+
+                        let ret = if arm.cases.is_empty() {
+                            // The default arm doesn't know its discriminant
+                            let pat = arm.variant(quote!(#name::), labelled, quote!(_), quote!(_));
+                            quote!(#pat => return Err(xdr_codec::Error::invalidcase(-1)),)
+                        } else if arm.shared() {
+                            // The discriminant has to be one of the arm's cases
+                            let disc = symtab.discriminant_of(sel, quote!(disc));
+                            let discs: Vec<_> = arm.cases.iter().map(|val| symtab.discriminant(sel, val)).collect();
+                            let err = symtab.invalid_case(sel, quote!(disc));
+                            // A bool selector's shared arm has both values, so there's nothing to check
+                            let check = if symtab.is_exhaustive(sel, &arms) {
+                                quote!()
+                            } else {
+                                quote! {
+                                    if #(disc != #discs)&&* {
+                                        return Err(#err);
+                                    }
+                                }
+                            };
+                            quote! {
+                                #pat => {
+                                    let disc = #disc;
+                                    #check
+                                    disc.pack(out)? #body
+                                }
                             }
+                        } else {
+                            // Negative literals need parentheses to call a method on them
+                            let disc = symtab.discriminant(sel, arm.cases[0]);
+                            let disc = if disc.as_str().starts_with('-') { quote!((#disc)) } else { disc };
+                            quote!(#pat => #disc.pack(out)? #body,)
                         };
                         Some(ret)
                     })
                     .collect();
 
-                if let &Some(ref decl) = defl {
-                    let decl = decl.as_ref();
-                    // Can't cast a value-carrying enum to i32
-                    let default = match decl {
-                        &Void => {
-                            quote! {
-                                &#name::default => return Err(xdr_codec::Error::invalidcase(-1)),
-                            }
-                        }
-                        &Named(_, _) => {
-                            quote! {
-                                &#name::default(_) => return Err(xdr_codec::Error::invalidcase(-1)),
-                            }
-                        }
-                    };
-
-                    matches.push(default)
-                }
-
-                quote!(match self { #(#matches)* })
+                quote!(Ok(match self { #(#matches)* }))
             }
 
             // Array and Flex types are wrapped in tuple structs
@@ -849,7 +1603,7 @@ impl Emitpack for Typespec {
             impl<Out: xdr_codec::Write> xdr_codec::Pack<Out> for #name {
                 #directive
                     fn pack(&self, out: &mut Out) -> xdr_codec::Result<usize> {
-                        Ok(#body)
+                        #body
                     }
             }
         }))
@@ -862,110 +1616,137 @@ impl Emitpack for Typespec {
         let name = quote_ident(&self.0);
         let ty = &self.1;
         let mut directive = quote!();
+        let mut sink_body = None;
 
         let body = match ty {
-            &Enum(ref defs) => {
+            &Enum(_) => {
                 directive = quote!(#[inline]);
-                let matchdefs: Vec<_> = defs.iter()
-                    .filter_map(|&EnumDefn(ref name, _)| {
-                        let tok = quote_ident(name);
-                        if let Some((ref _val, ref scope)) = symtab.getconst(name) {
-                            // let val = *val as i32;
-                            if let &Some(ref scope) = scope {
-                                let scope = quote_ident(scope);
-                                // Some(quote!(#val => #scope :: #tok,))
-                                Some(quote!(x if x == #scope :: #tok as i32 => #scope :: #tok,))
-                            } else {
-                                // Some(quote!(#val => #tok,))
-                                Some(quote!(x if x == #tok as i32 => #tok,))
-                            }
-                        } else {
-                            println!("unknown ident {}", name);
-                            None
-                        }
-                    })
-                    .collect();
-
                 quote!({
                     let (e, esz): (i32, _) = xdr_codec::Unpack::unpack(input)?;
                     sz += esz;
-                    match e {
-                        #(#matchdefs)*
-                        e => return Err(xdr_codec::Error::invalidenum(e))
-                    }
+                    <#name as std::convert::TryFrom<i32>>::try_from(e)?
                 })
             }
 
             &Struct(ref decls) => {
-                let decls: Vec<_> = decls
-                    .iter()
-                    .filter_map(|decl| decl.name_as_ident())
-                    .map(|(field, ty)| {
-                        let unpack = ty.unpacker(symtab);
-                        quote!(#field: { let (v, fsz) = #unpack; sz += fsz; v },)
-                    })
-                    .collect();
+                let link = symtab.list_link(&self.0, decls).map(quote_ident);
+                let body = |sink: bool| {
+                    let fields: Vec<_> = decls
+                        .iter()
+                        .filter_map(|decl| decl.name_as_ident())
+                        .map(|(field, ty)| if Some(&field) == link.as_ref() {
+                            quote!(#field: None,)
+                        } else {
+                            let unpack = match ty.sink_unpacker(symtab) {
+                                Some(unpack) if sink => unpack,
+                                _ => ty.unpacker(symtab),
+                            };
+                            quote!(#field: { let (v, fsz) = #unpack; sz += fsz; v },)
+                        })
+                        .collect();
+
+                    match link {
+                        None => quote!(#name { #(#fields)* }),
+                        Some(ref link) => {
+                            // Lists are unpacked a node at a time, appending each to the last
+                            let node = quote!(#name { #(#fields)* });
+                            quote!({
+                                let mut ret = #node;
+                                let mut tail = &mut ret.#link;
+                                loop {
+                                    let (more, lsz): (bool, _) = xdr_codec::Unpack::unpack(input)?;
+                                    sz += lsz;
+                                    if !more {
+                                        break;
+                                    }
+                                    let node = tail.insert(Box::new(#node));
+                                    tail = &mut node.#link;
+                                }
+                                ret
+                            })
+                        }
+                    }
+                };
 
-                quote!(#name { #(#decls)* })
+                if symtab.is_streamed(&Ident(self.0.clone(), None)) {
+                    sink_body = Some(body(true));
+                }
+                body(false)
             }
 
             &Union(ref sel, ref cases, ref defl) => {
                 let sel = sel.as_ref();
-                let mut matches: Vec<_> =
-                    cases.iter()
-                        .map(|&UnionCase(ref val, ref decl)| {
-                            let label = val.as_ident();
-                            let disc = match val.as_i64(symtab) {
-                                Some(v) => v as i32,
-                                None => return Err(Error::from(format!("discriminant value {:?} unknown", val))),
-                            };
-
-                            let ret = match decl {
-                                //&Void => quote!(#disc => #name::#label,),
-                                &Void => quote!(x if x == (#disc as i32) => #name::#label,),
-                                &Named(_, ref ty) => {
-                                    let unpack = ty.unpacker(symtab);
-                                    //quote!(#disc => #name::#label({ let (v, fsz) = #unpack; sz += fsz; v }),)
-                                    quote!(x if x == (#disc as i32) => #name::#label({ let (v, fsz) = #unpack; sz += fsz; v }),)
-                                },
-                            };
-                            Ok(ret)
-                        })
-                        .collect::<Result<Vec<_>>>()?;
-
-                if let &Some(ref decl) = defl {
-                    let decl = decl.as_ref();
-                    let defl = match decl {
-                        &Void => quote!(_ => #name::default),
-                        &Named(_, ref ty) => {
+                let labelled = symtab.labelled_unions();
+                let arms = symtab.union_arms(sel, cases, defl);
+
+                // Arms holding streamed data write it to the sink in `unpack_into`
+                let mut matches = Vec::new();
+                let mut sink_matches = Vec::new();
+                for arm in &arms {
+                    let (val, sink_val) = match arm.decl {
+                        &Void => (quote!(), quote!()),
+                        &Named(_, ref ty, _) => {
                             let unpack = ty.unpacker(symtab);
-                            quote!(_ => #name::default({
-                                let (v, csz) = #unpack;
-                                sz += csz;
-                                v
-                            }))
+                            let sink_unpack = ty.sink_unpacker(symtab).unwrap_or_else(|| unpack.clone());
+                            (
+                                quote!({ let (v, fsz) = #unpack; sz += fsz; v }),
+                                quote!({ let (v, fsz) = #sink_unpack; sz += fsz; v }),
+                            )
                         }
                     };
 
-                    matches.push(defl);
-                } else {
-                    let defl = quote!(v => return Err(xdr_codec::Error::invalidcase(v as i32)));
-                    matches.push(defl);
+                    if arm.cases.is_empty() {
+                        if symtab.is_exhaustive(sel, &arms) {
+                            continue;
+                        }
+                        let variant = arm.variant(quote!(#name::), labelled, quote!(), val);
+                        matches.push(quote!(_ => #variant,));
+                        let variant = arm.variant(quote!(#name::), labelled, quote!(), sink_val);
+                        sink_matches.push(quote!(_ => #variant,));
+                        continue;
+                    }
+
+                    for case in &arm.cases {
+                        if case.as_int(symtab).is_none() {
+                            return Err(Error::from(format!("discriminant value {:?} unknown", case)));
+                        }
+                        let disc = symtab.discriminant(sel, case);
+                        let selval = symtab.selector_value(sel, case);
+                        let variant = arm.variant(quote!(#name::), labelled, selval.clone(), val.clone());
+                        matches.push(quote!(#disc => #variant,));
+                        let variant = arm.variant(quote!(#name::), labelled, selval, sink_val.clone());
+                        sink_matches.push(quote!(#disc => #variant,));
+                    }
+                }
+
+                if defl.is_none() && !symtab.is_exhaustive(sel, &arms) {
+                    let err = symtab.invalid_case(sel, quote!(disc));
+                    matches.push(quote!(_ => return Err(#err),));
+                    sink_matches.push(quote!(_ => return Err(#err),));
                 }
 
                 let selunpack = match sel {
                     &Void => panic!("void switch selector?"),
-                    &Named(_, ref ty) => ty.unpacker(symtab),
+                    &Named(_, ref ty, _) => ty.unpacker(symtab),
                 };
 
-                quote!(match { let (v, dsz): (i32, _) = #selunpack; sz += dsz; v } { #(#matches)* })
+                let discty = symtab.discriminant_type(sel);
+                let selunpack = quote!(let (disc, dsz): (#discty, _) = #selunpack; sz += dsz;);
+
+                if symtab.is_streamed(&Ident(self.0.clone(), None)) {
+                    sink_body = Some(quote!({ #selunpack match disc { #(#sink_matches)* } }));
+                }
+                quote!({ #selunpack match disc { #(#matches)* } })
             }
 
             &Option(_) => ty.unpacker(symtab),
 
             &Flex(_, _) | &Array(_, _) => {
+                if let Some(unpk) = ty.sink_unpacker(symtab) {
+                    sink_body = Some(quote!({ let (v, usz) = #unpk; sz += usz; #name(v) }));
+                }
                 let unpk = ty.unpacker(symtab);
-                quote!({ let (v, usz) = #unpk; sz = usz; #name(v) })
+                quote!({ let (v, usz) = #unpk; sz += usz; #name(v) })
             }
 
             &Ident(_, _) => return Ok(None),
@@ -974,7 +1755,7 @@ impl Emitpack for Typespec {
             _ => return Err(Error::from(format!("unimplemented ty={:?}", ty))),
         };
 
-        Ok(Some(quote! {
+        let mut ret = quote! {
             impl<In: xdr_codec::Read> xdr_codec::Unpack<In> for #name {
                 #directive
                     fn unpack(input: &mut In) -> xdr_codec::Result<(#name, usize)> {
@@ -982,23 +1763,48 @@ impl Emitpack for Typespec {
                         Ok((#body, sz))
                     }
             }
-        }))
+        };
+
+        // Streamed opaque data can also be written to a sink rather than read into memory
+        if let Some(body) = sink_body {
+            ret.append(quote! {
+                impl #name {
+                    pub fn unpack_into<In, W>(input: &mut In, sink: &mut W) -> xdr_codec::Result<(#name, usize)>
+                    where
+                        In: xdr_codec::Read,
+                        W: xdr_codec::Write + ?Sized,
+                    {
+                        let mut sz = 0;
+                        Ok((#body, sz))
+                    }
+                }
+            });
+        }
+
+        Ok(Some(ret))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Symtab {
-    consts: BTreeMap<String, (i64, Option<String>)>,
+    consts: BTreeMap<String, (i128, Option<String>)>,
     typespecs: BTreeMap<String, Type>,
     typesyns: BTreeMap<String, Type>,
+    docs: BTreeMap<String, Doc>,
+    config: Config,
+    // All definitions in source order, with their locations
+    defns: Vec<(Location, Defn)>,
 }
 
 impl Symtab {
-    pub fn new(defns: &Vec<Defn>) -> Symtab {
+    pub fn new(defns: &Vec<(Location, Defn)>, config: &Config) -> Symtab {
         let mut ret = Symtab {
             consts: BTreeMap::new(),
             typespecs: BTreeMap::new(),
             typesyns: BTreeMap::new(),
+            docs: BTreeMap::new(),
+            config: config.clone(),
+            defns: defns.clone(),
         };
 
         ret.update_consts(&defns);
@@ -1006,38 +1812,64 @@ impl Symtab {
         ret
     }
 
-    fn update_consts(&mut self, defns: &Vec<Defn>) {
-        for defn in defns {
+    fn update_consts(&mut self, defns: &Vec<(Location, Defn)>) {
+        // Constants can be defined in terms of each other in any order, so keep evaluating them
+        // until nothing changes. Anything left undefined is reported by validation.
+        loop {
+            let before = self.consts.clone();
+
+            for &(_, ref defn) in defns {
+                match defn {
+                    &Defn::Typespec(ref name, ref ty, _) => self.update_enum_consts(name, ty),
+                    &Defn::Const(ref name, ref val, _) => {
+                        if let Some(v) = self.value(val) {
+                            self.defconst(name, v, None);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+
+            if self.consts == before {
+                break;
+            }
+        }
+
+        // Types have their constant expressions replaced by values
+        for &(_, ref defn) in defns {
             match defn {
-                &Defn::Typespec(ref name, ref ty) => {
-                    self.deftype(name, ty);
-                    self.update_enum_consts(name, ty);
+                &Defn::Typespec(ref name, ref ty, ref doc) => {
+                    let ty = ty.fold(self).map_fields(name, &self.config);
+                    self.deftype(name, &ty);
+                    self.defdoc(name, doc);
                 }
 
-                &Defn::Const(ref name, val) => self.defconst(name, val, None),
+                &Defn::Const(ref name, _, ref doc) => {
+                    self.defdoc(name, doc);
+                }
 
-                &Defn::Typesyn(ref name, ref ty) => {
-                    self.deftypesyn(name, ty);
+                &Defn::Typesyn(ref name, ref ty, ref doc) => {
+                    let ty = ty.fold(self).map_fields(name, &self.config);
+                    self.deftypesyn(name, &ty);
+                    self.defdoc(name, doc);
                 }
+
+                &Defn::Include(..) | &Defn::Passthrough(_) => (),
             }
         }
     }
 
     fn update_enum_consts(&mut self, scope: &String, ty: &Type) {
-        let mut err = stderr();
         let mut prev = -1;
 
         if let &Type::Enum(ref edefn) = ty {
-            for &EnumDefn(ref name, ref maybeval) in edefn {
+            for &EnumDefn(ref name, ref maybeval, _) in edefn {
                 let v = match maybeval {
                     &None => prev + 1,
                     &Some(ref val) => {
                         match self.value(val) {
                             Some(c) => c,
-                            None => {
-                                let _ = writeln!(&mut err, "Unknown value {:?}", val);
-                                continue;
-                            }
+                            None => continue, // reported by validation
                         }
                     }
                 };
@@ -1050,7 +1882,7 @@ impl Symtab {
         }
     }
 
-    fn defconst<S: AsRef<str>>(&mut self, name: S, val: i64, scope: Option<String>) {
+    fn defconst<S: AsRef<str>>(&mut self, name: S, val: i128, scope: Option<String>) {
         self.consts.insert(From::from(name.as_ref()), (val, scope));
     }
 
@@ -1062,17 +1894,234 @@ impl Symtab {
         self.typesyns.insert(From::from(name.as_ref()), ty.clone());
     }
 
-    pub fn getconst(&self, name: &String) -> Option<(i64, Option<String>)> {
+    fn defdoc<S: AsRef<str>>(&mut self, name: S, doc: &Doc) {
+        if !doc.is_empty() {
+            self.docs.insert(From::from(name.as_ref()), doc.clone());
+        }
+    }
+
+    pub fn getconst(&self, name: &String) -> Option<(i128, Option<String>)> {
         match self.consts.get(name) {
             None => None,
             Some(c) => Some(c.clone()),
         }
     }
 
-    pub fn value(&self, val: &Value) -> Option<i64> {
+    pub fn value(&self, val: &Value) -> Option<i128> {
+        self.eval(val).ok()
+    }
+
+    /// Evaluate a constant expression, or say why it can't be.
+    pub fn eval(&self, val: &Value) -> result::Result<i128, String> {
         match val {
-            &Value::Const(c) => Some(c),
-            &Value::Ident(ref id) => self.getconst(id).map(|(v, _)| v),
+            &Value::Const(c) => Ok(c),
+            &Value::Ident(ref id) => {
+                self.getconst(id)
+                    .map(|(v, _)| v)
+                    .or_else(|| builtin_const(id))
+                    .ok_or_else(|| format!("undefined constant `{}`", id))
+            }
+            &Value::Neg(ref v) => {
+                self.eval(v)?.checked_neg().filter(|&v| in_range(v)).ok_or_else(|| format!("`{}` overflows", val))
+            }
+            &Value::BinOp(op, ref a, ref b) => {
+                let (a, b) = (self.eval(a)?, self.eval(b)?);
+                match op.apply(a, b).filter(|&v| in_range(v)) {
+                    Some(v) => Ok(v),
+                    None if op == BinOp::Div && b == 0 => Err(format!("division by zero in `{}`", val)),
+                    None => Err(format!("`{}` overflows", val)),
+                }
+            }
+        }
+    }
+
+    /// The type of a union's selector, looking through typedefs. Enums are left as references
+    /// to the enum.
+    fn selector_type<'a>(&'a self, selector: &'a Decl) -> Option<&'a Type> {
+        let mut ty = match selector {
+            &Decl::Void => return None,
+            &Decl::Named(_, ref ty, _) => ty,
+        };
+        while let &Type::Ident(ref name, _) = ty {
+            match self.typesyns.get(name) {
+                Some(syn) => ty = syn,
+                None => break,
+            }
+        }
+        Some(ty)
+    }
+
+    // The value of a union case as the selector's type, which for enum selectors is the enum
+    fn selector_value(&self, selector: &Decl, case: &Value) -> Tokens {
+        match self.selector_type(selector) {
+            Some(&Type::Ident(ref name, _)) if self.is_enum(name) => case.as_token(self),
+            _ => self.discriminant(selector, case),
+        }
+    }
+
+    // Convert a reference to a value of the selector's type into its discriminant
+    fn discriminant_of(&self, selector: &Decl, val: Tokens) -> Tokens {
+        match self.selector_type(selector) {
+            Some(&Type::Ident(..)) => quote!(*#val as i32),
+            _ => quote!(*#val),
+        }
+    }
+
+    fn is_enum(&self, name: &str) -> bool {
+        match self.typespecs.get(name) {
+            Some(&Type::Enum(_)) => true,
+            _ => false,
+        }
+    }
+
+    // The field linking a struct to the next element of a list, as in `struct entry { ...; entry
+    // *next; }`. It has to be the struct's last field, and an optional pointer to the struct itself,
+    // perhaps by way of typedefs.
+    fn list_link<'a>(&self, name: &str, decls: &'a [Decl]) -> Option<&'a str> {
+        let (field, mut ty) = match decls.last() {
+            Some(&Decl::Named(ref field, ref ty, _)) => (field, ty),
+            _ => return None,
+        };
+
+        // Bounded by the number of typedefs, in case they're circular
+        for _ in 0..=self.typesyns.len() {
+            match ty {
+                &Type::Option(ref ty) => {
+                    return match ty.as_ref() {
+                        &Type::Ident(ref id, _) if id == name => Some(field),
+                        _ => None,
+                    };
+                }
+                &Type::Ident(ref id, _) => match self.typesyns.get(id) {
+                    Some(syn) => ty = syn,
+                    None => return None,
+                },
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    // The variable-length type a field has, perhaps by way of typedefs, and the newtype it's wrapped
+    // in, if any, as `typedef opaque fh<64>` is.
+    fn flex_type<'a>(&'a self, mut ty: &'a Type) -> Option<(&'a Type, Option<&'a str>)> {
+        let mut newtype = None;
+
+        // Bounded by the number of typedefs (plus the newtype), in case they're circular
+        for _ in 0..=self.typesyns.len() + 1 {
+            match ty {
+                &Type::Flex(..) => return Some((ty, newtype)),
+                &Type::Ident(ref id, _) if newtype.is_none() && self.extern_type(id).is_none() => {
+                    if let Some(syn) = self.typesyns.get(id) {
+                        ty = syn;
+                    } else {
+                        ty = self.typespecs.get(id)?;
+                        newtype = Some(id.as_str());
+                    }
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    // Whether a type is or contains streamed opaque data, perhaps by way of typedefs, structs and
+    // unions, so it has an `unpack_into` function.
+    fn is_streamed(&self, ty: &Type) -> bool {
+        self.streamed_within(ty, &mut HashSet::new())
+    }
+
+    fn streamed_within<'a>(&'a self, ty: &'a Type, seen: &mut HashSet<&'a str>) -> bool {
+        match ty {
+            &Type::Flex(ref ty, _) => **ty == Type::OpaqueStream,
+            // Each type is only looked into once, in case they're recursive
+            &Type::Ident(ref name, _) if self.extern_type(name).is_none() && seen.insert(name) => {
+                let mut decls: Vec<&Decl> = Vec::new();
+                match self.typespec(name) {
+                    Some(&Type::Struct(ref fields)) => decls.extend(fields),
+                    Some(&Type::Union(_, ref cases, ref defl)) => {
+                        decls.extend(cases.iter().map(|case| &case.1));
+                        decls.extend(defl.iter().map(|&(ref decl, _)| decl.as_ref()));
+                    }
+                    Some(ty) => return self.streamed_within(ty, seen),
+                    None => (),
+                }
+                decls.into_iter().any(|decl| match decl {
+                    &Decl::Named(_, ref ty, _) => self.streamed_within(ty, seen),
+                    &Decl::Void => false,
+                })
+            }
+            _ => false,
+        }
+    }
+
+    // The Rust type a union's discriminant is packed as. Enums are packed as i32.
+    fn discriminant_type(&self, selector: &Decl) -> Tokens {
+        match self.selector_type(selector) {
+            Some(&Type::Bool) => quote!(bool),
+            Some(&Type::UInt) => quote!(u32),
+            Some(&Type::Hyper) => quote!(i64),
+            Some(&Type::UHyper) => quote!(u64),
+            _ => quote!(i32),
+        }
+    }
+
+    // The value of a union case as a literal of its discriminant type, so it can be matched on
+    fn discriminant(&self, selector: &Decl, case: &Value) -> Tokens {
+        let val = match self.value(case) {
+            Some(val) => val,
+            None => {
+                let disc = case.as_token(self);
+                let ty = self.discriminant_type(selector);
+                return quote!((#disc as #ty));
+            }
+        };
+
+        match self.selector_type(selector) {
+            Some(&Type::Bool) => {
+                let val = val != 0;
+                quote!(#val)
+            }
+            Some(&Type::UInt) => {
+                let val = val as u32;
+                quote!(#val)
+            }
+            Some(&Type::Hyper) => {
+                let val = val as i64;
+                quote!(#val)
+            }
+            Some(&Type::UHyper) => {
+                let val = val as u64;
+                quote!(#val)
+            }
+            _ => {
+                let val = val as i32;
+                quote!(#val)
+            }
+        }
+    }
+
+    // Whether a union's cases cover every value of its selector, which can only be so for bools
+    fn is_exhaustive(&self, selector: &Decl, arms: &[UnionArm]) -> bool {
+        match self.selector_type(selector) {
+            Some(&Type::Bool) => {
+                let vals: HashSet<_> = arms
+                    .iter()
+                    .flat_map(|arm| &arm.cases)
+                    .filter_map(|val| self.value(val))
+                    .map(|v| v != 0)
+                    .collect();
+                vals.len() == 2
+            }
+            _ => false,
+        }
+    }
+
+    // An error for a discriminant which doesn't match any of a union's cases
+    fn invalid_case(&self, selector: &Decl, disc: Tokens) -> Tokens {
+        match self.selector_type(selector) {
+            Some(&Type::Bool) => quote!(xdr_codec::Error::invalidcase(i32::from(#disc))),
+            _ => quote!(xdr_codec::Error::invalidcase(#disc)),
         }
     }
 
@@ -1088,16 +2137,176 @@ impl Symtab {
         }
     }
 
-    pub fn constants(&self) -> Iter<String, (i64, Option<String>)> {
-        self.consts.iter()
+    /// A type defined outside the specification, which is referred to by path
+    pub(crate) fn extern_type(&self, name: &str) -> Option<&ExternType> {
+        self.config.extern_types.get(name)
     }
 
-    pub fn typespecs(&self) -> Iter<String, Type> {
-        self.typespecs.iter()
+    fn builders(&self) -> bool {
+        self.config.builders
+    }
+
+    /// Whether variable-length types with a maximum size use xdr-codec's bounded types
+    fn bounded_types(&self) -> bool {
+        self.config.bounded_types
+    }
+
+    fn iterative_drop(&self) -> bool {
+        self.config.iterative_drop
+    }
+
+    /// Whether union arms are struct-like variants, labelled with the arm's field name
+    fn labelled_unions(&self) -> bool {
+        self.config.union_labels
+    }
+
+    // The variant names for a union's cases. These are normally the case values, with numbers
+    // named like `Const5`. Labelled unions instead name numeric cases after their arm's field,
+    // where that's unique within the union, and numeric cases of a bool `TRUE` and `FALSE`.
+    fn case_labels(&self, selector: &Decl, cases: &[UnionCase]) -> Vec<quote::Ident> {
+        let labels: Vec<_> = cases.iter().map(|c| c.0.as_ident()).collect();
+        if !self.labelled_unions() {
+            return labels;
+        }
+
+        let boolsel = self.selector_type(selector) == Some(&Type::Bool);
+        let unique = |field: &str| {
+            let fields = cases
+                .iter()
+                .filter(|c| match c.1 {
+                    Decl::Named(ref name, ..) => name == field,
+                    Decl::Void => false,
+                })
+                .count();
+            let label = quote_ident(field);
+            fields == 1 && field != "default" && !labels.contains(&label)
+        };
+
+        cases
+            .iter()
+            .zip(labels.iter())
+            .map(|(&UnionCase(ref val, ref decl, _), label)| match (val, decl) {
+                (&Value::Const(1), _) if boolsel => quote::Ident::new("TRUE"),
+                (&Value::Const(0), _) if boolsel => quote::Ident::new("FALSE"),
+                (&Value::Const(_), &Decl::Named(ref field, ..)) if unique(field) => quote_ident(field),
+                _ => label.clone(),
+            })
+            .collect()
+    }
+
+    // The variants of a union, including the default arm if there is one
+    fn union_arms<'a>(
+        &self,
+        selector: &Decl,
+        cases: &'a [UnionCase],
+        defl: &'a Option<(Box<Decl>, Doc)>,
+    ) -> Vec<UnionArm<'a>> {
+        let mut arms: Vec<UnionArm> = Vec::new();
+        let mut labels: Vec<Vec<String>> = Vec::new();
+
+        for (&UnionCase(ref val, ref decl, ref doc), label) in cases.iter().zip(self.case_labels(selector, cases)) {
+            // Several cases sharing an arm are parsed into a copy of the arm for each case
+            if self.config.shared_arms {
+                if let Some(arm) = arms.last_mut() {
+                    if arm.decl == decl {
+                        arm.cases.push(val);
+                        labels.last_mut().unwrap().push(label.as_ref().to_string());
+                        continue;
+                    }
+                }
+            }
+            labels.push(vec![label.as_ref().to_string()]);
+            arms.push(UnionArm { label, cases: vec![val], decl, doc: Some(doc) });
+        }
+
+        if let &Some((ref decl, ref doc)) = defl {
+            arms.push(UnionArm {
+                label: quote::Ident::new("default"),
+                cases: Vec::new(),
+                decl,
+                doc: Some(doc),
+            });
+        }
+
+        // Shared arms are named after their field where no other arm has the same field, and
+        // otherwise after all their cases
+        let taken: Vec<_> = arms.iter().map(|arm| arm.label.clone()).collect();
+        for (idx, labels) in labels.into_iter().enumerate() {
+            if labels.len() == 1 {
+                continue;
+            }
+            let unique = arms[idx].field().and_then(|field| {
+                let label = quote_ident(field);
+                let count = arms.iter().filter(|arm| arm.field() == Some(field)).count();
+                if count == 1 && field != "default" && !taken.contains(&label) {
+                    Some(label)
+                } else {
+                    None
+                }
+            });
+            arms[idx].label = unique.unwrap_or_else(|| quote::Ident::new(labels.join("_")));
+        }
+
+        arms
+    }
+
+    /// Code to copy from a `%` line, if it's wanted
+    pub(crate) fn passthrough<'a>(&self, text: &'a str) -> Option<&'a str> {
+        self.config.passthrough.code(text)
+    }
+
+    /// Path to a named type: the type itself, or where an external type is defined
+    fn type_path(&self, name: &str) -> Tokens {
+        match self.extern_type(name) {
+            Some(ext) => {
+                let mut toks = Tokens::new();
+                toks.append(&ext.path);
+                toks
+            }
+            None => {
+                let id = quote_ident(name);
+                quote!(#id)
+            }
+        }
+    }
+
+    /// Standard traits to derive for a type
+    fn derives(&self, ty: &Type) -> Derives {
+        ty.derivable(self, None) & self.config.std_derives()
+    }
+
+    /// True if `Default` is wanted for a type but needs to be implemented by hand
+    fn manual_default(&self, ty: &Type) -> bool {
+        self.derives(ty).contains(Derives::DEFAULT) && ty.manual_default(self)
+    }
+
+    /// Derives and other attributes for a type definition
+    fn type_attrs(&self, name: &String, ty: &Type) -> Tokens {
+        let mut toks = Tokens::new();
+
+        let mut derives = self.derives(ty);
+        if self.manual_default(ty) {
+            derives.remove(Derives::DEFAULT);
+        }
+        derives.to_tokens(&mut toks);
+
+        let extra: Vec<_> = self.config
+            .extra_derives(name)
+            .map(|d| quote::Ident::new(d.as_str()))
+            .collect();
+        if !extra.is_empty() {
+            toks.append(quote!(#[derive(#(#extra),*)]));
+        }
+
+        for attr in self.config.attributes(name) {
+            toks.append(attr.as_str());
+        }
+
+        toks
     }
 
-    pub fn typesyns(&self) -> Iter<String, Type> {
-        self.typesyns.iter()
+    pub fn doc(&self, name: &String) -> Option<&Doc> {
+        self.docs.get(name)
     }
 }
 
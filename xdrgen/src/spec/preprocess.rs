@@ -0,0 +1,482 @@
+// A minimal C preprocessor: `#define` of integer constants, and conditional sections with `#if`,
+// `#ifdef`, `#ifndef`, `#elif`, `#else` and `#endif`. Other directives are left for the parser.
+use std::collections::BTreeMap;
+
+use super::error::{Diagnostic, Location};
+
+// `line` with its comments blanked out, so that everything else stays where it was. `in_comment` is
+// whether the line starts inside a `/* */` comment, and is left saying whether it ends inside one.
+fn strip_comments(line: &str, in_comment: &mut bool) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+
+    if *in_comment {
+        let len = match rest.find("*/") {
+            Some(p) => {
+                *in_comment = false;
+                p + 2
+            }
+            None => rest.len(),
+        };
+        out.extend(std::iter::repeat_n(' ', len));
+        rest = &rest[len..];
+    }
+
+    while let Some(c) = rest.chars().next() {
+        let len = if let Some(comment) = rest.strip_prefix("/*") {
+            match comment.find("*/") {
+                Some(p) => p + 4,
+                None => {
+                    *in_comment = true;
+                    rest.len()
+                }
+            }
+        } else if rest.starts_with("//") {
+            rest.len()
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        out.extend(std::iter::repeat_n(' ', len));
+        rest = &rest[len..];
+    }
+
+    out
+}
+
+// A directive's name and the rest of its line, if `line` is one.
+fn directive(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start().strip_prefix('#')?.trim_start();
+    let end = line.find(|c: char| !c.is_alphanumeric()).unwrap_or(line.len());
+    Some((&line[..end], line[end..].trim()))
+}
+
+/// An integer literal as C writes them, with an optional sign and suffix.
+pub(crate) fn int_literal(text: &str) -> Option<i64> {
+    let text = text.trim().trim_end_matches(['u', 'U', 'l', 'L']);
+    let (neg, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+
+    let val = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if text.len() > 1 && text.starts_with('0') {
+        i64::from_str_radix(&text[1..], 8).ok()?
+    } else if !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit()) {
+        text.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if neg { -val } else { val })
+}
+
+/// The name and value of `#define NAME VALUE`, if `line` is one. Macros with parameters aren't
+/// constants, so they're not included.
+pub(crate) fn define(line: &str) -> Option<(&str, &str)> {
+    match directive(line)? {
+        ("define", rest) => {
+            let end = rest.find(char::is_whitespace)?;
+            let name = &rest[..end];
+            if name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                Some((name, rest[end..].trim()))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Num(i64),
+    Ident(&'a str),
+    Op(&'a str),
+}
+
+fn tokenize(expr: &str) -> Option<Vec<Token<'_>>> {
+    const OPS: [&str; 22] = [
+        "&&", "||", "==", "!=", "<<", ">>", "<=", ">=", "<", ">", "!", "~", "(", ")", "+", "-", "*", "/", "%", "&",
+        "|", "^",
+    ];
+    let mut toks = Vec::new();
+    let mut rest = expr.trim_start();
+
+    while !rest.is_empty() {
+        let c = rest.chars().next()?;
+        let len = if c.is_alphanumeric() || c == '_' {
+            let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let word = &rest[..len];
+            toks.push(if c.is_ascii_digit() { Token::Num(int_literal(word)?) } else { Token::Ident(word) });
+            len
+        } else {
+            let op = OPS.iter().find(|op| rest.starts_with(*op))?;
+            toks.push(Token::Op(op));
+            op.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+
+    Some(toks)
+}
+
+// Evaluates `#if` expressions by precedence climbing. Undefined names are 0, as in C.
+struct Eval<'a, 'b> {
+    toks: &'b [Token<'a>],
+    pos: usize,
+    defines: &'b BTreeMap<String, Option<i64>>,
+}
+
+impl<'a, 'b> Eval<'a, 'b> {
+    fn next(&mut self) -> Option<Token<'a>> {
+        let tok = self.toks.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn peek_op(&self) -> Option<&'a str> {
+        match self.toks.get(self.pos) {
+            Some(&Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn primary(&mut self) -> Option<i64> {
+        match self.next()? {
+            Token::Num(v) => Some(v),
+            Token::Ident("defined") => {
+                let paren = self.peek_op() == Some("(");
+                if paren {
+                    self.pos += 1;
+                }
+                let name = match self.next()? {
+                    Token::Ident(name) => name,
+                    _ => return None,
+                };
+                if paren && self.next()? != Token::Op(")") {
+                    return None;
+                }
+                Some(self.defines.contains_key(name) as i64)
+            }
+            Token::Ident(name) => Some(self.defines.get(name).cloned().unwrap_or(None).unwrap_or(0)),
+            Token::Op("(") => {
+                let v = self.expr(0)?;
+                if self.next()? != Token::Op(")") {
+                    return None;
+                }
+                Some(v)
+            }
+            Token::Op("!") => Some((self.primary()? == 0) as i64),
+            Token::Op("~") => Some(!self.primary()?),
+            Token::Op("-") => self.primary()?.checked_neg(),
+            Token::Op("+") => self.primary(),
+            Token::Op(_) => None,
+        }
+    }
+
+    fn expr(&mut self, min_prec: u8) -> Option<i64> {
+        let mut lhs = self.primary()?;
+
+        while let Some(op) = self.peek_op() {
+            let prec = match op {
+                "||" => 1,
+                "&&" => 2,
+                "|" => 3,
+                "^" => 4,
+                "&" => 5,
+                "==" | "!=" => 6,
+                "<" | ">" | "<=" | ">=" => 7,
+                "<<" | ">>" => 8,
+                "+" | "-" => 9,
+                "*" | "/" | "%" => 10,
+                _ => break,
+            };
+            if prec < min_prec {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expr(prec + 1)?;
+
+            lhs = match op {
+                "||" => (lhs != 0 || rhs != 0) as i64,
+                "&&" => (lhs != 0 && rhs != 0) as i64,
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "==" => (lhs == rhs) as i64,
+                "!=" => (lhs != rhs) as i64,
+                "<" => (lhs < rhs) as i64,
+                ">" => (lhs > rhs) as i64,
+                "<=" => (lhs <= rhs) as i64,
+                ">=" => (lhs >= rhs) as i64,
+                "<<" => lhs.checked_shl(rhs as u32).filter(|v| rhs >= 0 && v >> rhs == lhs)?,
+                ">>" => lhs.checked_shr(rhs as u32).filter(|_| rhs >= 0)?,
+                "+" => lhs.checked_add(rhs)?,
+                "-" => lhs.checked_sub(rhs)?,
+                "*" => lhs.checked_mul(rhs)?,
+                "/" => lhs.checked_div(rhs)?,
+                _ => lhs.checked_rem(rhs)?,
+            };
+        }
+
+        Some(lhs)
+    }
+}
+
+fn value(expr: &str, defines: &BTreeMap<String, Option<i64>>) -> Option<i64> {
+    let toks = tokenize(expr)?;
+    let mut eval = Eval {
+        toks: &toks,
+        pos: 0,
+        defines: defines,
+    };
+    let v = eval.expr(0)?;
+    if eval.pos == toks.len() { Some(v) } else { None }
+}
+
+fn eval(expr: &str, defines: &BTreeMap<String, Option<i64>>) -> Option<bool> {
+    value(expr, defines).map(|v| v != 0)
+}
+
+// An `#if` section being processed
+struct Cond {
+    // Where the section started
+    offset: usize,
+    // True if the enclosing section is active
+    outer: bool,
+    // True if the current branch is active
+    active: bool,
+    // True if a branch has been taken
+    taken: bool,
+    // True after `#else`
+    seen_else: bool,
+}
+
+/// Apply the conditional sections in `source`, with `defines` defined beforehand.
+///
+/// Lines in inactive sections and the conditional directives themselves are blanked, so the
+/// result has the same lines as the source. `#define`s in active sections are kept for the parser.
+pub fn preprocess(source: &str, defines: &BTreeMap<String, i64>) -> Result<String, Diagnostic> {
+    let mut defines: BTreeMap<String, Option<i64>> = defines.iter().map(|(k, &v)| (k.clone(), Some(v))).collect();
+    let mut conds: Vec<Cond> = Vec::new();
+    let mut out = String::with_capacity(source.len());
+    let mut offset = 0;
+    let mut in_comment = false;
+
+    for line in source.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\n', '\r']);
+        let ending = &line[content.len()..];
+        let active = conds.last().map_or(true, |c| c.active);
+        let err = |msg: String| Diagnostic::error(&Location::new(source, offset, content.len()), msg);
+
+        // Comments can follow a directive's arguments, and aren't part of them. A line which starts
+        // inside a comment isn't a directive, even if it looks like one.
+        let commented = in_comment;
+        let stripped = strip_comments(content, &mut in_comment);
+        let directive = if commented { None } else { directive(&stripped) };
+        let mut text = content;
+        let keep = match directive {
+            Some((kw @ "if", expr)) | Some((kw @ "ifdef", expr)) | Some((kw @ "ifndef", expr)) => {
+                let cond = if !active {
+                    false
+                } else if kw == "if" {
+                    match eval(expr, &defines) {
+                        Some(cond) => cond,
+                        None => return Err(err(format!("can't evaluate `#if {}`", expr))),
+                    }
+                } else {
+                    defines.contains_key(expr) == (kw == "ifdef")
+                };
+                conds.push(Cond {
+                    offset: offset,
+                    outer: active,
+                    active: cond,
+                    taken: cond,
+                    seen_else: false,
+                });
+                false
+            }
+
+            Some(("elif", expr)) => {
+                let cond = match conds.last_mut() {
+                    Some(cond) if !cond.seen_else => cond,
+                    Some(_) => return Err(err(String::from("`#elif` after `#else`"))),
+                    None => return Err(err(String::from("`#elif` without `#if`"))),
+                };
+                cond.active = if cond.outer && !cond.taken {
+                    match eval(expr, &defines) {
+                        Some(v) => v,
+                        None => return Err(err(format!("can't evaluate `#elif {}`", expr))),
+                    }
+                } else {
+                    false
+                };
+                cond.taken |= cond.active;
+                false
+            }
+
+            Some(("else", _)) => {
+                match conds.last_mut() {
+                    Some(cond) if !cond.seen_else => {
+                        cond.active = cond.outer && !cond.taken;
+                        cond.seen_else = true;
+                    }
+                    Some(_) => return Err(err(String::from("duplicate `#else`"))),
+                    None => return Err(err(String::from("`#else` without `#if`"))),
+                }
+                false
+            }
+
+            Some(("endif", _)) => {
+                if conds.pop().is_none() {
+                    return Err(err(String::from("`#endif` without `#if`")));
+                }
+                false
+            }
+
+            Some(("define", rest)) if active => {
+                // `#if` can use the value, where it's one it can evaluate
+                let name = rest.split_whitespace().next().unwrap_or("");
+                let val = define(&stripped).and_then(|(_, val)| value(val, &defines));
+                defines.insert(name.to_string(), val);
+                text = &stripped;
+                true
+            }
+
+            Some(("undef", name)) if active => {
+                defines.remove(name);
+                false
+            }
+
+            _ => active,
+        };
+
+        if keep {
+            out.push_str(text);
+        }
+        out.push_str(ending);
+        offset += line.len();
+    }
+
+    match conds.last() {
+        Some(cond) => {
+            let len = source[cond.offset..].find('\n').unwrap_or(source.len() - cond.offset);
+            Err(Diagnostic::error(&Location::new(source, cond.offset, len), "`#if` without `#endif`"))
+        }
+        None => Ok(out),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pp(source: &str, defines: &[(&str, i64)]) -> Result<String, Diagnostic> {
+        preprocess(source, &defines.iter().map(|&(k, v)| (k.to_string(), v)).collect())
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(int_literal("255"), Some(255));
+        assert_eq!(int_literal("-0x10"), Some(-16));
+        assert_eq!(int_literal("010"), Some(8));
+        assert_eq!(int_literal("0"), Some(0));
+        assert_eq!(int_literal("64UL"), Some(64));
+        assert_eq!(int_literal("FOO"), None);
+        assert_eq!(int_literal("0x"), None);
+        assert_eq!(define("#define MAXNAMLEN 255"), Some(("MAXNAMLEN", "255")));
+        assert_eq!(define("  #  define  X  (-1)"), Some(("X", "(-1)")));
+        assert_eq!(define("#define X Y"), Some(("X", "Y")));
+        assert_eq!(define("#define X"), None);
+        assert_eq!(define("#define F(x) (x + 1)"), None);
+    }
+
+    #[test]
+    fn conditionals() {
+        let src = "#ifdef RPC_HDR\na\n#elif defined(RPC_XDR) && VERSION >= 3\nb\n#else\nc\n#endif\nd\n";
+
+        assert_eq!(pp(src, &[]).unwrap(), "\n\n\n\n\nc\n\nd\n");
+        assert_eq!(pp(src, &[("RPC_HDR", 1)]).unwrap(), "\na\n\n\n\n\n\nd\n");
+        assert_eq!(pp(src, &[("RPC_XDR", 1), ("VERSION", 3)]).unwrap(), "\n\n\nb\n\n\n\nd\n");
+        assert_eq!(pp(src, &[("RPC_XDR", 1), ("VERSION", 2)]).unwrap(), "\n\n\n\n\nc\n\nd\n");
+    }
+
+    #[test]
+    fn nested() {
+        let src = "#define A 1\n#if A\n#ifndef B\nx\n#else\ny\n#endif\n#else\n#ifdef B\nz\n#endif\n#endif";
+
+        assert_eq!(pp(src, &[]).unwrap(), "#define A 1\n\n\nx\n\n\n\n\n\n\n\n");
+        assert_eq!(pp(src, &[("B", 1)]).unwrap(), "#define A 1\n\n\n\n\ny\n\n\n\n\n\n");
+        assert_eq!(pp("#undef A\n#if A\nx\n#endif\n", &[("A", 1)]).unwrap(), "\n\n\n\n");
+    }
+
+    #[test]
+    fn define_expressions() {
+        let src = "#define A 16\n#define B (A * 2)\n#if B == 32\nx\n#endif\n";
+        assert_eq!(pp(src, &[]).unwrap(), "#define A 16\n#define B (A * 2)\n\nx\n\n");
+    }
+
+    #[test]
+    fn operators() {
+        let defs = [("A", 6), ("B", 3)];
+        let test = |expr: &str| pp(&format!("#if {}\nx\n#endif", expr), &defs).unwrap() == "\nx\n";
+
+        assert!(test("(1 << A) == 64"));
+        assert!(test("(A >> 1) == B"));
+        assert!(test("~A == -7"));
+        assert!(test("(A ^ B) == 5"));
+        assert!(test("A & 2 | B ^ 1 == 3"));
+        assert!(test("1 << 2 + 1 == 8"));
+        assert!(!test("1 << 2 < 3"));
+        assert!(pp("#if 1 << 64\n#endif", &[]).is_err());
+        assert!(pp("#if 1 << 63\n#endif", &[]).is_err());
+        assert!(pp("#if 1 >> -1\n#endif", &[]).is_err());
+    }
+
+    #[test]
+    fn comments() {
+        let src = "#ifdef X /* comment */\na\n#elif Y // comment\nb\n#endif /* end */\n#define Z 3 /* three */\n#if Z /* */ == 3\nc\n#endif";
+
+        assert_eq!(pp(src, &[("X", 1)]).unwrap(), "\na\n\n\n\n#define Z 3            \n\nc\n");
+        assert_eq!(pp(src, &[("Y", 1)]).unwrap(), "\n\n\nb\n\n#define Z 3            \n\nc\n");
+        let strip = |line: &str| strip_comments(line, &mut false);
+        assert_eq!(strip("a /* b */ c // d"), "a         c     ");
+        assert_eq!(strip("a /*/ b"), "a      ");
+
+        let mut in_comment = false;
+        assert_eq!(strip_comments("a /* b", &mut in_comment), "a     ");
+        assert!(in_comment);
+        assert_eq!(strip_comments("c", &mut in_comment), " ");
+        assert!(in_comment);
+        assert_eq!(strip_comments("d */ e /* f */ g", &mut in_comment), "     e         g");
+        assert!(!in_comment);
+    }
+
+    #[test]
+    fn multiline_comments() {
+        // Directives in comments are ignored, as cpp does
+        let src = "/*\n#ifdef FOO\n*/ const A = 1; /*\n#endif\n*/\n";
+        assert_eq!(pp(src, &[]).unwrap(), src);
+
+        let src = "#if 0 /* start\n#else\n*/\na\n#endif\n";
+        assert_eq!(pp(src, &[]).unwrap(), "\n\n\n\n\n");
+    }
+
+    #[test]
+    fn errors() {
+        let err = pp("a\n#ifdef X\nb\n", &[]).unwrap_err();
+        assert_eq!(err.message, "`#if` without `#endif`");
+        assert_eq!(err.location.line, 2);
+
+        assert_eq!(pp("#endif\n", &[]).unwrap_err().message, "`#endif` without `#if`");
+        assert_eq!(pp("#else\n", &[]).unwrap_err().message, "`#else` without `#if`");
+        let err = pp("#if 1\n#else\n#else\n#endif", &[]).unwrap_err();
+        assert_eq!(err.message, "duplicate `#else`");
+        assert_eq!(err.location.line, 3);
+        assert_eq!(pp("#if 1\n#else\n#elif 1\n#endif", &[]).unwrap_err().message, "`#elif` after `#else`");
+        assert_eq!(pp("#if 1 +\n#endif", &[]).unwrap_err().message, "can't evaluate `#if 1 +`");
+    }
+}
@@ -1,5 +1,5 @@
 use super::specification;
-use super::super::generate;
+use super::super::{generate, generate_with_config, Config, Derives, ExternType, Passthrough};
 use std::io::Cursor;
 
 #[test]
@@ -110,8 +110,11 @@ fn inline_struct() {
     println!("spec {:?}", s);
     assert!(s.is_ok());
 
-    let g = generate("", Cursor::new(spec.as_bytes()), Vec::new());
-    assert!(g.is_err());
+    let mut out = Vec::new();
+    generate("", Cursor::new(spec.as_bytes()), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    assert!(out.contains("pub thing : thing_thing"));
 }
 
 #[test]
@@ -126,8 +129,11 @@ fn inline_union() {
     println!("spec {:?}", s);
     assert!(s.is_ok());
 
-    let g = generate("", Cursor::new(spec.as_bytes()), Vec::new());
-    assert!(g.is_err());
+    let mut out = Vec::new();
+    generate("", Cursor::new(spec.as_bytes()), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("{}", out);
+    assert!(out.contains("pub thing : thing_thing"));
 }
 
 #[test]
@@ -135,6 +141,16 @@ fn case_type() {
     let specs = vec![
         "enum Foo { A, B, C }; union Bar switch (Foo x) { case A: void; case B: void; case C: void; };",
         "union Bar switch (int x) { case 1: void; case 2: void; case 3: void; };",
+        // Integer selectors can have constants and enum members as cases, as rpcgen allows
+        "enum Foo { A, B, C }; union Bar switch (unsigned int x) { case A: void; case B: void; case C: void; };",
+        "enum Foo { A, B, C}; union Bar switch (int x) { case A: void; case B: void; case C: void; };",
+        "const A = 1; const B = 2; typedef int sel; union Bar switch (sel x) { case A: void; case B: void; };",
+        "union Bar switch (bool x) { case TRUE: void; case FALSE: void; };",
+        "union Bar switch (bool x) { case 1: void; case 0: void; };",
+        "union Bar switch (unsigned int x) { case 0xffffffff: void; };",
+        "union Bar switch (unsigned hyper x) { case 0x100000000: void; };",
+        "union Bar switch (hyper x) { case -4294967296: void; };",
+        "union Bar switch (unsigned hyper x) { case 0xffffffffffffffff: void; };",
     ];
 
     for sp in specs {
@@ -151,7 +167,14 @@ fn case_type() {
 fn case_type_mismatch() {
     let specs = vec![
         "enum Foo { A, B, C}; union Bar switch (Foo x) { case 1: void; case 2: void; case 3: void; };",
-        "enum Foo { A, B, C}; union Bar switch (int x) { case A: void; case B: void; case C: void; };",
+        "enum Foo { A, B, C}; union Bar switch (bool x) { case A: void; case B: void; };",
+        "enum Foo { A, B }; enum Baz { C }; union Bar switch (Baz x) { case A: void; };",
+        "const A = -1; union Bar switch (unsigned int x) { case A: void; };",
+        "union Bar switch (bool x) { case 2: void; };",
+        "union Bar switch (int x) { case 0x80000000: void; };",
+        "union Bar switch (unsigned int x) { case 0x100000000: void; };",
+        "union Bar switch (unsigned hyper x) { case -1: void; };",
+        "union Bar switch (hyper x) { case 0x8000000000000000: void; };",
     ];
 
     for sp in specs {
@@ -251,3 +274,432 @@ union foo switch (int x) {
     println!("spec {:?}", s);
     assert!(s.is_ok())
 }
+
+#[test]
+fn doc_comments() {
+    let spec = r#"
+/* A thing */
+struct thing {
+    /* the a */
+    int a;
+    int b;  /* not b's */
+};
+union either switch (int d) {
+    /* just a */
+    case 0: int a;
+    /* nothing */
+    case 1: void;
+    /* anything else */
+    default: void;
+};
+"#;
+
+    let mut out = Vec::new();
+    let g = generate("", Cursor::new(spec.as_bytes()), &mut out);
+    assert!(g.is_ok());
+
+    let out = String::from_utf8(out).unwrap();
+    println!("out {}", out);
+    assert!(out.contains("/// A thing\n"));
+    assert!(out.contains("/// the a\n"));
+    assert!(!out.contains("not b's"));
+    assert!(out.contains("/// nothing\n"));
+    assert!(out.contains("/// anything else\n"));
+}
+
+#[test]
+fn derives() {
+    let spec = r#"
+        enum e { A = 1, B = 2 };
+        struct s { int a; float f; e x; };
+        union u switch (e d) { case A: int a; case B: void; };
+"#;
+    let config = Config::new()
+        .derive("Hash")
+        .derive("Default")
+        .derive("Thing")
+        .type_derive("u", "Other")
+        .type_attribute("s", "#[repr(C)]");
+
+    let mut out = Vec::new();
+    let g = generate_with_config("", Cursor::new(spec.as_bytes()), &mut out, &config);
+    assert!(g.is_ok());
+
+    let out = String::from_utf8(out).unwrap();
+    println!("out {}", out);
+    assert!(out.contains(
+        "#[derive( Copy , Clone , Debug , Eq , PartialEq , Hash , Default )] # [ derive ( Thing ) ] pub enum e { # [ default ] A"
+    ));
+    assert!(out.contains(
+        "#[derive( Copy , Clone , Debug , PartialEq , Default )] # [ derive ( Thing ) ] #[repr(C)] pub struct s"
+    ));
+    assert!(out.contains(
+        "#[derive( Copy , Clone , Debug , Eq , PartialEq , Hash )] # [ derive ( Thing , Other ) ] pub enum u"
+    ));
+}
+
+#[test]
+fn large_array_default() {
+    let spec = r#"
+        struct big { int a; opaque hash[64]; int table[64]; };
+        typedef int table[100];
+"#;
+    let config = Config::new().derive("Default");
+
+    let mut out = Vec::new();
+    let g = generate_with_config("", Cursor::new(spec.as_bytes()), &mut out, &config);
+    assert!(g.is_ok());
+
+    let out = String::from_utf8(out).unwrap();
+    println!("out {}", out);
+    assert!(out.contains("#[derive( Copy , Clone , Debug , Eq , PartialEq )] pub struct big"));
+    assert!(out.contains(
+        "big { a : Default :: default ( ) , hash : Default :: default ( ) , table : :: std :: array :: from_fn ( | _ | Default :: default ( ) ) , }"
+    ));
+    assert!(out.contains("impl Default for table"));
+}
+
+#[test]
+fn parse_error_location() {
+    let spec = "const A = 1;\n\nstruct foo {\n\tint a;\n\tint b\n};\n";
+
+    let err = specification(spec).unwrap_err();
+    println!("err {}", err);
+    assert_eq!((err.location.line, err.location.column), (6, 1));
+    assert_eq!(err.expected, vec!["`;`", "`<`", "`[`"]);
+    assert_eq!(err.found, "`}`");
+
+    let spec = "typedef int foo;\n\tstruct bool { int a; };\n";
+    let err = specification(spec).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse error: expected identifier, found `bool`\n --> 2:9\n  |\n2 | \tstruct bool { int a; };\n  | \t       ^^^^"
+    );
+
+    let spec = "struct foo {\n  int a;\n";
+    let err = specification(spec).unwrap_err();
+    assert_eq!((err.location.line, err.location.column), (3, 1));
+    assert_eq!(err.expected, vec!["`}`"]);
+    assert_eq!(err.found, "end of file");
+}
+
+#[test]
+fn parse_error_out_of_range() {
+    let spec = "const A = 1;\nconst B = 99999999999999999999;\n";
+    let err = specification(spec).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse error: expected a number from -9223372036854775808 to 18446744073709551615, found `99999999999999999999`\n --> 2:11\n  |\n2 | const B = 99999999999999999999;\n  |           ^^^^^^^^^^^^^^^^^^^^"
+    );
+
+    let spec = "union u switch (hyper h) { case 0x10000000000000000: void; };";
+    let err = specification(spec).unwrap_err();
+    assert_eq!((err.location.line, err.location.column), (1, 33));
+    assert_eq!(err.found, "`0x10000000000000000`");
+}
+
+#[test]
+fn parse_error_file() {
+    let spec = "bogus thing;";
+
+    let g = generate("foo.x", Cursor::new(spec.as_bytes()), Vec::new());
+    let err = g.unwrap_err().to_string();
+    println!("err {}", err);
+    assert!(err.starts_with(
+        "parse error: expected one of `const`, `enum`, `program`, `struct`, `typedef`, `union`, found `bogus`\n --> foo.x:1:1\n"
+    ));
+}
+
+fn validation_errors(spec: &str) -> String {
+    let g = generate("test.x", Cursor::new(spec.as_bytes()), Vec::new());
+    let err = g.unwrap_err().to_string();
+    println!("err {}", err);
+    err
+}
+
+#[test]
+fn undefined_type() {
+    let spec = "const bar = 1;\nstruct foo {\n    /* bar */ int a;\n    bar x;\n}; struct baz { bar y; };";
+
+    // Allowed by default, with a warning at the use of the type
+    let xdr = super::Symtab::new(&specification(spec).unwrap(), &Config::new());
+    let diags: Vec<_> = xdr.validate().into_iter().map(|d| d.to_string()).collect();
+    assert_eq!(diags.len(), 2);
+    assert!(diags[0].starts_with("warning: undefined type `bar` in `foo`\n --> 4:5\n"));
+    assert!(diags[1].starts_with("warning: undefined type `bar` in `baz`\n --> 5:17\n"));
+    assert!(generate("test.x", Cursor::new(spec.as_bytes()), Vec::new()).is_ok());
+
+    // Warnings go to the caller, with the file name
+    let warnings = generate_with_config("test.x", Cursor::new(spec.as_bytes()), Vec::new(), &Config::new()).unwrap();
+    let warnings: Vec<_> = warnings.iter().map(|w| w.to_string()).collect();
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0].starts_with("warning: undefined type `bar` in `foo`\n --> test.x:4:5\n"));
+
+    let config = Config::new().allow_undefined_types(false);
+    let g = generate_with_config("test.x", Cursor::new(spec.as_bytes()), Vec::new(), &config);
+    let err = g.unwrap_err().to_string();
+    println!("err {}", err);
+    assert!(err.starts_with("error: undefined type `bar` in `foo`\n --> test.x:4:5\n  |\n4 |     bar x;\n  |     ^^^\n"));
+}
+
+#[test]
+fn undefined_constant() {
+    let err = validation_errors("typedef int foo[SIZE];");
+    assert!(err.starts_with("error: undefined constant `SIZE` in `foo`\n --> test.x:1:17\n"));
+}
+
+#[test]
+fn validation_reports_all() {
+    let spec = r#"
+const A = 7;
+struct foo { int x; };
+typedef int foo;
+enum big { TOO_BIG = 0x100000000 };
+enum sel { X = 1, Y = 2 };
+union bar switch (sel x) { case X: void; case Y: void; case A: void; case X: int y; };
+union baz switch (bool x) { case TRUE: int y; case 3: void; };
+"#;
+
+    let err = validation_errors(spec);
+    let messages: Vec<_> = err.lines().filter(|l| l.starts_with("error: ")).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "error: `foo` is already defined at test.x:3:1",
+            "error: value 4294967296 of `TOO_BIG` in enum `big` is out of range for i32",
+            "error: case `A` in union `bar` doesn't match the selector type",
+            "error: duplicate case `X` in union `bar` (same value as `X`)",
+            "error: case `3` in union `baz` doesn't match the selector type",
+        ]
+    );
+    assert!(err.contains(" --> test.x:4:1\n  |\n4 | typedef int foo;\n  | ^^^^^^^"));
+
+    // Bad cases point at the case value, even when it repeats an earlier one
+    assert!(err.contains("union `bar` doesn't match the selector type\n --> test.x:7:61\n"));
+    assert!(err.contains("(same value as `X`)\n --> test.x:7:75\n"));
+    assert!(err.contains("union `baz` doesn't match the selector type\n --> test.x:8:52\n"));
+}
+
+#[test]
+fn streamed_fields() {
+    let spec = "typedef opaque blob<>;\nstruct inner { blob b; };\nstruct args {\n    opaque data<>;\n    inner in;\n};";
+    let config = Config::new().stream_field("blob").stream_field("args.data");
+    let g = generate_with_config("test.x", Cursor::new(spec.as_bytes()), Vec::new(), &config);
+    let err = g.unwrap_err().to_string();
+    println!("err {}", err);
+    assert!(err.starts_with("error: `args` has more than one streamed field: `data`, `in`\n --> test.x:5:11\n"));
+
+    // One streamed field, even by way of another struct, is fine
+    let config = Config::new().stream_field("blob");
+    assert!(generate_with_config("test.x", Cursor::new(spec.as_bytes()), Vec::new(), &config).is_ok());
+}
+
+#[test]
+fn duplicate_enum_member() {
+    let err = validation_errors("const A = 1; enum foo { A = 2, B = 3 };");
+    assert!(err.starts_with("error: `A` is already defined at test.x:1:1\n"));
+}
+
+#[test]
+fn unused_warnings() {
+    let spec = "const A = 1; const B = 2; typedef int foo[B]; struct bar { foo x; };";
+    let config = Config::new().warn_unused(true);
+
+    let xdr = super::Symtab::new(&specification(spec).unwrap(), &config);
+    let diags: Vec<_> = xdr.validate().into_iter().map(|d| (d.is_error(), d.message)).collect();
+    assert_eq!(
+        diags,
+        vec![
+            (false, "constant `A` is never used".to_string()),
+            (false, "type `bar` is never used".to_string()),
+        ]
+    );
+
+    let xdr = super::Symtab::new(&specification(spec).unwrap(), &Config::new());
+    assert!(xdr.validate().is_empty());
+}
+
+#[test]
+fn include_directive() {
+    let spec = "#include \"common.x\"\n  #include <other.x>\n#define X 1\ntypedef int foo;";
+
+    let s = specification(spec).unwrap();
+    let defns: Vec<_> = s.iter().map(|&(ref loc, ref defn)| (loc.line, loc.column, defn.clone())).collect();
+    assert_eq!(
+        defns,
+        vec![
+            (1, 1, super::Defn::Include("common.x".to_string(), false)),
+            (2, 3, super::Defn::Include("other.x".to_string(), true)),
+            (3, 1, super::Defn::constant("X", 1)),
+            (4, 1, super::Defn::typesyn("foo", super::Type::Int)),
+        ]
+    );
+}
+
+#[test]
+fn include_missing() {
+    let err = validation_errors("const A = 1;\n#include \"missing.x\"\n");
+    assert!(err.starts_with(
+        "error: can't find included file `missing.x`\n --> test.x:2:1\n  |\n2 | #include \"missing.x\"\n  | ^^^^^^^^^^^^^^^^^^^^"
+    ));
+}
+
+#[test]
+fn include_missing_system() {
+    let spec = "#include <rpc/types.h>\nstruct foo { int a; };";
+
+    let mut out = Vec::new();
+    let warnings = generate_with_config("test.x", Cursor::new(spec.as_bytes()), &mut out, &Config::new()).unwrap();
+    assert!(String::from_utf8(out).unwrap().contains("pub struct foo"));
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].message, "can't find included file `rpc/types.h`, so skipping it");
+    assert_eq!(warnings[0].location.line, 1);
+}
+
+#[test]
+fn extern_type() {
+    let spec = "typedef opaque fh<64>; struct file { fh handle; int mode; }; struct copyable { fh *h; };";
+    let config = Config::new()
+        .extern_type("fh", ExternType::new("::common::fh").derives(Derives::CLONE | Derives::DEBUG).boxed(false));
+
+    let mut out = Vec::new();
+    generate_with_config("", Cursor::new(spec.as_bytes()), &mut out, &config).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("out {}", out);
+
+    assert!(!out.contains("pub type fh"));
+    assert!(out.contains("#[derive( Clone , Debug )] pub struct file { pub handle : ::common::fh , pub mode : i32 , }"));
+    assert!(out.contains("pub struct copyable { pub h : Option < ::common::fh > , }"));
+}
+
+#[test]
+fn passthrough() {
+    let spec = r#"%#include <rpc/rpc.h>
+const A = 1;
+%rust pub const B: i64 = A + 1;
+%rust
+%rust pub const C: i64 = B + 1;
+struct foo { int x; };
+%// trailer
+"#;
+
+    let generated = |passthrough| {
+        let config = Config::new().passthrough(passthrough);
+        let mut out = Vec::new();
+        generate_with_config("", Cursor::new(spec.as_bytes()), &mut out, &config).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        println!("out {}", out);
+        out
+    };
+
+    let off = generated(Passthrough::Off);
+    assert!(!off.contains("rpc.h") && !off.contains("const B"));
+
+    let rust = generated(Passthrough::Rust);
+    assert!(!rust.contains("rpc.h") && !rust.contains("trailer"));
+    let a = rust.find("pub const A").unwrap();
+    let b = rust.find("pub const B: i64 = A + 1;\n\npub const C: i64 = B + 1;\n").unwrap();
+    let foo = rust.find("pub struct foo").unwrap();
+    assert!(a < b && b < foo);
+
+    let all = generated(Passthrough::All);
+    assert!(all.find("#include <rpc/rpc.h>").unwrap() < all.find("pub const A").unwrap());
+    assert!(all.contains("pub const B: i64 = A + 1;"));
+    assert!(all.find("// trailer").unwrap() > all.find("pub struct foo").unwrap());
+}
+
+#[test]
+fn preprocessor() {
+    let spec = "#define SIZE 16\n#ifdef BIG\ntypedef int arr[SIZE];\n#else\ntypedef int arr[4];\n#endif\n";
+
+    let generated = |config: &Config| {
+        let mut out = Vec::new();
+        generate_with_config("", Cursor::new(spec.as_bytes()), &mut out, config).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    let small = generated(&Config::new());
+    assert!(small.contains("pub const SIZE : i64 = 16i64 ;"));
+    assert!(small.contains("pub struct arr ( pub [ i32 ; 4i64 as usize ] ) ;"));
+
+    let big = generated(&Config::new().define("BIG", 1));
+    assert!(big.contains("pub struct arr ( pub [ i32 ; SIZE as usize ] ) ;"));
+
+    let err = validation_errors("struct a { int x; };\n#ifdef A\n");
+    assert!(err.starts_with("error: `#if` without `#endif`\n --> test.x:2:1\n"));
+
+    // Defines can be expressions, including of constants
+    let spec = "const A = 8;\n#define B (A * 2)\n#define C 0x10UL\ntypedef int arr[B + C];\n";
+    let mut out = Vec::new();
+    generate("", Cursor::new(spec.as_bytes()), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("pub const B : i64 = 16i64 ;"));
+    assert!(out.contains("pub const C : i64 = 16i64 ;"));
+    assert!(out.contains("pub struct arr ( pub [ i32 ; 32i64 as usize ] ) ;"));
+
+    let err = validation_errors("#define B (A * 2)\ntypedef int arr[B];\n");
+    assert!(err.starts_with("error: undefined constant `A` in `B`\n --> test.x:1:12\n"));
+
+    // Directives in a comment are part of the comment
+    let spec = "/*\n#ifdef FOO\n*/ const A = 1; /*\n#endif\n*/\n";
+    let mut out = Vec::new();
+    generate("", Cursor::new(spec.as_bytes()), &mut out).unwrap();
+    assert!(String::from_utf8(out).unwrap().contains("pub const A : i64 = 1i64 ;"));
+}
+
+#[test]
+fn constant_expressions() {
+    let spec = r#"
+const B = A * 2;
+const A = 1 << 4;
+const MASK = (A | B) & ~0;
+"#;
+    // `~` isn't an operator
+    assert!(specification(spec).is_err());
+
+    let spec = r#"
+const B = A * 2;
+const A = 1 << 4;
+const MASK = (A | B) - 1;
+enum e { X = MASK + 1, Y, Z = -(A / 4) };
+typedef opaque buf[MAXDATA + 4];
+typedef int list<B - A>;
+const MAXDATA = 8192;
+const BIG = 0xffffffffffffffff;
+const LOW = -0x7fffffffffffffff - 1;
+union u switch (int x) { case A + 1: void; case -(A / 4): void; default: int y; };
+"#;
+
+    let mut out = Vec::new();
+    generate("", Cursor::new(spec.as_bytes()), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    println!("out {}", out);
+
+    assert!(out.contains("pub const B : i64 = 32i64 ;"));
+    assert!(out.contains("pub const MASK : i64 = 47i64 ;"));
+    assert!(out.contains("pub const BIG : u64 = 18446744073709551615u64 ;"));
+    assert!(out.contains("pub const LOW : i64 = -9223372036854775808i64 ;"));
+    assert!(out.contains("X = 48isize , Y = 49isize , Z = -4isize ,"));
+    assert!(out.contains("pub struct buf ( pub xdr_codec :: OpaqueArray < { 8196i64 as usize } > ) ;"));
+    assert!(out.contains("Some ( 16i64 as usize )"));
+    assert!(out.contains("Const17"));
+    assert!(out.contains("Const_4"));
+}
+
+#[test]
+fn constant_expression_errors() {
+    let err = validation_errors(
+        "const A = 0xffffffffffffffff + 1;\nconst B = 1 / (A - A);\nconst C = 1 << 64;\nconst D = -(0xffffffffffffffff);\nconst E = F;",
+    );
+    let messages: Vec<_> = err.lines().filter(|l| l.starts_with("error: ")).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "error: `18446744073709551615 + 1` overflows in `A`",
+            "error: can't evaluate constant `A` in `B`",
+            "error: `1 << 64` overflows in `C`",
+            "error: `-18446744073709551615` overflows in `D`",
+            "error: undefined constant `F` in `E`",
+        ]
+    );
+}
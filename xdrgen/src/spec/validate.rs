@@ -0,0 +1,238 @@
+// Semantic checks on a specification, run before generating any code.
+use std::collections::{BTreeSet, HashMap};
+
+use super::{case_compatible, Decl, Defn, EnumDefn, Symtab, Type, UnionCase, Value};
+use super::error::{Diagnostic, Location};
+
+struct Validator<'a> {
+    symtab: &'a Symtab,
+    diags: Vec<Diagnostic>,
+    // Names of types and constants which are referenced
+    used: BTreeSet<&'a str>,
+}
+
+impl<'a> Validator<'a> {
+    fn error<S: Into<String>>(&mut self, loc: &Location, msg: S) {
+        self.diags.push(Diagnostic::error(loc, msg))
+    }
+
+    fn value(&mut self, loc: &Location, ctx: &str, val: &'a Value) {
+        let mut unknown = Vec::new();
+        self.operands(val, &mut unknown);
+
+        for id in &unknown {
+            if self.declared(id) {
+                self.error(loc, format!("can't evaluate constant `{}` in `{}`", id, ctx));
+            } else {
+                self.error(&loc.find_token(id), format!("undefined constant `{}` in `{}`", id, ctx));
+            }
+        }
+
+        // Expressions can still overflow if everything in them has a value
+        if unknown.is_empty() {
+            if let Err(e) = self.symtab.eval(val) {
+                self.error(loc, format!("{} in `{}`", e, ctx));
+            }
+        }
+    }
+
+    // Mark the constants in `val` as used, and collect those without values
+    fn operands(&mut self, val: &'a Value, unknown: &mut Vec<&'a str>) {
+        match val {
+            &Value::Ident(ref id) => {
+                self.used.insert(id);
+                if self.symtab.value(val).is_none() && !unknown.contains(&id.as_str()) {
+                    unknown.push(id);
+                }
+            }
+            &Value::Const(_) => (),
+            &Value::Neg(ref v) => self.operands(v, unknown),
+            &Value::BinOp(_, ref a, ref b) => {
+                self.operands(a, unknown);
+                self.operands(b, unknown);
+            }
+        }
+    }
+
+    // True if `name` is defined as a constant, even if it has no value
+    fn declared(&self, name: &str) -> bool {
+        self.symtab.defns.iter().any(|&(_, ref defn)| match defn {
+            &Defn::Const(ref n, ..) => n == name,
+            &Defn::Typespec(_, Type::Enum(ref defs), _) => defs.iter().any(|d| d.0 == name),
+            _ => false,
+        })
+    }
+
+    fn decl(&mut self, loc: &Location, ctx: &str, decl: &'a Decl) {
+        if let &Decl::Named(_, ref ty, _) = decl {
+            self.ty(loc, ctx, ty)
+        }
+    }
+
+    fn ty(&mut self, loc: &Location, ctx: &str, ty: &'a Type) {
+        use super::Type::*;
+
+        match ty {
+            &Ident(ref name, None) => {
+                self.used.insert(name);
+                if self.symtab.typespec(name).is_none() && self.symtab.extern_type(name).is_none() {
+                    // They may be defined alongside the generated code
+                    let loc = loc.find_token(name);
+                    let msg = format!("undefined type `{}` in `{}`", name, ctx);
+                    if self.symtab.config.allow_undefined_types {
+                        self.diags.push(Diagnostic::warning(&loc, msg));
+                    } else {
+                        self.error(&loc, msg);
+                    }
+                }
+            }
+
+            &Array(ref ty, ref sz) | &Flex(ref ty, Some(ref sz)) => {
+                self.value(loc, ctx, sz);
+                self.ty(loc, ctx, ty);
+            }
+            &Flex(ref ty, None) | &Option(ref ty) => self.ty(loc, ctx, ty),
+
+            &Struct(ref decls) => {
+                for decl in decls {
+                    self.decl(loc, ctx, decl)
+                }
+            }
+
+            &Enum(ref defs) => self.enumeration(loc, ctx, defs),
+
+            &Union(ref sel, ref cases, ref defl) => {
+                self.decl(loc, ctx, sel);
+                self.union_cases(loc, ctx, sel, cases);
+                if let &Some((ref defl, _)) = defl {
+                    self.decl(loc, ctx, defl)
+                }
+            }
+
+            _ => (),
+        }
+    }
+
+    // `unpack_into` writes streamed data to a single sink, so a struct can only stream one field
+    fn streamed_fields(&mut self, loc: &Location, name: &String) {
+        let decls = match self.symtab.typespec(name) {
+            Some(&Type::Struct(ref decls)) => decls,
+            _ => return,
+        };
+        let streamed: Vec<&str> = decls
+            .iter()
+            .filter_map(|decl| match decl {
+                &Decl::Named(ref field, ref ty, _) if self.symtab.is_streamed(ty) => Some(field.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        if streamed.len() > 1 {
+            let msg = format!("`{}` has more than one streamed field: `{}`", name, streamed.join("`, `"));
+            self.error(&loc.find_token(streamed[1]), msg);
+        }
+    }
+
+    fn enumeration(&mut self, loc: &Location, ctx: &str, defs: &'a [EnumDefn]) {
+        for &EnumDefn(ref name, ref val, _) in defs {
+            if let &Some(ref val) = val {
+                self.value(loc, ctx, val);
+            }
+
+            match self.symtab.getconst(name) {
+                Some((v, _)) if v < i32::min_value() as i128 || v > i32::max_value() as i128 => {
+                    self.error(loc, format!("value {} of `{}` in enum `{}` is out of range for i32", v, name, ctx))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn union_cases(&mut self, loc: &Location, ctx: &str, sel: &Decl, cases: &'a [UnionCase]) {
+        let mut seen = HashMap::new();
+
+        for (idx, &UnionCase(ref val, ref decl, _)) in cases.iter().enumerate() {
+            self.value(loc, ctx, val);
+            let case_loc = loc.find_case(idx);
+            let v = self.symtab.value(val);
+
+            if v.is_some() && !case_compatible(sel, val, self.symtab) {
+                self.error(&case_loc, format!("case `{}` in union `{}` doesn't match the selector type", val, ctx));
+            }
+
+            if let Some(v) = v {
+                if let Some(&prev) = seen.get(&v) {
+                    self.error(&case_loc, format!("duplicate case `{}` in union `{}` (same value as `{}`)", val, ctx, prev));
+                } else {
+                    seen.insert(v, val);
+                }
+            }
+
+            self.decl(loc, ctx, decl);
+        }
+    }
+
+    fn unused(&mut self) {
+        let symtab = self.symtab;
+        for &(ref loc, ref defn) in &symtab.defns {
+            let (what, name) = match defn {
+                &Defn::Typespec(ref name, ..) | &Defn::Typesyn(ref name, ..) => ("type", name),
+                &Defn::Const(ref name, ..) => ("constant", name),
+                &Defn::Include(..) | &Defn::Passthrough(_) => continue,
+            };
+            if !self.used.contains(name.as_str()) {
+                self.diags.push(Diagnostic::warning(loc, format!("{} `{}` is never used", what, name)));
+            }
+        }
+    }
+}
+
+impl Symtab {
+    /// Check the specification for problems which would make the generated code wrong or fail
+    /// to compile, and optionally for unused definitions.
+    ///
+    /// Returns everything found at once, in source order, with unused definitions last.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut v = Validator {
+            symtab: self,
+            diags: Vec::new(),
+            used: BTreeSet::new(),
+        };
+        let mut defined: HashMap<&str, &Location> = HashMap::new();
+
+        for &(ref loc, ref defn) in &self.defns {
+            let (name, ty) = match defn {
+                &Defn::Typespec(ref name, ref ty, _) | &Defn::Typesyn(ref name, ref ty, _) => (name, Some(ty)),
+                &Defn::Const(ref name, ..) => (name, None),
+                &Defn::Include(..) | &Defn::Passthrough(_) => continue,
+            };
+
+            // Enum members are constants too
+            let mut names = vec![name];
+            if let Some(&Type::Enum(ref defs)) = ty {
+                names.extend(defs.iter().map(|d| &d.0));
+            }
+            for name in names {
+                if let Some(prev) = defined.insert(name, loc) {
+                    v.error(loc, format!("`{}` is already defined at {}", name, prev));
+                }
+            }
+
+            if let &Defn::Const(_, ref val, _) = defn {
+                v.value(loc, name, val);
+            }
+            if let Some(ty) = ty {
+                v.ty(loc, name, ty);
+            }
+            if let &Defn::Typespec(_, Type::Struct(_), _) = defn {
+                v.streamed_fields(loc, name);
+            }
+        }
+
+        if self.config.warn_unused {
+            v.unused();
+        }
+
+        v.diags
+    }
+}
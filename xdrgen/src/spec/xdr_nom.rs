@@ -2,10 +2,14 @@
 use nom::{Err, ErrorKind, IResult, Needed, is_digit, is_space, not_line_ending};
 use nom::IResult::*;
 
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::str;
 
-use super::{Decl, Defn, EnumDefn, Type, UnionCase, Value};
+use super::{BinOp, Decl, Defn, Doc, EnumDefn, Type, UnionCase, Value};
 use super::Derives;
+use super::error::{token_len, Location, ParseError};
+use super::preprocess::{define, int_literal};
 
 #[inline]
 fn ignore<T>(_: T) -> () {
@@ -25,42 +29,197 @@ fn eof(input: &[u8]) -> IResult<&[u8], ()> {
     }
 }
 
-pub fn specification(input: &str) -> Result<Vec<Defn>, String> {
+// The furthest point in the input where a parser failed, and what it was expecting to find there.
+//
+// nom's errors only say which combinator gave up, which is usually at the start of the definition
+// containing the real problem, so the basic token parsers record their failures here instead. The
+// position is the length of the remaining input.
+struct Expected {
+    rest: usize,
+    what: BTreeSet<String>,
+    quiet: bool,
+    // Whether `what` is the only thing which could have been there, as for a malformed token
+    only: bool,
+}
+
+thread_local!(static EXPECTED: RefCell<Expected> = const { RefCell::new(Expected {
+    rest: usize::MAX,
+    what: BTreeSet::new(),
+    quiet: false,
+    only: false,
+}) });
+
+fn expected<S: Into<String>>(input: &[u8], what: S) {
+    EXPECTED.with(|exp| {
+        let mut exp = exp.borrow_mut();
+        if exp.quiet || input.len() > exp.rest || (input.len() == exp.rest && exp.only) {
+            return;
+        }
+        if input.len() < exp.rest {
+            exp.rest = input.len();
+            exp.what.clear();
+            exp.only = false;
+        }
+        exp.what.insert(what.into());
+    })
+}
+
+// Like `expected`, for a token which is there but malformed, so nothing else would have done.
+fn malformed<S: Into<String>>(input: &[u8], what: S) {
+    EXPECTED.with(|exp| {
+        let mut exp = exp.borrow_mut();
+        if exp.quiet || input.len() > exp.rest {
+            return;
+        }
+        exp.rest = input.len();
+        exp.what.clear();
+        exp.what.insert(what.into());
+        exp.only = true;
+    })
+}
+
+// Run a parser without recording what it expected, for when failure isn't a syntax error.
+fn quietly<T, F: FnOnce() -> T>(f: F) -> T {
+    let was = EXPECTED.with(|exp| ::std::mem::replace(&mut exp.borrow_mut().quiet, true));
+    let ret = f();
+    EXPECTED.with(|exp| exp.borrow_mut().quiet = was);
+    ret
+}
+
+// Directives which mean something, keyed by their position as the length of the remaining input,
+// since backtracking can parse the same directive more than once. Each is recorded with the length
+// of its line.
+thread_local!(static DIRECTIVES: RefCell<BTreeMap<usize, (usize, Defn)>> = const { RefCell::new(BTreeMap::new()) });
+
+fn record_directive(rest: usize, line: &[u8]) {
+    let text = String::from_utf8_lossy(line);
+    let defn = if let Some(text) = text.strip_prefix('%') {
+        Defn::Passthrough(text.to_string())
+    } else if let Some((path, system)) = include_path(&text) {
+        Defn::Include(path, system)
+    } else if let Some((name, val)) = define(&text).and_then(|(name, val)| Some((name, define_value(val)?))) {
+        Defn::Const(name.to_string(), val, Doc::default())
+    } else {
+        return;
+    };
+    DIRECTIVES.with(|dirs| dirs.borrow_mut().insert(rest, (line.len(), defn)));
+}
+
+// The value of a `#define` as a constant expression, if it is one. C's integer suffixes are allowed
+// on plain literals.
+fn define_value(text: &str) -> Option<Value> {
+    if let Some(val) = int_literal(text.trim_start_matches('(').trim_end_matches(')')) {
+        return Some(Value::Const(i128::from(val)));
+    }
+
+    // A terminator keeps the expression from being incomplete at the end of the input
+    let text = format!("{};", text);
+    match quietly(|| terminated!(text.as_bytes(), value, semi)) {
+        Done(b"", val) => Some(val),
+        _ => None,
+    }
+}
+
+// The file named by `#include "file"` or `#include <file>`, and whether it's the `<>` form
+fn include_path(line: &str) -> Option<(String, bool)> {
+    let arg = line.strip_prefix('#')?.trim_start().strip_prefix("include")?.trim();
+    let (path, system) = match arg.chars().next()? {
+        '"' => (arg[1..].strip_suffix('"')?, false),
+        '<' => (arg[1..].strip_suffix('>')?, true),
+        _ => return None,
+    };
+    if path.is_empty() { None } else { Some((path.to_string(), system)) }
+}
+
+fn parse_error(source: &str, rest: usize) -> ParseError {
+    let (rest, expected) = EXPECTED.with(|exp| {
+        let mut exp = exp.borrow_mut();
+        if exp.rest <= source.len() {
+            (exp.rest, std::mem::take(&mut exp.what).into_iter().collect())
+        } else {
+            (rest, Vec::new())
+        }
+    });
+
+    let offset = source.len() - rest;
+    let tail = &source[offset..];
+    let found = match &tail[..token_len(tail)] {
+        "" => String::from("end of file"),
+        tok => format!("`{}`", tok),
+    };
+
+    ParseError {
+        location: Location::at_token(source, offset),
+        expected: expected,
+        found: found,
+    }
+}
+
+pub fn specification(input: &str) -> Result<Vec<(Location, Defn)>, ParseError> {
+    EXPECTED.with(|exp| {
+        let mut exp = exp.borrow_mut();
+        exp.rest = usize::MAX;
+        exp.what.clear();
+        exp.only = false;
+    });
+    DIRECTIVES.with(|dirs| dirs.borrow_mut().clear());
+
     match spec(input.as_bytes()) {
-        Done(_, spec) => Ok(spec),
-        Error(Err::Position(kind, input)) => {
-            Err(format!(
-                "{:?}: {}",
-                kind,
-                String::from(str::from_utf8(input).unwrap())
-            ))
+        Done(_, spec) => {
+            let directives = DIRECTIVES.with(|dirs| std::mem::take(&mut *dirs.borrow_mut()));
+
+            let mut defns: Vec<_> = spec.into_iter()
+                .map(|(rest, defn)| (Location::at_token(input, input.len() - rest), rest, defn))
+                .chain(directives.into_iter().map(|(rest, (len, defn))| {
+                    (Location::new(input, input.len() - rest, len), rest, defn)
+                }))
+                .collect();
+            defns.sort_by_key(|d| std::cmp::Reverse(d.1));
+
+            // Each definition runs up to the start of the next one
+            let starts: Vec<_> = defns.iter().map(|d| input.len() - d.1).collect();
+            Ok(defns.into_iter()
+                .enumerate()
+                .map(|(idx, (loc, rest, defn))| {
+                    let start = input.len() - rest;
+                    let end = starts[idx..].iter().cloned().find(|&s| s > start).unwrap_or(input.len());
+                    (loc.with_span(input, start, end), defn)
+                })
+                .collect())
         }
-        Error(err) => Err(format!("Error: {:?}", err)),
-        Incomplete(need) => Err(format!("Incomplete {:?}", need)),
+        Error(Err::Position(_, rest)) => Err(parse_error(input, rest.len())),
+        Error(_) | Incomplete(_) => Err(parse_error(input, 0)),
     }
 }
 
-named!(spec< Vec<Defn> >,
+// Definitions, with the position of each as the length of the input remaining at its start
+named!(spec< Vec<(usize, Defn)> >,
     do_parse!(
         opt!(directive) >>
-        defns: many0!(definition) >>
+        first: opt!(apply!(definition, true)) >>
+        defns: many0!(apply!(definition, false)) >>
         spaces >> eof >>
-        (defns))
+        (first.into_iter().chain(defns).flat_map(|d| d).collect()))
 );
 
+#[cfg(test)]
+fn defns(spec: Vec<(usize, Defn)>) -> Vec<Defn> {
+    spec.into_iter().map(|(_, defn)| defn).collect()
+}
+
 #[cfg(test)]
 mod spec_tests {
     use super::*;
 
     #[test]
     fn it_can_parse_the_spec_and_ignore_comments_and_cdirectives() {
-        assert_eq!(spec(&b"#include <foo>"[..]),
+        assert_eq!(spec(&b"#include <foo>"[..]).map(defns),
                    Done(&b""[..], vec!()));
 
-        assert_eq!(spec(&b"// hello\n#include <foo>"[..]),
+        assert_eq!(spec(&b"// hello\n#include <foo>"[..]).map(defns),
                    Done(&b""[..], vec!()));
 
-        assert_eq!(spec(&b"#include <foo>\ntypedef int foo;"[..]),
+        assert_eq!(spec(&b"#include <foo>\ntypedef int foo;"[..]).map(defns),
                    Done(&b""[..], vec!(Defn::typesyn("foo", Type::Int))));
 
         assert_eq!(spec(&br#"
@@ -75,7 +234,7 @@ mod spec_tests {
     };
     #include "other"
     enum bop { a = 2, b = 1 };
-    "#[..]),
+    "#[..]).map(defns),
                    Done(&b""[..],
                         vec!(Defn::constant("mip", 123),
                              Defn::typesyn("foo", Type::Int),
@@ -86,9 +245,25 @@ mod spec_tests {
     }
 }
 
-named!(definition<Defn>,
-       alt!(type_def => { |t| t } |
-            const_def => { |c| c }));
+// `bol` is true if the definition starts at the beginning of a line. A C-style typedef can define
+// two names at once, and a program definition doesn't define any.
+named_args!(definition(bol: bool) < Vec<(usize, Defn)> >,
+    do_parse!(
+        doc: apply!(doc_comments, bol) >>
+        pos: position >>
+        defns: alt!(type_def => { |t| vec![t] } |
+                    tagged_type_def |
+                    const_def => { |c| vec![c] } |
+                    program_def => { |_| vec![] }) >>
+        (defns.into_iter()
+            .filter(|d| !d.is_self_alias())
+            .map(|d| (pos, d.with_doc(doc.clone())))
+            .collect()))
+);
+
+fn position(input: &[u8]) -> IResult<&[u8], usize> {
+    Done(input, input.len())
+}
 
 fn is_hexdigit(ch: u8) -> bool {
     match ch as char {
@@ -117,46 +292,83 @@ fn digit<F: Fn(u8) -> bool>(input: &[u8], isdigit: F) -> IResult<&[u8], &[u8]> {
     Incomplete(Needed::Unknown)
 }
 
-named!(lbrace,  preceded!(spaces, apply!(ctag, "{")));
-named!(rbrace,  preceded!(spaces, apply!(ctag, "}")));
-named!(lbrack,  preceded!(spaces, apply!(ctag, "[")));
-named!(rbrack,  preceded!(spaces, apply!(ctag, "]")));
-named!(lparen,  preceded!(spaces, apply!(ctag, "(")));
-named!(rparen,  preceded!(spaces, apply!(ctag, ")")));
-named!(lt,      preceded!(spaces, apply!(ctag, "<")));
-named!(gt,      preceded!(spaces, apply!(ctag, ">")));
-named!(colon,   preceded!(spaces, apply!(ctag, ":")));
-named!(semi,    preceded!(spaces, apply!(ctag, ";")));
-named!(comma,   preceded!(spaces, apply!(ctag, ",")));
-named!(eq,      preceded!(spaces, apply!(ctag, "=")));
-named!(star,    preceded!(spaces, apply!(ctag, "*")));
-
-named!(hexnumber<i64>,
+// Punctuation, after any spaces
+fn punct<'a>(input: &'a [u8], p: &'static str) -> IResult<&'a [u8], &'a [u8]> {
+    let res = preceded!(input, spaces, apply!(ctag, p));
+    if !res.is_done() {
+        expected(ws(input), format!("`{}`", p));
+    }
+    res
+}
+
+named!(lbrace,  apply!(punct, "{"));
+named!(rbrace,  apply!(punct, "}"));
+named!(lbrack,  apply!(punct, "["));
+named!(rbrack,  apply!(punct, "]"));
+named!(lparen,  apply!(punct, "("));
+named!(rparen,  apply!(punct, ")"));
+named!(lt,      apply!(punct, "<"));
+named!(gt,      apply!(punct, ">"));
+named!(colon,   apply!(punct, ":"));
+named!(semi,    apply!(punct, ";"));
+named!(comma,   apply!(punct, ","));
+named!(eq,      apply!(punct, "="));
+named!(star,    apply!(punct, "*"));
+
+// The value of a literal's digits, or None if it doesn't fit in a hyper or unsigned hyper
+fn literal(digits: &str, radix: u32, negative: bool) -> Option<i128> {
+    let val = u64::from_str_radix(digits, radix).ok()? as i128;
+    if negative {
+        Some(-val).filter(|&v| v >= i64::min_value() as i128)
+    } else {
+        Some(val)
+    }
+}
+
+named!(hexnumber<Option<i128> >,
     do_parse!(
+        sign: opt!(apply!(ctag, "-")) >>
         apply!(ctag, "0x") >>
         val: map_res!(apply!(digit, is_hexdigit), str::from_utf8) >>
-        (i64::from_str_radix(val, 16).unwrap())
+        (literal(val, 16, sign.is_some()))
     )
 );
 
-named!(octnumber<i64>,
+named!(octnumber<Option<i128> >,
     do_parse!(
         sign: opt!(apply!(ctag, "-")) >>
         apply!(ctag, "0") >>
         val: opt!(map_res!(apply!(digit, is_octdigit), str::from_utf8)) >>
-        (i64::from_str_radix(val.unwrap_or("0"), 8).unwrap() * (if sign.is_some() { -1 } else { 1 }))
+        (literal(val.unwrap_or("0"), 8, sign.is_some()))
     )
 );
 
-named!(decnumber<i64>,
+named!(decnumber<Option<i128> >,
     do_parse!(
         sign: opt!(apply!(ctag, "-")) >>
         val: map_res!(apply!(digit, is_digit), str::from_utf8) >>
-        (i64::from_str_radix(val, 10).unwrap() * (if sign.is_some() { -1 } else { 1 }))
+        (literal(val, 10, sign.is_some()))
     )
 );
 
-named!(number<i64>, preceded!(spaces, alt!(hexnumber | octnumber | decnumber)));
+fn number(input: &[u8]) -> IResult<&[u8], i128> {
+    let start = ws(input);
+    match preceded!(input, spaces, alt!(hexnumber | octnumber | decnumber)) {
+        Done(rest, Some(val)) => Done(rest, val),
+        Done(..) => {
+            malformed(start, format!("a number from {} to {}", i64::min_value(), u64::max_value()));
+            Error(Err::Position(ErrorKind::MapOpt, start))
+        }
+        Error(err) => {
+            expected(start, "number");
+            Error(err)
+        }
+        Incomplete(needed) => {
+            expected(start, "number");
+            Incomplete(needed)
+        }
+    }
+}
 
 #[cfg(test)]
 mod number_tests {
@@ -184,6 +396,17 @@ mod number_tests {
         assert_eq!(number(&b" -0+"[..]), Done(&b"+"[..], 0));
     }
 
+    #[test]
+    fn wide_numbers() {
+        assert_eq!(number(&b"0xffffffffffffffff+"[..]), Done(&b"+"[..], u64::max_value() as i128));
+        assert_eq!(number(&b"18446744073709551615+"[..]), Done(&b"+"[..], u64::max_value() as i128));
+        assert_eq!(number(&b"-9223372036854775808+"[..]), Done(&b"+"[..], i64::min_value() as i128));
+        assert_eq!(number(&b"-0x8000000000000000+"[..]), Done(&b"+"[..], i64::min_value() as i128));
+        assert!(number(&b"0x10000000000000000+"[..]).is_err());
+        assert!(number(&b"18446744073709551616+"[..]).is_err());
+        assert!(number(&b"-9223372036854775809+"[..]).is_err());
+    }
+
     #[test]
     fn incomplete_numbers_are_unknown() {
         assert_eq!(number(&b"0x12344"[..]), Incomplete(Needed::Unknown));
@@ -218,9 +441,10 @@ fn token(input: &[u8]) -> IResult<&[u8], &[u8]> {
 macro_rules! kw {
     ($fnname:ident, $kw:expr) => (
         fn $fnname(input: &[u8]) -> IResult<&[u8], ()> {
-            match token(input) {
+            let kw = $kw.as_bytes();
+            let res = match token(input) {
                 Done(rest, val) =>
-                    if val == $kw {
+                    if val == kw {
                         Done(rest, ())
                     } else {
                         Error(Err::Position(ErrorKind::Custom(0), input))
@@ -229,37 +453,43 @@ macro_rules! kw {
                 Incomplete(_) => {
                     // If its either incomplete but longer that what we're looking for, or what we
                     // have doesn't match, then its not for us.
-                    if input.len() > $kw.len() || input != &$kw[..input.len()] {
+                    if input.len() > kw.len() || input != &kw[..input.len()] {
                         Error(Err::Position(ErrorKind::Custom(0), input))
                     } else {
-                        Incomplete(Needed::Size($kw.len() - input.len()))
+                        Incomplete(Needed::Size(kw.len() - input.len()))
                     }
                 },
+            };
+            if !res.is_done() {
+                expected(ws(input), concat!("`", $kw, "`"));
             }
+            res
         });
 }
 
-kw!(kw_bool, b"bool");
-kw!(kw_case, b"case");
-kw!(kw_char, b"char"); // special case - part time keyword
-kw!(kw_const, b"const");
-kw!(kw_default, b"default");
-kw!(kw_double, b"double");
-kw!(kw_enum, b"enum");
-kw!(kw_float, b"float");
-kw!(kw_hyper, b"hyper");
-kw!(kw_int, b"int");
-kw!(kw_long, b"long"); // special case - part time keyword
-kw!(kw_opaque, b"opaque");
-kw!(kw_quadruple, b"quadruple");
-kw!(kw_short, b"short"); // special case - part time keyword
-kw!(kw_string, b"string");
-kw!(kw_struct, b"struct");
-kw!(kw_switch, b"switch");
-kw!(kw_typedef, b"typedef");
-kw!(kw_union, b"union");
-kw!(kw_unsigned, b"unsigned");
-kw!(kw_void, b"void");
+kw!(kw_bool, "bool");
+kw!(kw_case, "case");
+kw!(kw_char, "char"); // special case - part time keyword
+kw!(kw_const, "const");
+kw!(kw_default, "default");
+kw!(kw_double, "double");
+kw!(kw_enum, "enum");
+kw!(kw_float, "float");
+kw!(kw_hyper, "hyper");
+kw!(kw_int, "int");
+kw!(kw_long, "long"); // special case - part time keyword
+kw!(kw_opaque, "opaque");
+kw!(kw_program, "program"); // special case - part time keyword
+kw!(kw_quadruple, "quadruple");
+kw!(kw_short, "short"); // special case - part time keyword
+kw!(kw_string, "string");
+kw!(kw_struct, "struct");
+kw!(kw_switch, "switch");
+kw!(kw_typedef, "typedef");
+kw!(kw_union, "union");
+kw!(kw_unsigned, "unsigned");
+kw!(kw_version, "version"); // special case - part time keyword
+kw!(kw_void, "void");
 
 named!(keyword<()>,
        alt!(kw_bool |
@@ -349,16 +579,20 @@ mod keyword_tests {
 
 fn ident(input: &[u8]) -> IResult<&[u8], &str> {
     // Grab an identifier and make sure it isn't a keyword
-    match token(input) {
+    let res = match token(input) {
         Done(rest, val) => {
-            match keyword(input) {
+            match quietly(|| keyword(input)) {
                 Done(..) => Error(Err::Position(ErrorKind::Custom(1), val)),
                 Error(..) | Incomplete(..) => Done(rest, str::from_utf8(val).unwrap()),
             }
         }
         Error(e) => Error(e),
         Incomplete(need) => Incomplete(need),
+    };
+    if !res.is_done() {
+        expected(ws(input), "identifier");
     }
+    res
 }
 
 #[cfg(test)]
@@ -373,13 +607,14 @@ mod ident_tests {
     }
 }
 
-named!(blockcomment<()>,
-    do_parse!(apply!(ctag, "/*") >> take_until_and_consume!(&b"*/"[..]) >> (())));
+named!(blockcomment<&[u8], &[u8]>,
+    do_parse!(apply!(ctag, "/*") >> text: take_until_and_consume!(&b"*/"[..]) >> (text)));
 
 // `linecomment`, and `directive` end at eol, but do not consume it
-named!(linecomment<()>,
+named!(linecomment<&[u8], &[u8]>,
     do_parse!(
-        apply!(ctag, "//") >> opt!(not_line_ending) >> peek!(alt!(eol | eof)) >> (())
+        apply!(ctag, "//") >> text: opt!(not_line_ending) >> peek!(alt!(eol | eof)) >>
+        (text.unwrap_or(&b""[..]))
     )
 );
 
@@ -387,11 +622,15 @@ named!(linecomment<()>,
 named!(directive<()>,
     do_parse!(
         opt!(whitespace) >>
-        alt!(
-            apply!(ctag, "#") |
-            apply!(ctag, "%")) >>
-        opt!(not_line_ending) >>
-        peek!(alt!(eol | eof)) >> (())
+        line: recognize!(do_parse!(
+            alt!(
+                apply!(ctag, "#") |
+                apply!(ctag, "%")) >>
+            opt!(not_line_ending) >> (())
+        )) >>
+        rest: position >>
+        peek!(alt!(eol | eof)) >>
+        (record_directive(rest + line.len(), line))
     )
 );
 
@@ -401,11 +640,11 @@ mod comment_tests {
 
     #[test]
     fn safely_ignore_comments() {
-        assert_eq!(blockcomment(&b"/* foo */bar"[..]), Done(&b"bar"[..], ()));
-        assert_eq!(blockcomment(&b"/* blip /* foo */bar"[..]), Done(&b"bar"[..], ()));
+        assert_eq!(blockcomment(&b"/* foo */bar"[..]), Done(&b"bar"[..], &b" foo "[..]));
+        assert_eq!(blockcomment(&b"/* blip /* foo */bar"[..]), Done(&b"bar"[..], &b" blip /* foo "[..]));
         assert_eq!(blockcomment(&b"x"[..]), Error(Err::Position(ErrorKind::Tag, &b"x"[..])));
-        assert_eq!(linecomment(&b"// foo\nbar"[..]), Done(&b"\nbar"[..], ()));
-        assert_eq!(linecomment(&b"// foo bar\n "[..]), Done(&b"\n "[..], ()));
+        assert_eq!(linecomment(&b"// foo\nbar"[..]), Done(&b"\nbar"[..], &b" foo"[..]));
+        assert_eq!(linecomment(&b"// foo bar\n "[..]), Done(&b"\n "[..], &b" foo bar"[..]));
         assert_eq!(linecomment(&b"x"[..]), Error(Err::Position(ErrorKind::Tag, &b"x"[..])));
     }
 
@@ -440,8 +679,8 @@ named!(spaces<()>,
         many0!(
             alt!( do_parse!(eol >> opt!(complete!(directive)) >> (()))
                 | whitespace
-                | blockcomment
-                | linecomment
+                | blockcomment => { ignore }
+                | linecomment => { ignore }
                 )
         ),
         ignore
@@ -455,6 +694,63 @@ fn ws(input: &[u8]) -> &[u8] {
     }
 }
 
+// Like `spaces`, but collects the comments immediately preceding an item to use as its
+// documentation.
+//
+// Only comments starting on their own line count; one following something else on the same
+// line is annotating that instead. A blank line or a directive between the comments and the item
+// detaches them. `bol` is true if `input` starts at the beginning of a line.
+//
+// Fails if there's nothing after the comments, so that trailing whitespace doesn't make the
+// parse incomplete.
+fn doc_comments(input: &[u8], bol: bool) -> IResult<&[u8], Doc> {
+    let mut lines = Vec::new();
+    let mut bol = bol;
+    let mut blank = bol;
+    let mut input = input;
+
+    loop {
+        if input.is_empty() {
+            return Error(Err::Position(ErrorKind::Eof, input));
+        }
+
+        if let Done(rest, _) = eol(input) {
+            if blank {
+                lines.clear();
+            }
+            input = rest;
+            bol = true;
+            blank = true;
+
+            if let Done(rest, _) = complete!(input, directive) {
+                lines.clear();
+                input = rest;
+                blank = false;
+            }
+        } else if let Done(rest, _) = complete!(input, whitespace) {
+            input = rest;
+        } else if let Done(rest, text) = blockcomment(input) {
+            if bol {
+                lines.extend(String::from_utf8_lossy(text).lines().map(|l| {
+                    // strip the leading `*` of each line of a ` * ...` style block
+                    let t = l.trim_start();
+                    t.strip_prefix('*').unwrap_or(l).to_string()
+                }));
+            }
+            input = rest;
+            blank = false;
+        } else if let Done(rest, text) = linecomment(input) {
+            if bol {
+                lines.push(String::from_utf8_lossy(text).trim_start_matches('/').to_string());
+            }
+            input = rest;
+            blank = false;
+        } else {
+            return Done(input, Doc::new(lines));
+        }
+    }
+}
+
 #[cfg(test)]
 mod whitespace_tests {
     use super::*;
@@ -485,6 +781,86 @@ mod whitespace_tests {
     }
 }
 
+#[cfg(test)]
+mod doc_comment_tests {
+    use super::*;
+
+    fn doc(lines: &[&str]) -> Doc {
+        Doc(lines.iter().map(|l| String::from(*l)).collect())
+    }
+
+    #[test]
+    fn collects_preceding_comments() {
+        assert_eq!(doc_comments(&b"x"[..], false), Done(&b"x"[..], Doc::default()));
+        assert_eq!(doc_comments(&b"/* foo */ x"[..], true), Done(&b"x"[..], doc(&["foo"])));
+        assert_eq!(doc_comments(&b"\n// foo\n// bar\nx"[..], false), Done(&b"x"[..], doc(&["foo", "bar"])));
+        assert_eq!(doc_comments(&b"\n/*\n * foo\n *   bar\n */\nx"[..], false),
+                   Done(&b"x"[..], doc(&["foo", "  bar"])));
+        assert_eq!(doc_comments(&b"\n/* foo\n *\n *     bar\n */\nx"[..], false),
+                   Done(&b"x"[..], doc(&["```text", "foo", "", "    bar", "```"])));
+    }
+
+    #[test]
+    fn skips_unattached_comments() {
+        assert_eq!(doc_comments(&b"/* foo */\nx"[..], false), Done(&b"x"[..], Doc::default()));
+        assert_eq!(doc_comments(&b"\n/* foo */\n\nx"[..], false), Done(&b"x"[..], Doc::default()));
+        assert_eq!(doc_comments(&b"\n/* foo */\n#define x\nx"[..], false), Done(&b"x"[..], Doc::default()));
+        assert_eq!(doc_comments(&b"\n/* foo */\n\n/* bar */\nx"[..], false), Done(&b"x"[..], doc(&["bar"])));
+    }
+
+    #[test]
+    fn fails_at_end() {
+        assert_eq!(doc_comments(&b""[..], true), Error(Err::Position(ErrorKind::Eof, &b""[..])));
+        assert_eq!(doc_comments(&b"\n /* foo */\n"[..], true), Error(Err::Position(ErrorKind::Eof, &b""[..])));
+    }
+
+    #[test]
+    fn attaches_docs_to_items() {
+        let spec = spec(&br#"
+// constant
+const A = 1;
+/* type */
+struct foo {
+    int a;      /* not a's doc */
+    // b's doc
+    int b;
+};
+enum bar {
+    /* X's doc */
+    X,
+    Y
+};
+union baz switch (bar x) {
+    // X arm
+    case X: int a;
+    // Y arm
+    case Y: void;
+    // default arm
+    default: void;
+};
+"#[..]).map(defns);
+
+        let mut foo = Decl::named("b", Type::Int);
+        if let Decl::Named(_, _, ref mut d) = foo { *d = doc(&["b's doc"]) }
+        let mut x = EnumDefn::new("X", None);
+        x.2 = doc(&["X's doc"]);
+
+        assert_eq!(spec,
+                   Done(&b""[..],
+                        vec!(Defn::constant("A", 1).with_doc(doc(&["constant"])),
+                             Defn::typespec("foo", Type::Struct(vec!(Decl::named("a", Type::Int), foo)))
+                                 .with_doc(doc(&["type"])),
+                             Defn::typespec("bar", Type::Enum(vec!(x, EnumDefn::new("Y", None)))),
+                             Defn::typespec("baz",
+                                            Type::union((Decl::named("x", Type::ident("bar")),
+                                                         vec!(UnionCase(Value::ident("X"),
+                                                                        Decl::named("a", Type::Int),
+                                                                        doc(&["X arm"])),
+                                                              UnionCase(Value::ident("Y"), Decl::Void, doc(&["Y arm"]))),
+                                                         Some((Decl::Void, doc(&["default arm"])))))))));
+    }
+}
+
 named!(enum_type_spec< Vec<EnumDefn> >,
        preceded!(kw_enum, enum_body));
 
@@ -499,17 +875,93 @@ named!(enum_body< Vec<EnumDefn> >,
 
 named!(enum_assign<EnumDefn>,
     do_parse!(
+        doc: apply!(doc_comments, false) >>
         id: ident >>
         v: opt!(preceded!(eq, value)) >>
-        (EnumDefn::new(id, v))
+        (EnumDefn::new(id, v).with_doc(doc))
     )
 );
 
-named!(value<Value>,
-       alt!(number => { |c| Value::Const(c) } |
-            ident => { |id| Value::ident(id) }
-            )
-       );
+// A constant expression
+fn value(input: &[u8]) -> IResult<&[u8], Value> {
+    expr(input, 0)
+}
+
+// An expression of operators binding at least as tightly as `min_prec`, by precedence climbing
+fn expr(input: &[u8], min_prec: u8) -> IResult<&[u8], Value> {
+    let (mut input, mut lhs) = match operand(input) {
+        Done(rest, v) => (rest, v),
+        Error(e) => return Error(e),
+        Incomplete(n) => return Incomplete(n),
+    };
+
+    loop {
+        match quietly(|| binop(input)) {
+            Done(rest, op) if op.precedence() >= min_prec => {
+                match expr(rest, op.precedence() + 1) {
+                    Done(rest, rhs) => {
+                        lhs = Value::binop(op, lhs, rhs);
+                        input = rest;
+                    }
+                    Error(e) => return Error(e),
+                    Incomplete(n) => return Incomplete(n),
+                }
+            }
+            _ => return Done(input, lhs),
+        }
+    }
+}
+
+named!(operand<Value>,
+    alt!(number => { |c| Value::Const(c) } |
+         ident => { |id| Value::ident(id) } |
+         delimited!(lparen, value, rparen) |
+         preceded!(apply!(punct, "-"), operand) => { |v| Value::Neg(Box::new(v)) }
+    )
+);
+
+named!(binop<BinOp>,
+    preceded!(spaces, alt!(
+        apply!(ctag, "<<") => { |_| BinOp::Shl } |
+        apply!(ctag, ">>") => { |_| BinOp::Shr } |
+        apply!(ctag, "|") => { |_| BinOp::Or } |
+        apply!(ctag, "&") => { |_| BinOp::And } |
+        apply!(ctag, "+") => { |_| BinOp::Add } |
+        apply!(ctag, "-") => { |_| BinOp::Sub } |
+        apply!(ctag, "*") => { |_| BinOp::Mul } |
+        apply!(ctag, "/") => { |_| BinOp::Div }
+    ))
+);
+
+#[cfg(test)]
+mod value_tests {
+    use super::*;
+
+    fn c(v: i128) -> Value {
+        Value::Const(v)
+    }
+
+    #[test]
+    fn parses_expressions() {
+        assert_eq!(value(&b"A;"[..]), Done(&b";"[..], Value::ident("A")));
+        assert_eq!(value(&b"1 + 2 * 3;"[..]),
+                   Done(&b";"[..], Value::binop(BinOp::Add, c(1), Value::binop(BinOp::Mul, c(2), c(3)))));
+        assert_eq!(value(&b"(1+2)*3;"[..]),
+                   Done(&b";"[..], Value::binop(BinOp::Mul, Value::binop(BinOp::Add, c(1), c(2)), c(3))));
+        assert_eq!(value(&b"8 - 2 - 1;"[..]),
+                   Done(&b";"[..], Value::binop(BinOp::Sub, Value::binop(BinOp::Sub, c(8), c(2)), c(1))));
+        assert_eq!(value(&b"1 << A | 4 ]"[..]),
+                   Done(&b" ]"[..], Value::binop(BinOp::Or, Value::binop(BinOp::Shl, c(1), Value::ident("A")), c(4))));
+        assert_eq!(value(&b"-A>"[..]), Done(&b">"[..], Value::Neg(Box::new(Value::ident("A")))));
+        assert_eq!(value(&b"A-1:"[..]), Done(&b":"[..], Value::binop(BinOp::Sub, Value::ident("A"), c(1))));
+    }
+
+    #[test]
+    fn displays_expressions() {
+        let (_, v) = value(&b"(A + 1) * -(2 << B);"[..]).unwrap();
+        assert_eq!(v.to_string(), "(A + 1) * -(2 << B)");
+    }
+}
 
 named!(struct_type_spec< Vec<Decl> >,
        preceded!(kw_struct, struct_body));
@@ -517,16 +969,19 @@ named!(struct_type_spec< Vec<Decl> >,
 named!(struct_body< Vec<Decl> >,
     do_parse!(
         lbrace >>
-        decls: many1!(terminated!(declaration, semi)) >>
+        decls: many1!(do_parse!(
+            doc: apply!(doc_comments, false) >>
+            decl: declaration >> semi >>
+            (decl.with_doc(doc)))) >>
         rbrace >>
         (decls)
     )
 );
 
-named!(union_type_spec<(Decl, Vec<UnionCase>, Option<Decl>)>,
+named!(union_type_spec<(Decl, Vec<UnionCase>, Option<(Decl, Doc)>)>,
     do_parse!(kw_union >> body:union_body >> (body)));
 
-named!(union_body<(Decl, Vec<UnionCase>, Option<Decl>)>,
+named!(union_body<(Decl, Vec<UnionCase>, Option<(Decl, Doc)>)>,
     do_parse!(
         kw_switch >> lparen >> decl:declaration >> rparen >>
         lbrace >>
@@ -539,17 +994,19 @@ named!(union_body<(Decl, Vec<UnionCase>, Option<Decl>)>,
 
 named!(union_case< Vec<UnionCase> >,
     do_parse!(
+        doc: apply!(doc_comments, false) >>
         vs: many1!(do_parse!(kw_case >> v:value >> colon >> (v))) >>
         decl: declaration >> semi >>
-        (vs.into_iter().map(|v| UnionCase(v, decl.clone())).collect())
+        (vs.into_iter().map(|v| UnionCase(v, decl.clone(), doc.clone())).collect())
     )
 );
 
-named!(union_default<Decl>,
+named!(union_default<(Decl, Doc)>,
     do_parse!(
+        doc: apply!(doc_comments, false) >>
         kw_default >> colon >>
         decl: declaration >> semi >>
-        (decl)
+        ((decl, doc))
     )
 );
 
@@ -623,8 +1080,8 @@ mod declaration_tests {
                      "foo",
                      Type::Union(
                          Box::new(Decl::named("bar", Type::Int)),
-                         vec!(UnionCase(Value::Const(1), Decl::Void)),
-                         Some(Box::new(Decl::named("val", Type::Int)))
+                         vec!(UnionCase(Value::Const(1), Decl::Void, Doc::default())),
+                         Some((Box::new(Decl::named("val", Type::Int)), Doc::default()))
                      )
                  )
             )
@@ -638,13 +1095,13 @@ named!(type_spec<Type>,
             do_parse!(kw_unsigned >> kw_int >> (Type::UInt)) |
             do_parse!(kw_unsigned >> kw_long >> (Type::UInt)) |          // backwards compat with rpcgen
             do_parse!(kw_unsigned >> kw_char >>                          // backwards compat with rpcgen
-                (Type::ident_with_derives("u8", Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG))) |
+                (Type::ident_with_derives("u8", Derives::all()))) |
             do_parse!(kw_unsigned >> kw_short >> (Type::UInt)) |         // backwards compat with rpcgen
             do_parse!(kw_unsigned >> kw_hyper >> (Type::UHyper)) |
             kw_unsigned => { |_| Type::UInt } |                     // backwards compat with rpcgen
             kw_long => { |_| Type::Int } |                          // backwards compat with rpcgen
             kw_char => {                                            // backwards compat with rpcgen
-                |_| Type::ident_with_derives("i8", Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG)
+                |_| Type::ident_with_derives("i8", Derives::all())
             } |
             kw_short => { |_| Type::Int } |                         // backwards compat with rpcgen
             kw_int => { |_| Type::Int } |
@@ -656,7 +1113,9 @@ named!(type_spec<Type>,
             enum_type_spec => { |defns| Type::Enum(defns) } |
             struct_type_spec => { |defns| Type::Struct(defns) } |
             do_parse!(kw_struct >> id:ident >> (Type::ident(id))) |    // backwards compat with rpcgen
+            do_parse!(kw_enum >> id:ident >> (Type::ident(id))) |      // backwards compat with rpcgen
             union_type_spec => { |u| Type::union(u) } |
+            do_parse!(kw_union >> id:ident >> (Type::ident(id))) |     // backwards compat with rpcgen
             ident => { |id| Type::ident(id) }
         )
     )
@@ -677,7 +1136,7 @@ mod type_spec_tests {
         assert_eq!(type_spec(&b"unsigned hyper "[..]), Done(&b" "[..], Type::UHyper));
 
     assert_eq!(type_spec(&b"unsigned char "[..]), Done(&b" "[..],
-        Type::Ident("u8".into(), Some(Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG))));
+        Type::Ident("u8".into(), Some(Derives::all()))));
     assert_eq!(type_spec(&b"unsigned short "[..]), Done(&b" "[..], Type::UInt));
 
         assert_eq!(type_spec(&b" hyper "[..]), Done(&b" "[..], Type::Hyper));
@@ -686,7 +1145,7 @@ mod type_spec_tests {
         assert_eq!(type_spec(&b"// thing\n bool "[..]), Done(&b" "[..], Type::Bool));
 
     assert_eq!(type_spec(&b"char "[..]), Done(&b" "[..],
-        Type::Ident("i8".into(), Some(Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG))));
+        Type::Ident("i8".into(), Some(Derives::all()))));
 
         assert_eq!(type_spec(&b"short "[..]), Done(&b" "[..], Type::Int));
     }
@@ -705,9 +1164,9 @@ mod type_spec_tests {
         assert_eq!(type_spec(&b"union switch (int a) { case 1: void; case 2: int a; default: void; } "[..]),
                              Done(&b" "[..],
                                   Type::Union(Box::new(Decl::named("a", Type::Int)),
-                                              vec!(UnionCase(Value::Const(1), Decl::Void),
-                                                   UnionCase(Value::Const(2), Decl::named("a", Type::Int))),
-                                              Some(Box::new(Decl::Void)))));
+                                              vec!(UnionCase(Value::Const(1), Decl::Void, Doc::default()),
+                                                   UnionCase(Value::Const(2), Decl::named("a", Type::Int), Doc::default())),
+                                              Some((Box::new(Decl::Void), Doc::default())))));
     }
 
     #[test]
@@ -737,8 +1196,8 @@ mod type_spec_tests {
 
 named!(const_def<Defn>,
     do_parse!(
-        kw_const >> id:ident >> eq >> v:number >> semi >>
-            (Defn::constant(id, v)))
+        kw_const >> id:ident >> eq >> v:value >> semi >>
+            (Defn::Const(id.to_string(), v, Doc::default())))
 );
 
 #[cfg(test)]
@@ -756,7 +1215,7 @@ named!(type_def<Defn>,
         do_parse!(kw_typedef >> decl: nonvoid_declaration >> semi >>
             ({
                 match decl.clone() {
-                    Decl::Named(name, ty) => {
+                    Decl::Named(name, ty, _) => {
                         if ty.is_syn() {
                             Defn::typesyn(name, ty)
                         } else {
@@ -773,6 +1232,58 @@ named!(type_def<Defn>,
     )
 );
 
+// A C-style typedef which names a struct, enum or union as well as defining it, such as
+// `typedef struct foo { ... } foo_t;`. Both names can be used for the type.
+named!(tagged_type_def< Vec<Defn> >,
+    do_parse!(
+        kw_typedef >>
+        tagged: alt!(
+            do_parse!(kw_enum >> id:ident >> e:enum_body >> ((id, Type::Enum(e))))
+        |   do_parse!(kw_struct >> id:ident >> s:struct_body >> ((id, Type::Struct(s))))
+        |   do_parse!(kw_union >> id:ident >> u:union_body >> ((id, Type::union(u))))
+        ) >>
+        name: ident >> semi >>
+        ({
+            let (tag, ty) = tagged;
+            let mut defns = vec![Defn::typespec(tag, ty)];
+            if name != tag {
+                defns.push(Defn::typesyn(name, Type::ident(tag)));
+            }
+            defns
+        })
+    )
+);
+
+// An RPC program definition (RFC 5531). These are parsed so that specifications written for rpcgen
+// can be used as they are, but nothing is generated for them.
+named!(program_def<()>,
+    do_parse!(
+        kw_program >> ident >> lbrace >>
+        many1!(version_def) >>
+        rbrace >> eq >> value >> semi >> (())
+    )
+);
+
+named!(version_def<()>,
+    do_parse!(
+        kw_version >> ident >> lbrace >>
+        many1!(procedure_def) >>
+        rbrace >> eq >> value >> semi >> (())
+    )
+);
+
+named!(procedure_def<()>,
+    do_parse!(
+        procedure_type >> ident >> lparen >>
+        procedure_type >> many0!(preceded!(comma, procedure_type)) >>
+        rparen >> eq >> value >> semi >> (())
+    )
+);
+
+// rpcgen also allows `string`, for strings of any length
+named!(procedure_type<()>,
+    alt!(kw_void | kw_string | map!(type_spec, ignore)));
+
 #[cfg(test)]
 mod typedef_tests {
     use super::*;
@@ -799,12 +1310,46 @@ mod typedef_tests {
                    Done(&b""[..], Defn::typespec("foo", Type::Struct(vec!(Decl::named("a", Type::Int))))));
     }
 
+    #[test]
+    fn parses_tagged_type_defs() {
+        assert_eq!(tagged_type_def(&b"typedef struct foo { int a; } foo_t;"[..]),
+                   Done(&b""[..], vec!(Defn::typespec("foo", Type::Struct(vec!(Decl::named("a", Type::Int)))),
+                                       Defn::typesyn("foo_t", Type::ident("foo")))));
+        assert_eq!(tagged_type_def(&b"typedef enum foo { a } foo;"[..]),
+                   Done(&b""[..], vec!(Defn::typespec("foo", Type::Enum(vec!(EnumDefn::new("a", None)))))));
+        assert_eq!(type_def(&b"typedef enum foo bar;"[..]),
+                   Done(&b""[..], Defn::typesyn("bar", Type::ident("foo"))));
+        assert_eq!(type_def(&b"typedef union foo *bar;"[..]),
+                   Done(&b""[..], Defn::typesyn("bar", Type::option(Type::ident("foo")))));
+    }
+
+    #[test]
+    fn drops_self_aliases() {
+        assert_eq!(spec(&b"typedef struct foo foo;\ntypedef foo bar;"[..]).map(defns),
+                   Done(&b""[..], vec!(Defn::typesyn("bar", Type::ident("foo")))));
+    }
+
+    #[test]
+    fn parses_programs() {
+        let prog = br#"
+            program PROG {
+                version VERS {
+                    void PROC_NULL(void) = 0;
+                    res PROC_ONE(args) = 1;
+                    string PROC_TWO(unsigned int, struct args) = 2;
+                } = 1;
+                version VERS2 { void PROC_NULL(void) = 0; } = 2;
+            } = 0x20000000;"#;
+        assert_eq!(program_def(&prog[..]), Done(&b""[..], ()));
+        assert_eq!(spec(&prog[..]).map(defns), Done(&b""[..], vec!()));
+    }
+
     #[test]
     fn parses_unions_types() {
         assert_eq!(type_def(&b"union foo switch(int a) { case 1: int a; };"[..]),
                    Done(&b""[..], Defn::typespec("foo",
                                                  Type::Union(Box::new(Decl::named("a", Type::Int)),
-                                                             vec!(UnionCase(Value::Const(1), Decl::named("a", Type::Int))),
+                                                             vec!(UnionCase(Value::Const(1), Decl::named("a", Type::Int), Doc::default())),
                                                              None))));
     }
 }
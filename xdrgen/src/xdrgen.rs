@@ -8,9 +8,9 @@ use std::fs::File;
 use std::io::{BufReader, Write};
 use std::io::{stderr, stdin, stdout};
 
-use clap::App;
+use clap::{App, Arg};
 
-use xdrgen::generate;
+use xdrgen::{generate_with_config, Config, ExternType, Passthrough, Strings};
 
 fn main() {
     let _ = env_logger::init();
@@ -18,8 +18,198 @@ fn main() {
     let matches = App::new("XDR code generator")
         .version(env!("CARGO_PKG_VERSION"))
         .arg_from_usage("[FILE] 'Set .x file'")
+        .arg(
+            Arg::with_name("derive")
+                .short("d")
+                .long("derive")
+                .value_name("TRAIT")
+                .help("Derive an additional trait on generated types")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("include")
+                .short("I")
+                .value_name("DIR")
+                .help("Search DIR for #included files")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("define")
+                .short("D")
+                .value_name("NAME[=VALUE]")
+                .help("Define NAME for #if and #ifdef, as VALUE or 1")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("extern")
+                .long("extern")
+                .value_name("TYPE=PATH")
+                .help("Refer to TYPE at the Rust PATH instead of generating it")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("modules")
+                .long("modules")
+                .help("Generate a module for each #included file"),
+        )
+        .arg(
+            Arg::with_name("passthrough")
+                .long("passthrough")
+                .value_name("LINES")
+                .help("Copy %rust lines, or all % lines, into the generated code")
+                .takes_value(true)
+                .possible_values(&["rust", "all"]),
+        )
+        .arg(
+            Arg::with_name("union-labels")
+                .long("union-labels")
+                .help("Generate union arms as struct-like variants named after their fields"),
+        )
+        .arg(
+            Arg::with_name("shared-arms")
+                .long("shared-arms")
+                .help("Generate one union variant for all the cases sharing an arm"),
+        )
+        .arg(
+            Arg::with_name("builders")
+                .long("builders")
+                .help("Generate builders for structs, and derive Default where possible"),
+        )
+        .arg(
+            Arg::with_name("bounded")
+                .long("bounded")
+                .help("Use bounded types for strings and arrays with a maximum size"),
+        )
+        .arg(
+            Arg::with_name("iterative-drop")
+                .long("iterative-drop")
+                .help("Drop lists a node at a time, so long lists can't overflow the stack"),
+        )
+        .arg(
+            Arg::with_name("strings")
+                .long("strings")
+                .value_name("KIND")
+                .help("Represent strings as UTF-8, lossily decoded UTF-8, or bytes")
+                .takes_value(true)
+                .possible_values(&["utf8", "lossy", "bytes"]),
+        )
+        .arg(
+            Arg::with_name("string-field")
+                .long("string-field")
+                .value_name("PATH=KIND")
+                .help("Represent one string field (type.field) or typedef as utf8, lossy or bytes")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("stream-field")
+                .long("stream-field")
+                .value_name("PATH")
+                .help("Stream one opaque field (type.field) or typedef rather than holding it in memory")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("deny-undefined")
+                .long("deny-undefined")
+                .help("Make types which aren't defined in the specification an error rather than a warning"),
+        )
+        .arg(
+            Arg::with_name("warn-unused")
+                .long("warn-unused")
+                .help("Warn about unused types and constants"),
+        )
         .get_matches();
 
+    let config = matches
+        .values_of("derive")
+        .into_iter()
+        .flatten()
+        .fold(Config::new(), |config, d| config.derive(d));
+    let config = matches
+        .values_of("include")
+        .into_iter()
+        .flatten()
+        .fold(config, |config, dir| config.include_dir(dir));
+    let config = matches
+        .values_of("define")
+        .into_iter()
+        .flatten()
+        .fold(config, |config, def| match def.find('=') {
+            None => config.define(def, 1),
+            Some(idx) => match def[idx + 1..].parse() {
+                Ok(v) => config.define(&def[..idx], v),
+                Err(_) => {
+                    let _ = writeln!(stderr(), "-D takes NAME=VALUE with an integer VALUE, not {}", def);
+                    std::process::exit(1);
+                }
+            },
+        });
+    let config = matches
+        .values_of("extern")
+        .into_iter()
+        .flatten()
+        .fold(config, |config, ext| match ext.find('=') {
+            Some(idx) => config.extern_type(&ext[..idx], ExternType::new(&ext[idx + 1..])),
+            None => {
+                let _ = writeln!(stderr(), "--extern takes TYPE=PATH, not {}", ext);
+                std::process::exit(1);
+            }
+        })
+        .file_modules(matches.is_present("modules"))
+        .passthrough(match matches.value_of("passthrough") {
+            Some("all") => Passthrough::All,
+            Some(_) => Passthrough::Rust,
+            None => Passthrough::Off,
+        })
+        .union_labels(matches.is_present("union-labels"))
+        .shared_arms(matches.is_present("shared-arms"))
+        .builders(matches.is_present("builders"))
+        .bounded_types(matches.is_present("bounded"))
+        .iterative_drop(matches.is_present("iterative-drop"))
+        .allow_undefined_types(!matches.is_present("deny-undefined"))
+        .warn_unused(matches.is_present("warn-unused"));
+
+    let strings = |kind| match kind {
+        "utf8" => Some(Strings::Utf8),
+        "lossy" => Some(Strings::Lossy),
+        "bytes" => Some(Strings::Bytes),
+        _ => None,
+    };
+    let config = match matches.value_of("strings") {
+        Some(kind) => config.strings(strings(kind).unwrap()),
+        None => config,
+    };
+    let config = matches
+        .values_of("string-field")
+        .into_iter()
+        .flatten()
+        .fold(config, |config, field| {
+            let parsed = field.find('=').and_then(|idx| strings(&field[idx + 1..]).map(|kind| (idx, kind)));
+            match parsed {
+                Some((idx, kind)) => config.string_field(&field[..idx], kind),
+                None => {
+                    let _ = writeln!(stderr(), "--string-field takes PATH=utf8, lossy or bytes, not {}", field);
+                    std::process::exit(1);
+                }
+            }
+        });
+    let config = matches
+        .values_of("stream-field")
+        .into_iter()
+        .flatten()
+        .fold(config, |config, field| config.stream_field(field));
+
     let output = stdout();
     let mut err = stderr();
 
@@ -31,12 +221,20 @@ fn main() {
                 std::process::exit(1);
             }
         };
-        generate(fname, BufReader::new(f), output)
+        generate_with_config(fname, BufReader::new(f), output, &config)
     } else {
-        generate("stdin", BufReader::new(stdin()), output)
+        generate_with_config("stdin", BufReader::new(stdin()), output, &config)
     };
 
-    if let Err(e) = res {
-        let _ = writeln!(&mut err, "Failed: {}", e);
+    match res {
+        Ok(warnings) => {
+            for warning in warnings {
+                let _ = writeln!(&mut err, "{}", warning);
+            }
+        }
+        Err(e) => {
+            let _ = writeln!(&mut err, "Failed: {}", e);
+            std::process::exit(1);
+        }
     }
 }
@@ -8,10 +8,39 @@ use std::fs::{File, create_dir_all};
 use std::io::{Cursor, Write};
 use std::process::Command;
 
-use xdrgen::generate;
+use xdrgen::{generate_with_config, Config, Derives, ExternType, Passthrough, Strings};
 use xdr_codec::Result;
 
 fn build_test(name: &str, xdr_spec: &str) -> Result<()> {
+    build_test_with_config(name, xdr_spec, &Config::default())
+}
+
+fn build_test_with_config(name: &str, xdr_spec: &str, config: &Config) -> Result<()> {
+    build_test_with_prelude(name, xdr_spec, config, "")
+}
+
+// Prelude code for tests to pack a value, check that it unpacks to the same size and to something
+// which packs the same, and return the packed bytes. Comparing packed forms works for recursive
+// types, which don't derive PartialEq.
+const ROUNDTRIP: &str = r#"
+    fn roundtrip<T>(val: &T) -> Vec<u8>
+    where
+        T: xdr_codec::Pack<Vec<u8>> + xdr_codec::Unpack<std::io::Cursor<Vec<u8>>>,
+    {
+        let mut out = Vec::new();
+        let sz = val.pack(&mut out).unwrap();
+        assert_eq!(sz, out.len());
+        let (back, usz) = T::unpack(&mut std::io::Cursor::new(out.clone())).unwrap();
+        assert_eq!(usz, sz);
+        let mut again = Vec::new();
+        back.pack(&mut again).unwrap();
+        assert_eq!(again, out);
+        out
+    }
+"#;
+
+// Build with `prelude` as extra Rust code at the top level of the test crate
+fn build_test_with_prelude(name: &str, xdr_spec: &str, config: &Config, prelude: &str) -> Result<()> {
     let tempdir = tempdir::TempDir::new("build").expect("Failed to make tempdir");
     let dir = tempdir.path();
 
@@ -49,6 +78,8 @@ xdr-codec = {{ path = "{}" }}
 #![allow(dead_code, non_camel_case_types, unused_assignments, unused_imports)]
 extern crate xdr_codec;
 
+{}
+
 mod test {{
     use xdr_codec;
     include!("{}");
@@ -56,6 +87,7 @@ mod test {{
 
 fn main() {{}}
 "#,
+        prelude,
         testfile.as_os_str().to_string_lossy()
     );
 
@@ -73,7 +105,7 @@ fn main() {{}}
 
     {
         let test = File::create(&testfile)?;
-        generate(name, Cursor::new(xdr_spec.as_bytes()), test)?;
+        generate_with_config(name, Cursor::new(xdr_spec.as_bytes()), test, config)?;
     }
 
     let compile = {
@@ -293,6 +325,25 @@ fn arrays() {
     }
 }
 
+#[test]
+fn large_arrays() {
+    let name = "large_arrays";
+    let spec = r#"
+        struct a { opaque hash[64]; int table[256]; int small[2]; };
+        struct b { a things[33]; };
+        typedef unsigned int table[100];
+    "#;
+    let config = Config::new()
+        .derive("Hash")
+        .derive("PartialOrd")
+        .derive("Ord")
+        .derive("Default");
+
+    if let Err(e) = build_test_with_config(name, spec, &config) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
 #[test]
 fn flex() {
     let name = "flex";
@@ -318,3 +369,955 @@ fn derive_float() {
         panic!("test {} failed: {}", name, e);
     }
 }
+
+#[test]
+fn doc_comments() {
+    let name = "doc_comments";
+    let spec = r#"
+        /* Max name length */
+        const MAXNAME = 32;
+
+        /*
+         * Kinds, laid out as:
+         *
+         *     TEXT | DATA
+         */
+        enum kind {
+            // Some text
+            TEXT = 0,
+            DATA = 1        /* raw */
+        };
+
+        // Either one
+        union thing switch (kind k) {
+        /* text */
+        case TEXT:
+            string text<MAXNAME>;
+        // data
+        case DATA:
+            void;
+        };
+
+        /** A file */
+        struct file {
+            /* name of file */
+            string name<MAXNAME>;
+            thing contents;
+        };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn derive_all() {
+    let name = "derive_all";
+    let spec = r#"
+        enum e { A = 1, B = 2 };
+        struct a { int i; float f; e x; };
+        struct b { e x; opaque o[4]; string s<>; a *next; };
+        struct c { e x; hyper h<>; c *next; };
+        union u switch (e d) { case A: int i; case B: void; };
+        union uf switch (int d) { case 1: float f; default: void; };
+        typedef int arr[3];
+    "#;
+    let config = Config::new()
+        .derive("Hash")
+        .derive("PartialOrd")
+        .derive("Ord")
+        .derive("Default")
+        .attribute("#[allow(missing_docs)]")
+        .type_attribute("c", "#[must_use]");
+
+    if let Err(e) = build_test_with_config(name, spec, &config) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn includes() {
+    let incdir = tempdir::TempDir::new("include").expect("Failed to make tempdir");
+    let common = r#"
+        const MAXNAME = 255;
+        typedef string name<MAXNAME>;
+        enum status { OK = 0, ERR = 1 };
+    "#;
+    let file = r#"
+        #include "common.x"
+        struct file { name n; status s; };
+    "#;
+    File::create(incdir.path().join("common.x")).unwrap().write_all(common.as_bytes()).unwrap();
+    File::create(incdir.path().join("file.x")).unwrap().write_all(file.as_bytes()).unwrap();
+
+    let spec = r#"
+        #include <common.x>
+        #include "file.x"
+        union result switch (status s) { case OK: file f; case ERR: void; };
+        typedef name names<MAXNAME>;
+    "#;
+
+    for &modules in &[false, true] {
+        let name = if modules { "include_modules" } else { "includes" };
+        let config = Config::new().include_dir(incdir.path()).file_modules(modules);
+
+        if let Err(e) = build_test_with_config(name, spec, &config) {
+            panic!("test {} failed: {}", name, e);
+        }
+    }
+}
+
+#[test]
+fn extern_types() {
+    let name = "extern_types";
+    let incdir = tempdir::TempDir::new("include").expect("Failed to make tempdir");
+    let common = r#"
+        typedef opaque handle<64>;
+        enum status { OK = 0, ERR = 1 };
+        struct attrs { unsigned int mode; unsigned hyper size; };
+    "#;
+    File::create(incdir.path().join("common.x")).unwrap().write_all(common.as_bytes()).unwrap();
+
+    // The shared types, as another crate would generate them
+    let mut generated = Vec::new();
+    generate_with_config("common.x", Cursor::new(common.as_bytes()), &mut generated, &Config::new()).unwrap();
+    let mut prelude = format!("pub mod common {{\n use xdr_codec;\n{}\n}}", String::from_utf8(generated).unwrap());
+    // Primitives implement all the standard traits, so don't limit what's derived
+    prelude.push_str(r#"
+        fn _ordered(a: &test::counts, b: &test::counts) -> bool {
+            let _: std::collections::HashSet<test::counts> = [*a, *b].iter().cloned().collect();
+            a < b
+        }
+    "#);
+
+    let spec = r#"
+        #include "common.x"
+        struct file { handle h; attrs a; attrs *next_attrs; counter c; };
+        union result switch (status s) { case OK: file f; case ERR: void; };
+        struct counts { counter c; unsigned int n; };
+    "#;
+    let std = Derives::CLONE | Derives::DEBUG | Derives::EQ | Derives::PARTIALEQ;
+    let config = Config::new()
+        .derive("Hash")
+        .derive("PartialOrd")
+        .derive("Ord")
+        .include_dir(incdir.path())
+        .extern_type("handle", ExternType::new("::common::handle").derives(std).boxed(false))
+        .extern_type("status", ExternType::new("::common::status").derives(std | Derives::COPY))
+        .extern_type("attrs", ExternType::new("::common::attrs").derives(std | Derives::COPY))
+        .extern_type("counter", ExternType::new("u32").primitive(true));
+
+    if let Err(e) = build_test_with_prelude(name, spec, &config, &prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn passthrough() {
+    let name = "passthrough";
+    let spec = r#"
+%#include <rpc/rpc.h>
+%rust use std::fmt;
+struct point { int x; int y; };
+%rust impl fmt::Display for point {
+%rust     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "({}, {})", self.x, self.y) }
+%rust }
+"#;
+    let config = Config::new().passthrough(Passthrough::Rust);
+
+    if let Err(e) = build_test_with_config(name, spec, &config) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn preprocessor() {
+    let name = "preprocessor";
+    let spec = r#"
+#define MAXNAMLEN 255
+#define FHSIZE 0x20
+
+#ifdef RPC_HDR
+%#include <stdio.h>
+#endif
+
+#if defined(V3) && MAXNAMLEN > 100
+typedef opaque fhandle<FHSIZE>;
+#else
+typedef opaque fhandle[FHSIZE];
+#endif
+
+#ifndef V3
+struct v2only { int x; };
+#endif
+
+struct entry { string name<MAXNAMLEN>; fhandle fh; };
+"#;
+    let config = Config::new().define("V3", 1).passthrough(Passthrough::All);
+
+    if let Err(e) = build_test_with_config(name, spec, &config) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn const_expressions() {
+    let name = "const_expressions";
+    let spec = r#"
+        const MAXDATA = 8192;
+        const HDRSIZE = 2 * 4;
+        const FLAGS = (1 << 3) | (1 << 5);
+        enum kind { SMALL = HDRSIZE, LARGE = SMALL * 2 };
+        struct packet { opaque hdr[HDRSIZE]; opaque data<MAXDATA + HDRSIZE>; int counts[LARGE - SMALL]; };
+        union u switch (unsigned int x) { case FLAGS & 8: int y; case HDRSIZE + 1: void; };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn nested_types() {
+    let name = "nested_types";
+    let spec = r#"
+        struct outer {
+            struct { int a; enum { RED, GREEN } colour; } inner;
+            union switch (int x) {
+                case 0:
+                case 1: struct { hyper h; } *both;
+                default: void;
+            } u;
+            struct { unsigned int n; } list<>;
+        };
+        union result switch (int status) { case 0: struct { opaque data<>; } ok; default: void; };
+    "#;
+    // The nested types have names of their own
+    let prelude = r#"
+        fn check(o: test::outer, r: test::result) {
+            let _: test::outer_inner = o.inner;
+            let _: test::outer_inner_colour = test::outer_inner_colour::GREEN;
+            let _: Vec<test::outer_list> = o.list;
+            if let test::outer_u::Const1(b) = o.u {
+                let _: Option<Box<test::outer_u_both>> = b;
+            }
+            if let test::result::Const0(ok) = r {
+                let _: Vec<u8> = ok.data;
+            }
+        }
+    "#;
+
+    if let Err(e) = build_test_with_prelude(name, spec, &Config::new(), prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn typedef_forms() {
+    let name = "typedef_forms";
+    let spec = r#"
+        struct node { int val; struct node *next; };
+        typedef struct node node;
+        typedef struct node *node_ptr;
+        typedef node *node_ptr2;
+        typedef struct { int a; } anon_struct;
+        typedef enum { RED, GREEN } anon_enum;
+        typedef union switch (bool b) { case TRUE: int x; case FALSE: void; } anon_union;
+        typedef struct tagged { int a; } tagged_t;
+        typedef enum colour { BLUE, YELLOW } colour_t;
+        typedef union tagged_u switch (colour c) { case BLUE: int x; default: void; } tagged_u;
+        typedef enum colour colour2;
+        typedef union tagged_u tagged_u2;
+        typedef struct { int a; } *anon_ptr;
+        typedef struct { int a; } anon_list<>;
+        typedef struct { int a; } anon_array[2];
+        typedef opaque fixed[4];
+        typedef string name<16>;
+        typedef name names<>;
+        typedef names more_names;
+    "#;
+    let prelude = r#"
+        fn check(p: test::node_ptr, q: test::node_ptr2, t: test::tagged_t, c: test::colour2) {
+            let _: Option<Box<test::node>> = p;
+            let _: Option<Box<test::node>> = q;
+            let _: test::tagged = t;
+            let _: test::colour = c;
+            let _: test::anon_ptr = None::<Box<test::anon_ptr_item>>;
+            let _: test::anon_list = test::anon_list(Vec::<test::anon_list_item>::new());
+            let _: test::more_names = test::names(Vec::<test::name>::new());
+        }
+    "#;
+
+    if let Err(e) = build_test_with_prelude(name, spec, &Config::new(), prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn union_discriminants() {
+    let name = "union_discriminants";
+    let spec = r#"
+        const BIG = 0xffffffff;
+        union flag switch (bool set) { case 1: int val; case 0: void; };
+        union word switch (unsigned int w) { case BIG: int val; case 1: void; };
+        union big switch (unsigned hyper h) { case 0x100000000: int val; case 0xffffffffffffffff: void; default: void; };
+        union signed switch (hyper h) { case -5: int val; case 5: void; };
+    "#;
+    // Discriminants are packed as the selector's type
+    let prelude = r#"
+        fn packed<T: xdr_codec::Pack<Vec<u8>>>(val: &T) -> Vec<u8> {
+            let mut out = Vec::new();
+            val.pack(&mut out).unwrap();
+            out
+        }
+
+        #[test]
+        fn discriminants() {
+            use std::io::Cursor;
+
+            let b = packed(&test::flag::Const1(7));
+            assert_eq!(b, vec![0, 0, 0, 1, 0, 0, 0, 7]);
+            assert_eq!(xdr_codec::unpack::<_, test::flag>(&mut Cursor::new(b)).unwrap(), test::flag::Const1(7));
+            assert!(xdr_codec::unpack::<_, test::flag>(&mut Cursor::new(vec![0, 0, 0, 2])).is_err());
+
+            let b = packed(&test::word::BIG(7));
+            assert_eq!(b, vec![0xff, 0xff, 0xff, 0xff, 0, 0, 0, 7]);
+            assert_eq!(xdr_codec::unpack::<_, test::word>(&mut Cursor::new(b)).unwrap(), test::word::BIG(7));
+
+            let b = packed(&test::big::Const4294967296(7));
+            assert_eq!(b, vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 7]);
+            assert_eq!(xdr_codec::unpack::<_, test::big>(&mut Cursor::new(b)).unwrap(), test::big::Const4294967296(7));
+            let b = vec![0, 0, 0, 0, 0, 0, 0, 1];
+            assert_eq!(xdr_codec::unpack::<_, test::big>(&mut Cursor::new(b)).unwrap(), test::big::default);
+            assert_eq!(packed(&test::big::Const18446744073709551615), vec![0xff; 8]);
+
+            let b = packed(&test::signed::Const_5(7));
+            assert_eq!(b, vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfb, 0, 0, 0, 7]);
+            assert_eq!(xdr_codec::unpack::<_, test::signed>(&mut Cursor::new(b)).unwrap(), test::signed::Const_5(7));
+            assert_eq!(packed(&test::signed::Const5), vec![0, 0, 0, 0, 0, 0, 0, 5]);
+
+            // Unknown discriminants are reported as they are, rather than truncated
+            let b = vec![0, 0, 0, 1, 0, 0, 0, 0];
+            let err = xdr_codec::unpack::<_, test::signed>(&mut Cursor::new(b)).unwrap_err();
+            assert_eq!(err.to_string(), "invalid union case: '4294967296'");
+        }
+    "#;
+
+    if let Err(e) = build_test_with_prelude(name, spec, &Config::new(), prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn union_labels() {
+    let name = "union_labels";
+    let spec = r#"
+        enum op { OP_READ = 1, OP_WRITE = 2, OP_NULL = 3 };
+        union request switch (op o) {
+            case OP_READ: unsigned int count;
+            case OP_WRITE: opaque data<>;
+            case OP_NULL: void;
+        };
+        union result switch (int status) {
+            case 0: request ok;
+            case 1: case 2: int errno;
+            case 3: void;
+            default: string message<>;
+        };
+        union maybe switch (bool present) { case 1: hyper type; case 0: void; };
+    "#;
+    // Variants are struct-like, and numeric cases are named after their field where it's unique
+    let prelude = ROUNDTRIP.to_string() + r#"
+        #[test]
+        fn labels() {
+            use test::*;
+
+            assert_eq!(roundtrip(&request::OP_READ { count: 5 }), vec![0, 0, 0, 1, 0, 0, 0, 5]);
+            roundtrip(&request::OP_WRITE { data: vec![1, 2, 3] });
+            roundtrip(&request::OP_NULL);
+
+            assert_eq!(roundtrip(&result::ok { ok: request::OP_NULL }), vec![0, 0, 0, 0, 0, 0, 0, 3]);
+            roundtrip(&result::Const1 { errno: 1 });
+            roundtrip(&result::Const2 { errno: 2 });
+            roundtrip(&result::Const3);
+
+            let b = vec![0, 0, 0, 9, 0, 0, 0, 2, b'h', b'i', 0, 0];
+            match xdr_codec::unpack(&mut std::io::Cursor::new(b)).unwrap() {
+                result::default { message } => assert_eq!(message, "hi"),
+                other => panic!("unexpected {:?}", other),
+            }
+
+            assert_eq!(roundtrip(&maybe::TRUE { type_: 3 }), vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3]);
+            roundtrip(&maybe::FALSE);
+        }
+    "#;
+    let config = Config::new().union_labels(true);
+
+    if let Err(e) = build_test_with_prelude(name, spec, &config, &prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn shared_arms() {
+    let name = "shared_arms";
+    let spec = r#"
+        enum stat { OK = 0, ENOENT = 2, EIO = 5, EPERM = 1 };
+        union res switch (stat s) {
+            case OK: opaque data<>;
+            case ENOENT: case EIO: unsigned int errinfo;
+            case EPERM: void;
+        };
+        union numbers switch (unsigned int n) {
+            case 1: case 2: int val;
+            case 3: case 4: void;
+            default: int val;
+        };
+    "#;
+    let prelude = ROUNDTRIP.to_string() + r#"
+        #[test]
+        fn shared() {
+            use test::*;
+
+            assert_eq!(roundtrip(&res::errinfo(stat::EIO, 7)), vec![0, 0, 0, 5, 0, 0, 0, 7]);
+            roundtrip(&res::errinfo(stat::ENOENT, 7));
+            roundtrip(&res::OK(vec![1]));
+            roundtrip(&res::EPERM);
+            // The discriminant has to be one of the arm's cases
+            assert!(xdr_codec::pack(&res::errinfo(stat::OK, 7), &mut Vec::new()).is_err());
+
+            assert_eq!(roundtrip(&numbers::Const1_Const2(2, 5)), vec![0, 0, 0, 2, 0, 0, 0, 5]);
+            assert_eq!(roundtrip(&numbers::Const3_Const4(4)), vec![0, 0, 0, 4]);
+            assert!(xdr_codec::pack(&numbers::Const3_Const4(1), &mut Vec::new()).is_err());
+        }
+
+        #[test]
+        fn accessors() {
+            use test::*;
+
+            assert_eq!(res::errinfo(stat::EIO, 7).discriminant(), stat::EIO);
+            assert_eq!(res::OK(vec![]).discriminant(), stat::OK);
+            assert_eq!(res::errinfo(stat::EIO, 7).as_errinfo(), Some(&7));
+            assert_eq!(res::errinfo(stat::EIO, 7).as_data(), None);
+            assert_eq!(res::OK(vec![1]).as_data(), Some(&vec![1]));
+
+            assert_eq!(numbers::Const3_Const4(3).discriminant(), Some(3));
+            assert_eq!(numbers::default(9).discriminant(), None);
+            assert_eq!(numbers::default(9).as_val(), Some(&9));
+            assert_eq!(numbers::Const1_Const2(1, 8).as_val(), Some(&8));
+            assert_eq!(numbers::Const3_Const4(3).as_val(), None);
+        }
+    "#;
+    let config = Config::new().shared_arms(true);
+
+    if let Err(e) = build_test_with_prelude(name, spec, &config, &prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn enum_conversions() {
+    let name = "enum_conversions";
+    let spec = r#"
+        enum colour { RED = 1, GREEN = 5, type = -2 };
+        union paint switch (colour c) { case RED: int shade; default: void; };
+    "#;
+    let prelude = r#"
+        #[test]
+        fn conversions() {
+            use std::convert::TryFrom;
+            use test::*;
+
+            assert_eq!(colour::ALL, [colour::RED, colour::GREEN, colour::type_]);
+            assert_eq!(colour::try_from(5).unwrap(), colour::GREEN);
+            assert_eq!(colour::try_from(-2).unwrap(), colour::type_);
+            assert!(colour::try_from(2).is_err());
+            assert_eq!(i32::from(colour::GREEN), 5);
+            assert_eq!(colour::type_.name(), "type");
+            assert_eq!("GREEN".parse::<colour>().unwrap(), colour::GREEN);
+            assert!("BLUE".parse::<colour>().is_err());
+            for c in &colour::ALL {
+                assert_eq!(c.name().parse::<colour>().unwrap(), *c);
+            }
+
+            assert_eq!(paint::RED(3).discriminant(), Some(colour::RED));
+        }
+    "#;
+
+    if let Err(e) = build_test_with_prelude(name, spec, &Config::new(), prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn builders() {
+    let name = "builders";
+    let spec = r#"
+        enum kind { FILE = 1, DIR = 2 };
+        union owner switch (int t) { case 0: unsigned int uid; default: void; };
+        struct inner { int a; hyper b; };
+        typedef opaque fh<4>;
+        typedef fh handle;
+        struct file {
+            string name<8>;
+            opaque data<4>;
+            fh h;
+            handle h2;
+            kind type;
+            inner in;
+            owner who;
+            unsigned int blocks[40];
+            int *next;
+            float ratio;
+        };
+    "#;
+    let prelude = r#"
+        #[test]
+        fn build() {
+            use test::*;
+
+            let f = file::builder().name("x").who(owner::Const0(1)).build().unwrap();
+            assert_eq!(f.name, "x");
+            assert_eq!(f.data, Vec::<u8>::new());
+            assert_eq!(f.type_, kind::FILE);
+            assert_eq!(f.in_, inner::default());
+            assert_eq!(&f.blocks[..], &[0; 40][..]);
+            assert_eq!(f.next, None);
+
+            let f = file::builder().data(&[1u8, 2][..]).type_(kind::DIR).who(owner::default).build().unwrap();
+            assert_eq!(f.data, vec![1, 2]);
+            assert_eq!(f.type_, kind::DIR);
+
+            // Unions have no default
+            assert!(file::builder().build().is_err());
+            // Nor can fields be longer than the spec allows
+            assert!(file::builder().name("123456789").who(owner::default).build().is_err());
+            assert!(file::builder().data(vec![0; 5]).who(owner::default).build().is_err());
+            // Including by way of typedefs
+            assert!(file::builder().h(fh(vec![0; 4])).who(owner::default).build().is_ok());
+            assert!(file::builder().h(fh(vec![0; 5])).who(owner::default).build().is_err());
+            assert!(file::builder().h2(fh(vec![0; 5])).who(owner::default).build().is_err());
+
+            let _: inner = Default::default();
+        }
+    "#;
+    let config = Config::new().builders(true);
+
+    if let Err(e) = build_test_with_prelude(name, spec, &config, prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn bounded_types() {
+    let name = "bounded_types";
+    let spec = r#"
+        const MAXNAME = 8;
+        typedef opaque handle<4>;
+        struct entry {
+            string name<MAXNAME>;
+            string comment<>;
+            handle h;
+            unsigned int ids<2>;
+        };
+        union res switch (int s) { case 0: string msg<3>; default: void; };
+    "#;
+    let prelude = r#"
+        #[test]
+        fn bounded() {
+            use std::convert::TryFrom;
+            use xdr_codec::{BoundedOpaque, BoundedString, BoundedVec, Pack};
+            use test::*;
+
+            let e = entry {
+                name: BoundedString::new("file").unwrap(),
+                comment: String::from("unbounded"),
+                h: handle(BoundedOpaque::new(vec![1, 2, 3]).unwrap()),
+                ids: BoundedVec::new(vec![7]).unwrap(),
+            };
+            let mut out = Vec::new();
+            e.pack(&mut out).unwrap();
+            let back: entry = xdr_codec::unpack(&mut std::io::Cursor::new(out)).unwrap();
+            assert_eq!(back, e);
+            assert_eq!(back.name, "file");
+
+            assert!(BoundedString::<{ MAXNAME as usize }>::new("too long!").is_err());
+            assert!(BoundedOpaque::<4>::try_from(vec![0; 5]).is_err());
+            let mut ids = e.ids.clone();
+            ids.push(8).unwrap();
+            assert!(ids.push(9).is_err());
+
+            // Oversized data is rejected when unpacking
+            let mut out = Vec::new();
+            "toolong".pack(&mut out).unwrap();
+            let mut input = std::io::Cursor::new([vec![0, 0, 0, 0], out].concat());
+            assert!(xdr_codec::unpack::<_, res>(&mut input).is_err());
+
+            let _: Option<&BoundedString<3>> = res::Const0(BoundedString::new("ok").unwrap()).as_msg();
+
+            // Builders take the plain types, and check the bounds when building
+            let built = entry::builder()
+                .name("file")
+                .comment("unbounded")
+                .h(vec![1, 2, 3])
+                .ids(vec![7])
+                .build()
+                .unwrap();
+            assert_eq!(built, e);
+            assert!(entry::builder().name("too long!").build().is_err());
+            assert!(entry::builder().h(vec![0; 5]).build().is_err());
+            assert!(entry::builder().ids(vec![1, 2, 3]).build().is_err());
+        }
+    "#;
+    let config = Config::new().bounded_types(true).builders(true);
+
+    if let Err(e) = build_test_with_prelude(name, spec, &config, prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn string_types() {
+    let name = "string_types";
+    let spec = r#"
+        typedef string filename<255>;
+        struct entry {
+            filename name;
+            string comment<>;
+            string tag<8>;
+            filename *alias;
+        };
+        union lookup switch (int s) { case 0: string path<>; default: void; };
+    "#;
+    let prelude = r#"
+        fn unpack<T: xdr_codec::Unpack<std::io::Cursor<Vec<u8>>>>(b: Vec<u8>) -> xdr_codec::Result<T> {
+            xdr_codec::unpack(&mut std::io::Cursor::new(b))
+        }
+
+        #[test]
+        fn strings() {
+            use xdr_codec::{Pack, XdrString};
+            use test::*;
+
+            let e = entry {
+                name: filename(XdrString(vec![b'a', 0xff])),
+                comment: String::from("x"),
+                tag: String::from("t"),
+                alias: Some(filename(XdrString::from("b"))),
+            };
+            let mut out = Vec::new();
+            e.pack(&mut out).unwrap();
+            assert_eq!(unpack::<entry>(out).unwrap(), e);
+
+            // Invalid UTF-8 in `comment` is replaced
+            let mut out = Vec::new();
+            XdrString::from("f").pack(&mut out).unwrap();
+            XdrString(vec![0xff]).pack(&mut out).unwrap();
+            "t".pack(&mut out).unwrap();
+            0u32.pack(&mut out).unwrap();
+            let got = unpack::<entry>(out.clone()).unwrap();
+            assert_eq!(got.comment, "\u{fffd}");
+            assert_eq!(got.name.0.to_string(), "f");
+
+            // But not in `tag`
+            let mut out = Vec::new();
+            XdrString::from("f").pack(&mut out).unwrap();
+            "c".pack(&mut out).unwrap();
+            XdrString(vec![0xff]).pack(&mut out).unwrap();
+            0u32.pack(&mut out).unwrap();
+            assert!(unpack::<entry>(out).is_err());
+
+            let mut out = Vec::new();
+            0u32.pack(&mut out).unwrap();
+            XdrString(vec![0xfe]).pack(&mut out).unwrap();
+            let l = unpack::<lookup>(out).unwrap();
+            assert_eq!(l.as_path().map(|p| p.as_bytes()), Some(&[0xfe][..]));
+        }
+    "#;
+    let config = Config::new()
+        .strings(Strings::Bytes)
+        .string_field("entry.comment", Strings::Lossy)
+        .string_field("entry.tag", Strings::Utf8);
+
+    if let Err(e) = build_test_with_prelude(name, spec, &config, prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn linked_lists() {
+    let name = "linked_lists";
+    let spec = r#"
+        struct entry {
+            unsigned int id;
+            string name<>;
+            entry *next;
+        };
+        struct dirlist {
+            entry *entries;
+            bool eof;
+        };
+
+        typedef struct node *nodelist;
+        struct node {
+            int val;
+            nodelist next;
+        };
+
+        struct notlist {
+            notlist *next;
+            int val;
+        };
+    "#;
+    let prelude = ROUNDTRIP.to_string() + r#"
+        use xdr_codec::{Pack, Unpack};
+
+        fn entries(n: u32) -> Option<Box<test::entry>> {
+            let mut list = None;
+            for id in (0..n).rev() {
+                list = Some(Box::new(test::entry { id, name: format!("e{}", id), next: list }));
+            }
+            list
+        }
+
+        #[test]
+        fn short() {
+            use test::*;
+
+            let list = dirlist { entries: entries(2), eof: true };
+            let out = roundtrip(&list);
+
+            // Same as the recursive encoding
+            let mut expect = Vec::new();
+            for id in 0..2u32 {
+                true.pack(&mut expect).unwrap();
+                id.pack(&mut expect).unwrap();
+                format!("e{}", id).pack(&mut expect).unwrap();
+            }
+            false.pack(&mut expect).unwrap();
+            true.pack(&mut expect).unwrap();
+            assert_eq!(out, expect);
+
+            roundtrip(&dirlist { entries: None, eof: false });
+            roundtrip(&node { val: 1, next: Some(Box::new(node { val: 2, next: None })) });
+            roundtrip(&notlist { next: Some(Box::new(notlist { next: None, val: 1 })), val: 2 });
+        }
+
+        #[test]
+        fn long() {
+            use test::*;
+
+            let list = dirlist { entries: entries(500_000), eof: true };
+            let out = roundtrip(&list);
+            let got = dirlist::unpack(&mut std::io::Cursor::new(out)).unwrap().0;
+            let mut n = 0;
+            let mut e = &got.entries;
+            while let Some(ref ent) = *e {
+                assert_eq!(ent.id, n);
+                n += 1;
+                e = &ent.next;
+            }
+            assert_eq!(n, 500_000);
+
+            let mut list = None;
+            for val in 0..500_000 {
+                list = Some(Box::new(node { val, next: list }));
+            }
+            drop(list);
+        }
+
+        #[test]
+        fn truncated() {
+            use test::*;
+
+            let mut out = Vec::new();
+            dirlist { entries: entries(3), eof: true }.pack(&mut out).unwrap();
+            out.truncate(out.len() - 8);
+            assert!(dirlist::unpack(&mut std::io::Cursor::new(out)).is_err());
+        }
+    "#;
+
+    let config = Config::new().iterative_drop(true);
+
+    if let Err(e) = build_test_with_prelude(name, spec, &config, &prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn linked_lists_no_drop() {
+    let name = "linked_lists_no_drop";
+    let spec = r#"
+        struct entry {
+            unsigned int id;
+            string name<>;
+            entry *next;
+        };
+    "#;
+    let prelude = ROUNDTRIP.to_string() + r#"
+        #[test]
+        fn move_fields() {
+            use test::*;
+
+            let last = entry { id: 2, name: "b".to_string(), next: None };
+            let list = entry { id: 1, name: "a".to_string(), next: Some(Box::new(last)) };
+            roundtrip(&list);
+
+            // Without a Drop impl, fields can be moved out
+            let entry { name, next, .. } = list;
+            assert_eq!(name, "a");
+            assert_eq!(next.unwrap().name, "b");
+        }
+    "#;
+
+    if let Err(e) = build_test_with_prelude(name, spec, &Config::new(), &prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn opaque_streams() {
+    let name = "opaque_streams";
+    let spec = r#"
+        typedef opaque blob<>;
+        typedef blob blob2;
+        struct writeargs {
+            unsigned int offset;
+            opaque data<8>;
+            opaque meta<>;
+        };
+        struct readres {
+            bool eof;
+            blob2 b;
+        };
+        union result switch (int status) {
+            case 0: readres ok;
+            case 1: writeargs echo;
+            default: void;
+        };
+    "#;
+    let prelude = r#"
+        use std::io::{Cursor, Read};
+        use xdr_codec::{OpaqueStream, Pack, Unpack};
+
+        #[test]
+        fn streams() {
+            use test::*;
+
+            let args = writeargs {
+                offset: 1,
+                data: OpaqueStream::new(5, Cursor::new(b"hello world".to_vec())),
+                meta: vec![9],
+            };
+            let mut out = Vec::new();
+            assert_eq!(args.pack(&mut out).unwrap(), 24);
+            assert!(args.data.is_consumed());
+            assert!(args.pack(&mut Vec::new()).is_err());
+
+            let mut expect = Vec::new();
+            1u32.pack(&mut expect).unwrap();
+            xdr_codec::Opaque::borrowed(b"hello").pack(&mut expect).unwrap();
+            xdr_codec::Opaque::borrowed(&[9]).pack(&mut expect).unwrap();
+            assert_eq!(out, expect);
+
+            // Unpack reads the data into memory
+            let (got, sz) = writeargs::unpack(&mut Cursor::new(out.clone())).unwrap();
+            assert_eq!(sz, 24);
+            let mut data = Vec::new();
+            got.data.into_reader().unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"hello");
+
+            // unpack_into writes it to the sink
+            let mut sink = Vec::new();
+            let (got, sz) = writeargs::unpack_into(&mut Cursor::new(out), &mut sink).unwrap();
+            assert_eq!(sz, 24);
+            assert_eq!((got.offset, got.data.len(), got.meta), (1, 5, vec![9]));
+            assert!(got.data.is_consumed());
+            assert_eq!(sink, b"hello");
+
+            let args = writeargs {
+                offset: 1,
+                data: OpaqueStream::from_bytes(vec![0; 9]),
+                meta: vec![],
+            };
+            assert!(args.pack(&mut Vec::new()).is_err());
+
+            let mut out = Vec::new();
+            blob(OpaqueStream::from_bytes(&b"abc"[..])).pack(&mut out).unwrap();
+            let mut sink = Vec::new();
+            let (got, sz) = blob::unpack_into(&mut Cursor::new(out), &mut sink).unwrap();
+            assert_eq!((got.0.len(), sz), (3, 8));
+            assert_eq!(sink, b"abc");
+
+            // Structs and unions holding streamed data pass the sink on
+            let res = result::Const0(readres { eof: true, b: blob(OpaqueStream::from_bytes(&b"xyz"[..])) });
+            let mut out = Vec::new();
+            assert_eq!(res.pack(&mut out).unwrap(), 16);
+            let mut sink = Vec::new();
+            let (got, sz) = result::unpack_into(&mut Cursor::new(out.clone()), &mut sink).unwrap();
+            assert_eq!(sz, 16);
+            assert_eq!(sink, b"xyz");
+            match got {
+                result::Const0(ref ok) => assert!(ok.eof && ok.b.0.is_consumed() && ok.b.0.len() == 3),
+                _ => panic!("wrong arm {:?}", got),
+            }
+            let (got, _) = result::unpack(&mut Cursor::new(out)).unwrap();
+            assert!(got.as_ok().map_or(false, |ok| !ok.b.0.is_consumed()));
+
+            let mut out = Vec::new();
+            7i32.pack(&mut out).unwrap();
+            let mut sink = Vec::new();
+            let (got, sz) = result::unpack_into(&mut Cursor::new(out), &mut sink).unwrap();
+            assert!(matches!(got, result::default) && sz == 4 && sink.is_empty());
+        }
+    "#;
+    let config = Config::new().stream_field("writeargs.data").stream_field("blob");
+
+    if let Err(e) = build_test_with_prelude(name, spec, &config, prelude) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+// Specifications from the wild, as written for rpcgen
+fn build_spec_file(name: &str, file: &str, prefix: &str) {
+    let path = std::env::current_dir().unwrap().join("tests/specs").join(file);
+    let spec = std::fs::read_to_string(&path).expect("can't read spec");
+
+    if let Err(e) = build_test(name, &format!("{}{}", prefix, spec)) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn spec_nfs3() {
+    build_spec_file("spec_nfs3", "nfs3_subset.x", "");
+}
+
+#[test]
+fn spec_nfs4() {
+    build_spec_file("spec_nfs4", "nfs4_prot.x", "");
+}
+
+#[test]
+fn spec_mount() {
+    build_spec_file("spec_mount", "mount.x", "");
+}
+
+#[test]
+fn spec_portmap() {
+    build_spec_file("spec_portmap", "portmap.x", "");
+}
+
+#[test]
+fn spec_nlm() {
+    // rpcgen has `netobj` built in, and the string limits are only defined for C
+    let prefix = "const MAX_NETOBJ_SZ = 1024; typedef opaque netobj<MAX_NETOBJ_SZ>; \
+                  const LM_MAXSTRLEN = 1024; const MAXNAMELEN = LM_MAXSTRLEN + 1;\n";
+    build_spec_file("spec_nlm", "nlm_prot.x", prefix);
+}
+
+#[test]
+fn spec_rquota() {
+    build_spec_file("spec_rquota", "rquota.x", "");
+}
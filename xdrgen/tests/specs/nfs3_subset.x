@@ -0,0 +1,159 @@
+/*
+ * A hand-written cut-down version of the NFS version 3 protocol, keeping a
+ * few of its types and procedures to cover the idioms the full
+ * specification uses: C integer typedefs, fixed and variable-length opaque
+ * data, unions switched on `bool` and on a status enum with a `default`
+ * arm, a linked list through an optional pointer, and a program definition.
+ */
+
+const FH3_MAX = 64;
+const VERF3_SIZE = 8;
+
+typedef unsigned hyper uint64;
+typedef unsigned long uint32;
+typedef long int32;
+typedef string filename3<>;
+typedef uint64 fileid3;
+typedef uint64 cookie3;
+typedef uint64 offset3;
+typedef uint32 count3;
+typedef opaque verf3[VERF3_SIZE];
+
+enum nfsstat3 {
+	NFS3_OK       = 0,
+	NFS3ERR_NOENT = 2,
+	NFS3ERR_IO    = 5,
+	NFS3ERR_STALE = 70,
+	NFS3ERR_BADHANDLE = 10001
+};
+
+enum ftype3 {
+	NF3REG = 1,
+	NF3DIR = 2,
+	NF3LNK = 5
+};
+
+struct nfs_fh3 {
+	opaque data<FH3_MAX>;
+};
+
+struct nfstime3 {
+	uint32 seconds;
+	uint32 nseconds;
+};
+
+struct fattr3 {
+	ftype3   type;
+	uint32   mode;
+	uint64   size;
+	fileid3  fileid;
+	nfstime3 mtime;
+};
+
+/* Attributes are optional in most replies */
+union post_op_attr switch (bool attributes_follow) {
+case TRUE:
+	fattr3 attributes;
+case FALSE:
+	void;
+};
+
+struct READ3args {
+	nfs_fh3 file;
+	offset3 offset;
+	count3  count;
+};
+
+struct READ3resok {
+	post_op_attr file_attributes;
+	count3       count;
+	bool         eof;
+	opaque       data<>;
+};
+
+struct READ3resfail {
+	post_op_attr file_attributes;
+};
+
+union READ3res switch (nfsstat3 status) {
+case NFS3_OK:
+	READ3resok   resok;
+default:
+	READ3resfail resfail;
+};
+
+enum stable_how {
+	UNSTABLE  = 0,
+	DATA_SYNC = 1,
+	FILE_SYNC = 2
+};
+
+struct WRITE3args {
+	nfs_fh3    file;
+	offset3    offset;
+	count3     count;
+	stable_how stable;
+	opaque     data<>;
+};
+
+struct WRITE3resok {
+	count3     count;
+	stable_how committed;
+	verf3      verf;
+};
+
+union WRITE3res switch (nfsstat3 status) {
+case NFS3_OK:
+	WRITE3resok resok;
+default:
+	post_op_attr attributes;
+};
+
+struct READDIR3args {
+	nfs_fh3 dir;
+	cookie3 cookie;
+	verf3   cookieverf;
+	count3  count;
+};
+
+/* Directory entries are returned as a list */
+struct entry3 {
+	fileid3   fileid;
+	filename3 name;
+	cookie3   cookie;
+	entry3    *nextentry;
+};
+
+struct dirlist3 {
+	entry3 *entries;
+	bool   eof;
+};
+
+struct READDIR3resok {
+	post_op_attr dir_attributes;
+	verf3        cookieverf;
+	dirlist3     reply;
+};
+
+union READDIR3res switch (nfsstat3 status) {
+case NFS3_OK:
+	READDIR3resok resok;
+default:
+	post_op_attr dir_attributes;
+};
+
+program NFS_PROGRAM {
+	version NFS_V3 {
+		void
+		NFSPROC3_NULL(void) = 0;
+
+		READ3res
+		NFSPROC3_READ(READ3args) = 6;
+
+		WRITE3res
+		NFSPROC3_WRITE(WRITE3args) = 7;
+
+		READDIR3res
+		NFSPROC3_READDIR(READDIR3args) = 16;
+	} = 3;
+} = 100003;
@@ -0,0 +1,64 @@
+/*
+ * A hand-written specification shaped like the port mapper protocol
+ * (version 2), for the rpcgen idioms it uses: `%` lines for the C output
+ * inside `#ifdef`, `unsigned long`, a list through a `struct` pointer with a
+ * typedef for its head, and a program definition.
+ */
+
+#ifdef RPC_HDR
+%#define PMAP_PORT_STRING "111"
+#endif
+
+const PMAP_PORT = 111;
+
+/* Where a program version is listening */
+struct mapping {
+	unsigned long prog;
+	unsigned long vers;
+	unsigned long prot;
+	unsigned long port;
+};
+
+const IPPROTO_TCP = 6;
+const IPPROTO_UDP = 17;
+
+struct pmaplist {
+	mapping map;
+	struct pmaplist *next;
+};
+
+typedef pmaplist *pmaplist_ptr;
+
+struct call_args {
+	unsigned long prog;
+	unsigned long vers;
+	unsigned long proc;
+	opaque args<>;
+};
+
+struct call_result {
+	unsigned long port;
+	opaque res<>;
+};
+
+program PMAP_PROG {
+	version PMAP_VERS {
+		void
+		PMAPPROC_NULL(void) = 0;
+
+		bool
+		PMAPPROC_SET(mapping) = 1;
+
+		bool
+		PMAPPROC_UNSET(mapping) = 2;
+
+		unsigned int
+		PMAPPROC_GETPORT(mapping) = 3;
+
+		pmaplist_ptr
+		PMAPPROC_DUMP(void) = 4;
+
+		call_result
+		PMAPPROC_CALLIT(call_args) = 5;
+	} = 2;
+} = 100000;